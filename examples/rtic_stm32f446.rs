@@ -8,9 +8,6 @@
 #![no_std]
 #![no_main]
 
-
-mod deque;
-
 // you can put a breakpoint on `rust_begin_unwind` to catch panics
 use panic_probe as _;
 
@@ -24,16 +21,8 @@ mod app {
         serial::{config::Config, Event::Rxne, Serial},
     };
     use rtt_target::{rtt_init_print, rprintln, rprint};
-    use rustibus::RustIBus::{IBusMsg, popIBusMsg};
-    use crate::deque::{Deque, Writer};
-    use heapless::spsc::{Queue, Consumer, Producer};
-
-    const CAPACITY: usize = 0x40;
-
-
-    static mut Q: Queue<u8, CAPACITY> = Queue::new();
-    static mut buf: Deque<CAPACITY> = Deque::new();
-
+    use rustibus::RustIBus::IBusMsg;
+    use rustibus::hal::IBusReceiver;
 
     #[shared]
     struct Shared { }
@@ -41,7 +30,7 @@ mod app {
     #[local]
     struct Local {
         led: PA5<Output<PushPull>>,
-        usart1: Serial<USART1>
+        receiver: IBusReceiver<Serial<USART1>>
     }
 
     #[monotonic(binds = TIM3, default = true)]
@@ -85,67 +74,22 @@ mod app {
 
         // Init the static resources to use them later through RTIC
         (Shared { },
-         Local { led:led, usart1: serial},
+         Local { led:led, receiver: IBusReceiver::new(serial)},
          init::Monotonics(mono))
     }
 
-    #[task(binds = USART1, priority = 1, local = [led, usart1])]
+    #[task(binds = USART1, priority = 1, local = [led, receiver])]
     fn usart1(mut cx: usart1::Context) {
         cx.local.led.set_high();
-        match cx.local.usart1.read() {
-            Ok(ch) => {
-                cx.local.led.toggle();
-                let mut producer = unsafe { Q.split().0 };
-                producer.enqueue(ch);
-                parse::spawn();
-            },
-            _ => ()
-        }
-        cx.local.led.set_low();
-    }
-
-
-    // #[task(local = [led])]
-    // fn tick(mut cx: tick::Context) {
-    //     tick::spawn_after(1_u64.secs()).ok();
-    //     // Depending on the application, you could want to delegate some of the work done here to
-    //     // the idle task if you want to minimize the latency of interrupts with same priority (if
-    //     // you have any). That could be done
-    //     rprintln!("tick");
-    //     cx.local.led.toggle();
-    //     let mut producer = unsafe { Q.split().0 };
-    //     for ch in [0x20, 0x40, 0xDB, 0x05, 0xDC, 0x05, 0x54, 0x05,
-    //         0xDC, 0x05, 0xE8, 0x03, 0xD0, 0x07, 0xD2, 0x05,
-    //         0xE8, 0x03, 0xDC, 0x05, 0xDC, 0x05, 0xDC, 0x05,
-    //         0xDC, 0x05, 0xDC, 0x05, 0xDC, 0x05, 0xDA, 0xF3] {
-    //         producer.enqueue(ch);
-    //     }
-    //     parse::spawn();
-    // }
-
-    #[task]
-    fn parse(mut cx: parse::Context ) {
-        let mut consumer = unsafe { Q.split().1 };
-
-        while consumer.ready() {
-            let ch = unsafe{consumer.dequeue_unchecked()};
-            unsafe{ buf.push(ch) };
-        }
-        while unsafe {!buf.is_empty()} {
-            let (mut msg, step) = unsafe{ popIBusMsg(&buf) };
-            match msg {
-                Some(m) => {
-                    match m{
-                        IBusMsg::SetMsg(data) => rprintln!("data: {:?}", data),
-                        _ => ()
-                    }
-                },
-                None => ()
-            };
-            if step == 0 { break; }
-            for _ in 0..step {
-                _ = unsafe { buf.pop() };
+        // Read until no more bytes are waiting; serial errors only cost a message.
+        loop {
+            match cx.local.receiver.poll() {
+                Ok(IBusMsg::SetMsg(data)) => rprintln!("data: {:?}", data),
+                Ok(_) => (),
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(_)) => ()
             }
         }
+        cx.local.led.set_low();
     }
 }
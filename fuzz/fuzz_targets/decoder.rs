@@ -0,0 +1,29 @@
+//! Feeds arbitrary bytes to the message decoders. Every decoded message must encode to bytes
+//! that decode to the same message again.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rustibus::stream::StreamDecoder;
+use rustibus::testkit::{decode_msgs, decode_responses, encode};
+
+fuzz_target!(|data: &[u8]| {
+    for msg in decode_msgs(data) {
+        assert_eq!(decode_msgs(&encode(&msg)), [msg]);
+    }
+    for msg in decode_responses(data) {
+        assert_eq!(decode_responses(&encode(&msg)), [msg]);
+    }
+
+    // The ring buffer of the stream decoder must find the same messages
+    let mut requests = StreamDecoder::<64>::new();
+    let mut responses = StreamDecoder::<64>::for_responses();
+    let (mut from_requests, mut from_responses) = (Vec::new(), Vec::new());
+    for &byte in data {
+        requests.feed(byte);
+        responses.feed(byte);
+        from_requests.extend(core::iter::from_fn(|| requests.pop()));
+        from_responses.extend(core::iter::from_fn(|| responses.pop()));
+    }
+    assert_eq!(from_requests, decode_msgs(data));
+    assert_eq!(from_responses, decode_responses(data));
+});
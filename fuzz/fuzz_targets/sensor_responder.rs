@@ -0,0 +1,34 @@
+//! Drives a sensor bus with arbitrary traffic and timing. The input is a sequence of chunks:
+//! a time step in units of 8 us, a byte count in the low nibble of the next byte, and that
+//! many bytes to inject. Whatever the input, the bus may only ever transmit valid responses.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rustibus::RustIBus::IBusSensor;
+use rustibus::half_duplex::{SensorBus, Timing};
+use rustibus::registry::SensorRegistry;
+use rustibus::testkit::{MockSerial, decode_responses};
+
+fuzz_target!(|data: &[u8]| {
+    let mut temperature = || 400u32;
+    let mut pressure = || 101_325u32;
+    let mut registry = SensorRegistry::<2>::new();
+    registry.register(IBusSensor::TEMP, &mut temperature).unwrap();
+    registry.register(IBusSensor::PRESS, &mut pressure).unwrap();
+    let mut bus: SensorBus<_, _> = SensorBus::new(MockSerial::new().with_echo(), registry, Timing::default());
+
+    let mut now_us = 0u32;
+    let mut rest = data;
+    while let [step, count, tail @ ..] = rest {
+        let count = ((count & 0x0f) as usize).min(tail.len());
+        let (bytes, tail) = tail.split_at(count);
+        now_us = now_us.wrapping_add(*step as u32 * 8);
+        bus.port().inject(bytes);
+        bus.poll(now_us);
+        rest = tail;
+    }
+
+    let transmitted = bus.port().take_transmitted();
+    let responses = decode_responses(&transmitted);
+    assert_eq!(responses.len() as u32, bus.stats().responses);
+});
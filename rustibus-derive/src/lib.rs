@@ -0,0 +1,122 @@
+//! Derive macro for `rustibus::telemetry::IBusTelemetry`.
+//!
+//! Fields marked with `#[ibus(sensor = "TEMP")]` become sensors, numbered from address 1
+//! in declaration order. The value length follows from the sensor type, unless it is given
+//! explicitly with `#[ibus(sensor = "RPM", length = "Long")]`.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Ident, LitStr};
+
+
+/// IBus addresses 1 up to 15 are available for sensors.
+const MAX_SENSORS: usize = 15;
+
+
+struct SensorField {
+    member: syn::Member,
+    sensor: Ident,
+    length: Option<Ident>,
+}
+
+
+#[proc_macro_derive(IBusTelemetry, attributes(ibus))]
+pub fn derive_ibus_telemetry(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+
+fn expand(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => return Err(syn::Error::new_spanned(input, "IBusTelemetry can only be derived for structs")),
+    };
+
+    let sensors = sensor_fields(fields)?;
+    if sensors.is_empty() {
+        return Err(syn::Error::new_spanned(input, "IBusTelemetry needs at least one field marked #[ibus(sensor = \"...\")]"));
+    }
+    if sensors.len() > MAX_SENSORS {
+        return Err(syn::Error::new_spanned(input, "IBus supports at most 15 sensors"));
+    }
+
+    let lengths = sensors.iter().map(|s| {
+        let sensor = &s.sensor;
+        match &s.length {
+            Some(length) => quote! { ::rustibus::RustIBus::IBusSensorLength::#length },
+            None => quote! { ::rustibus::RustIBus::IBusSensor::#sensor.default_length() },
+        }
+    });
+    let table = sensors.iter().zip(lengths).map(|(s, length)| {
+        let sensor = &s.sensor;
+        quote! { (::rustibus::RustIBus::IBusSensor::#sensor, #length) }
+    });
+    let values = sensors.iter().enumerate().map(|(i, s)| {
+        let member = &s.member;
+        let sensor = &s.sensor;
+        quote! {
+            #i => ::rustibus::telemetry::SensorValue::to_ibus(&self.#member, ::rustibus::RustIBus::IBusSensor::#sensor),
+        }
+    });
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::rustibus::telemetry::IBusTelemetry for #name #ty_generics #where_clause {
+            const SENSORS: &'static [(::rustibus::RustIBus::IBusSensor, ::rustibus::RustIBus::IBusSensorLength)] = &[
+                #(#table),*
+            ];
+
+            fn sensor_value(&self, index: usize) -> u32 {
+                match index {
+                    #(#values)*
+                    _ => 0,
+                }
+            }
+        }
+    })
+}
+
+
+fn sensor_fields(fields: &Fields) -> syn::Result<Vec<SensorField>> {
+    let mut sensors = Vec::new();
+    for (index, field) in fields.iter().enumerate() {
+        let member = match &field.ident {
+            Some(ident) => syn::Member::Named(ident.clone()),
+            None => syn::Member::Unnamed(index.into()),
+        };
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("ibus")) {
+            let mut sensor = None;
+            let mut length = None;
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("sensor") {
+                    sensor = Some(variant(&meta.value()?.parse()?)?);
+                    Ok(())
+                } else if meta.path.is_ident("length") {
+                    length = Some(variant(&meta.value()?.parse()?)?);
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `sensor` or `length`"))
+                }
+            })?;
+            match sensor {
+                Some(sensor) => sensors.push(SensorField { member: member.clone(), sensor, length }),
+                None => return Err(syn::Error::new_spanned(attr, "missing `sensor = \"...\"`")),
+            }
+        }
+    }
+    Ok(sensors)
+}
+
+
+fn variant(lit: &LitStr) -> syn::Result<Ident> {
+    // Keep the span of the string, so an unknown sensor type is reported where it was written.
+    syn::parse_str::<Ident>(&lit.value())
+        .map(|ident| Ident::new(&ident.to_string(), lit.span()))
+        .map_err(|_| syn::Error::new(lit.span(), "expected the name of a variant, e.g. \"TEMP\""))
+}
+
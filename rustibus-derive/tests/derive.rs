@@ -0,0 +1,43 @@
+use rustibus::RustIBus::{IBusMsg, IBusSensor, IBusSensorLength};
+use rustibus::telemetry::IBusTelemetry;
+
+
+#[derive(IBusTelemetry)]
+struct Telemetry {
+    #[ibus(sensor = "TEMP")]
+    temperature: f32,
+    #[allow(dead_code)]
+    name: &'static str,
+    #[ibus(sensor = "EXTV")]
+    battery: f32,
+    #[ibus(sensor = "RPM", length = "Long")]
+    rpm: u32,
+}
+
+#[derive(IBusTelemetry)]
+struct Tuple(#[ibus(sensor = "PRESS")] u32);
+
+
+#[test]
+fn test_sensor_table() {
+    assert_eq!(Telemetry::SENSORS, &[
+        (IBusSensor::TEMP, IBusSensorLength::Short),
+        (IBusSensor::EXTV, IBusSensorLength::Short),
+        (IBusSensor::RPM, IBusSensorLength::Long),
+    ]);
+    assert_eq!(Tuple::SENSORS, &[(IBusSensor::PRESS, IBusSensorLength::Long)]);
+}
+
+#[test]
+fn test_responses() {
+    let t = Telemetry { temperature: 30.0, name: "node", battery: 11.1, rpm: 80_000 };
+    assert_eq!(t.respond(&IBusMsg::DiscoveryRequest(3)), Some(IBusMsg::DiscoveryResponse(3)));
+    assert_eq!(t.respond(&IBusMsg::DiscoveryRequest(4)), None);
+    assert_eq!(t.respond(&IBusMsg::TypeRequest(2)),
+               Some(IBusMsg::TypeResponse(2, IBusSensor::EXTV, IBusSensorLength::Short)));
+    assert_eq!(t.respond(&IBusMsg::ValueRequest(1)), Some(IBusMsg::ValueResponseShort(1, 700)));
+    assert_eq!(t.respond(&IBusMsg::ValueRequest(2)), Some(IBusMsg::ValueResponseShort(2, 1110)));
+    assert_eq!(t.respond(&IBusMsg::ValueRequest(3)), Some(IBusMsg::ValueResponseLong(3, 80_000)));
+
+    assert_eq!(Tuple(101_325).respond(&IBusMsg::ValueRequest(1)), Some(IBusMsg::ValueResponseLong(1, 101_325)));
+}
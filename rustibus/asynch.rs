@@ -0,0 +1,225 @@
+
+use embedded_io_async::{Read, Write};
+
+use crate::RustIBus::{IBusMsg, popIBusMsg, pushIBusMsg, MAX_LENGTH};
+use crate::deque::Deque;
+use crate::half_duplex::EchoFilter;
+use crate::registry::SensorResponder;
+
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Error<E> {
+    Io(E),
+    /// The stream ended.
+    Eof,
+}
+
+
+/// Reads the available bytes into `buffer`, at most as many as fit.
+async fn fill<R: Read, const SIZE: usize>(serial: &mut R, buffer: &mut Deque<SIZE>) -> Result<(), Error<R::Error>> {
+    let mut chunk = [0u8; MAX_LENGTH as usize];
+    // The deque holds at most SIZE - 1 bytes. A full message is always popped before we get
+    // here, so with SIZE checked in the constructors there is room for at least one byte.
+    let n = chunk.len().min(SIZE - 1 - buffer.len());
+    match serial.read(&mut chunk[..n]).await {
+        Ok(0) => Err(Error::Eof),
+        Ok(n) => {
+            buffer.load(&chunk[..n]);
+            Ok(())
+        },
+        Err(e) => Err(Error::Io(e))
+    }
+}
+
+/// Pops the next complete message from `buffer`, skipping anything that is not a message.
+fn next_msg<const SIZE: usize>(buffer: &mut Deque<SIZE>) -> Option<IBusMsg> {
+    while !Deque::is_empty(buffer) {
+        let (msg, step) = popIBusMsg(buffer);
+        if step == 0 {
+            break;
+        }
+        for _ in 0..step {
+            buffer.pop();
+        }
+        if msg.is_some() {
+            return msg;
+        }
+    }
+    None
+}
+
+
+/// Decodes the servo stream of an IBus receiver from an async serial port.
+pub struct IBusReceiver<R: Read, const SIZE: usize = 64> {
+    serial: R,
+    buffer: Deque<SIZE>,
+}
+
+impl<R: Read, const SIZE: usize> IBusReceiver<R, SIZE> {
+    pub fn new(serial: R) -> Self {
+        const { assert!(SIZE > MAX_LENGTH as usize + 1, "SIZE must hold a complete message") };
+        Self { serial, buffer: Deque::new() }
+    }
+
+    pub fn release(self) -> R { self.serial }
+
+    /// Wait for the next complete message.
+    pub async fn next_frame(&mut self) -> Result<IBusMsg, Error<R::Error>> {
+        loop {
+            if let Some(msg) = next_msg(&mut self.buffer) {
+                return Ok(msg);
+            }
+            fill(&mut self.serial, &mut self.buffer).await?;
+        }
+    }
+}
+
+
+/// Answers the sensor requests of a receiver on an async serial port.
+///
+/// Responses are written as soon as the request is decoded; the echo of what we write is
+/// dropped when it is read back on a single-wire port.
+pub struct SensorPort<RW: Read + Write, S: SensorResponder, const SIZE: usize = 64> {
+    serial: RW,
+    responder: S,
+    buffer: Deque<SIZE>,
+    echo: EchoFilter,
+}
+
+impl<RW: Read + Write, S: SensorResponder, const SIZE: usize> SensorPort<RW, S, SIZE> {
+    pub fn new(serial: RW, responder: S) -> Self {
+        const { assert!(SIZE > MAX_LENGTH as usize + 1, "SIZE must hold a complete message") };
+        Self { serial, responder, buffer: Deque::new(), echo: EchoFilter::new() }
+    }
+
+    pub fn responder(&mut self) -> &mut S { &mut self.responder }
+    pub fn release(self) -> (RW, S) { (self.serial, self.responder) }
+
+    /// Wait for the next request for one of our sensors, and answer it.
+    pub async fn respond_next(&mut self) -> Result<(), Error<RW::Error>> {
+        loop {
+            while let Some(msg) = next_msg(&mut self.buffer) {
+                if let Some(response) = self.responder.respond(&msg) {
+                    let mut tx = [0u8; MAX_LENGTH as usize];
+                    let n = pushIBusMsg(&response, &mut tx) as usize;
+                    self.echo.expect(&tx[..n]);
+                    self.serial.write_all(&tx[..n]).await.map_err(Error::Io)?;
+                    self.serial.flush().await.map_err(Error::Io)?;
+                    return Ok(());
+                }
+            }
+
+            let mut chunk = [0u8; MAX_LENGTH as usize];
+            let n = chunk.len().min(SIZE - 1 - self.buffer.len());
+            let n = match self.serial.read(&mut chunk[..n]).await {
+                Ok(0) => return Err(Error::Eof),
+                Ok(n) => n,
+                Err(e) => return Err(Error::Io(e))
+            };
+            for byte in &chunk[..n] {
+                if let (Some(byte), _) = self.echo.filter(*byte) {
+                    self.buffer.push(byte);
+                }
+            }
+        }
+    }
+
+    /// Answer requests until the port fails.
+    pub async fn run(&mut self) -> Error<RW::Error> {
+        loop {
+            if let Err(e) = self.respond_next().await {
+                return e;
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RustIBus::IBusSensor;
+    use crate::registry::SensorRegistry;
+    use embedded_io_async::{ErrorKind, ErrorType};
+    use futures_executor::block_on;
+
+    /// One end of an in-memory pipe: reads come from a script, in small chunks, and
+    /// writes are collected. With `echo`, everything written is also read back.
+    struct Pipe<'a> {
+        rx: &'a [u8],
+        echo: Deque<64>,
+        tx: Deque<64>,
+        with_echo: bool,
+    }
+
+    impl<'a> Pipe<'a> {
+        fn new(rx: &'a [u8], with_echo: bool) -> Self {
+            Pipe { rx, echo: Deque::new(), tx: Deque::new(), with_echo }
+        }
+    }
+
+    impl ErrorType for Pipe<'_> {
+        type Error = ErrorKind;
+    }
+
+    impl Read for Pipe<'_> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+            if !self.echo.is_empty() {
+                let mut n = 0;
+                while n < buf.len() && !self.echo.is_empty() {
+                    buf[n] = self.echo.pop();
+                    n += 1;
+                }
+                return Ok(n);
+            }
+            let n = buf.len().min(self.rx.len()).min(3);
+            buf[..n].copy_from_slice(&self.rx[..n]);
+            self.rx = &self.rx[n..];
+            Ok(n)
+        }
+    }
+
+    impl Write for Pipe<'_> {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+            self.tx.load(buf);
+            if self.with_echo {
+                self.echo.load(buf);
+            }
+            Ok(buf.len())
+        }
+    }
+
+    #[test]
+    fn test_next_frame() {
+        let stream = [0x55, 0x04, 0x81, 0x7a, 0xff,
+                      0x20, 0x40, 0xDB, 0x05, 0xDC, 0x05, 0x54, 0x05,
+                      0xDC, 0x05, 0xE8, 0x03, 0xD0, 0x07, 0xD2, 0x05,
+                      0xE8, 0x03, 0xDC, 0x05, 0xDC, 0x05, 0xDC, 0x05,
+                      0xDC, 0x05, 0xDC, 0x05, 0xDC, 0x05, 0xDA, 0xF3, 0x20];
+        let mut receiver = IBusReceiver::<_>::new(Pipe::new(&stream, false));
+        block_on(async {
+            assert_eq!(receiver.next_frame().await, Ok(IBusMsg::DiscoveryRequest(1)));
+            assert_eq!(receiver.next_frame().await, Ok(IBusMsg::SetMsg([
+                0x5DB, 0x5DC, 0x554, 0x5DC, 0x3E8, 0x7D0, 0x5D2,
+                0x3E8, 0x5DC, 0x5DC, 0x5DC, 0x5DC, 0x5DC, 0x5DC])));
+            assert_eq!(receiver.next_frame().await, Err(Error::Eof));
+        });
+    }
+
+    #[test]
+    fn test_sensor_port() {
+        // Discovery of addresses 1 and 2, then a value request for 1
+        let stream = [0x04, 0x81, 0x7a, 0xff, 0x04, 0x82, 0x79, 0xff, 0x04, 0xa1, 0x5a, 0xff];
+        let mut volt = || 1234u32;
+        let mut registry = SensorRegistry::<1>::new();
+        registry.register(IBusSensor::EXTV, &mut volt).unwrap();
+        let mut port = SensorPort::<_, _>::new(Pipe::new(&stream, true), registry);
+        assert_eq!(block_on(port.run()), Error::Eof);
+
+        let (pipe, _) = port.release();
+        let mut expected = [0u8; 10];
+        pushIBusMsg(&IBusMsg::DiscoveryResponse(1), &mut expected[..4]);
+        pushIBusMsg(&IBusMsg::ValueResponseShort(1, 1234), &mut expected[4..]);
+        assert!(pipe.tx.iter().eq(expected.iter().copied()));
+    }
+}
@@ -0,0 +1,143 @@
+
+use std::fmt::Write;
+use std::str::FromStr;
+
+use rustibus::RustIBus::IBusMsg;
+
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Format {
+    Table,
+    Json,
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "table" => Ok(Format::Table),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("unknown format '{}', expected table, json or csv", s))
+        }
+    }
+}
+
+
+/// The name, address and value of a message that is not a servo message.
+fn fields(msg: &IBusMsg) -> (&'static str, Option<u8>, Option<String>) {
+    match *msg {
+        IBusMsg::SetMsg(_) => ("set", None, None),
+        IBusMsg::DiscoveryRequest(addr) => ("discover_request", Some(addr), None),
+        IBusMsg::DiscoveryResponse(addr) => ("discover_response", Some(addr), None),
+        IBusMsg::TypeRequest(addr) => ("type_request", Some(addr), None),
+        IBusMsg::TypeResponse(addr, sensor, _) => ("type_response", Some(addr), Some(format!("{:?}", sensor))),
+        IBusMsg::ValueRequest(addr) => ("value_request", Some(addr), None),
+        IBusMsg::ValueResponseShort(addr, value) => ("value_response", Some(addr), Some(value.to_string())),
+        IBusMsg::ValueResponseLong(addr, value) => ("value_response", Some(addr), Some(value.to_string())),
+    }
+}
+
+
+/// The line printed before the first message, if the format has one.
+pub fn header(format: Format) -> Option<String> {
+    match format {
+        Format::Table => {
+            let mut line = format!("{:<17} {:>4} {:>10}", "type", "addr", "value");
+            for i in 1..=14 {
+                write!(line, " {:>5}", format!("ch{}", i)).unwrap();
+            }
+            Some(line)
+        },
+        Format::Csv => {
+            let mut line = String::from("type,addr,value");
+            for i in 1..=14 {
+                write!(line, ",ch{}", i).unwrap();
+            }
+            Some(line)
+        },
+        Format::Json => None
+    }
+}
+
+
+/// A message as a single line of output, without the line end.
+pub fn line(format: Format, msg: &IBusMsg) -> String {
+    let (name, addr, value) = fields(msg);
+    let channels = match msg {
+        IBusMsg::SetMsg(data) => Some(data),
+        _ => None
+    };
+    match format {
+        Format::Table => {
+            let mut line = format!("{:<17} {:>4} {:>10}", name,
+                                   addr.map(|a| a.to_string()).unwrap_or_default(), value.unwrap_or_default());
+            for v in channels.into_iter().flatten() {
+                write!(line, " {:>5}", v).unwrap();
+            }
+            line.trim_end().to_string()
+        },
+        Format::Csv => {
+            let mut line = format!("{},{},{}", name, addr.map(|a| a.to_string()).unwrap_or_default(),
+                                   value.unwrap_or_default());
+            match channels {
+                Some(data) => for v in data { write!(line, ",{}", v).unwrap(); },
+                None => line.push_str(&",".repeat(14))
+            }
+            line
+        },
+        Format::Json => {
+            let mut line = format!("{{\"type\":\"{}\"", name);
+            if let Some(addr) = addr {
+                write!(line, ",\"addr\":{}", addr).unwrap();
+            }
+            match *msg {
+                IBusMsg::TypeResponse(_, sensor, length) =>
+                    write!(line, ",\"sensor\":\"{:?}\",\"length\":{}", sensor, length as u8).unwrap(),
+                IBusMsg::ValueResponseShort(_, value) => write!(line, ",\"value\":{}", value).unwrap(),
+                IBusMsg::ValueResponseLong(_, value) => write!(line, ",\"value\":{}", value).unwrap(),
+                _ => ()
+            }
+            if let Some(data) = channels {
+                let values: Vec<String> = data.iter().map(|v| v.to_string()).collect();
+                write!(line, ",\"channels\":[{}]", values.join(",")).unwrap();
+            }
+            line.push('}');
+            line
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustibus::RustIBus::{IBusSensor, IBusSensorLength};
+
+    #[test]
+    fn test_lines() {
+        let set = IBusMsg::SetMsg([1500, 1000, 2000, 1500, 1500, 1500, 1500, 1500, 1500, 1500, 1500, 1500, 1500, 1500]);
+        let typ = IBusMsg::TypeResponse(2, IBusSensor::PRESS, IBusSensorLength::Long);
+        assert_eq!(line(Format::Json, &set),
+                   "{\"type\":\"set\",\"channels\":[1500,1000,2000,1500,1500,1500,1500,1500,1500,1500,1500,1500,1500,1500]}");
+        assert_eq!(line(Format::Json, &typ), "{\"type\":\"type_response\",\"addr\":2,\"sensor\":\"PRESS\",\"length\":4}");
+        assert_eq!(line(Format::Json, &IBusMsg::ValueResponseShort(1, 400)),
+                   "{\"type\":\"value_response\",\"addr\":1,\"value\":400}");
+
+        assert_eq!(line(Format::Csv, &set), "set,,,1500,1000,2000,1500,1500,1500,1500,1500,1500,1500,1500,1500,1500,1500");
+        assert_eq!(line(Format::Csv, &IBusMsg::DiscoveryRequest(3)), "discover_request,3,,,,,,,,,,,,,,,");
+        assert_eq!(header(Format::Csv).unwrap().split(',').count(), line(Format::Csv, &set).split(',').count());
+
+        assert_eq!(line(Format::Table, &IBusMsg::ValueRequest(1)), "value_request        1");
+        let row = line(Format::Table, &set);
+        assert_eq!(row.len(), header(Format::Table).unwrap().len());
+        assert!(row.ends_with(" 1500  1000  2000  1500  1500  1500  1500  1500  1500  1500  1500  1500  1500  1500"));
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("csv".parse::<Format>(), Ok(Format::Csv));
+        assert!("xml".parse::<Format>().is_err());
+    }
+}
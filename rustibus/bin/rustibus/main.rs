@@ -0,0 +1,214 @@
+//! Decodes an IBus stream on a laptop, e.g. from a USB-UART adapter, to debug the wiring.
+
+use std::io::{self, Write};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use rustibus::stream::{LinkStats, StreamDecoder};
+
+mod format;
+mod monitor;
+mod source;
+
+use format::Format;
+use source::Source;
+
+
+const USAGE: &str = "\
+usage: rustibus [options] <source>
+       rustibus monitor [options] <source>
+
+Decodes the IBus messages in <source> and prints them on stdout.
+<source> is a serial device, a capture file, or - for stdin.
+The monitor shows live channel bars, the link health and the telemetry sensors instead;
+it does not take --format, --responses or --stats.
+
+options:
+  --format <table|json|csv>  output format (default: table)
+  --baud <rate>              baud rate of a serial device (default: 115200)
+  --responses                decode sensor responses instead of receiver messages
+  --stats                    print link statistics every second while reading a device
+  --channels <14|18>         number of channels shown by the monitor (default: 14)
+  --speed <factor>           replay speed of a capture file, 0 for no delays
+                             (default: 0, or 1 for the monitor)
+  -h, --help                 show this help
+
+Link statistics are printed on stderr at the end of a file.";
+
+
+#[derive(PartialEq, Debug)]
+struct Options {
+    source: String,
+    format: Format,
+    baud: u32,
+    responses: bool,
+    stats: bool,
+    monitor: bool,
+    channels: usize,
+    speed: Option<f64>,
+}
+
+fn parse_args<I: Iterator<Item=String>>(args: I) -> Result<Option<Options>, String> {
+    let mut source = None;
+    let mut options = Options { source: String::new(), format: Format::Table, baud: 115_200, responses: false,
+                                stats: false, monitor: false, channels: 14, speed: None };
+    let mut args = args.peekable();
+    if args.peek().map(String::as_str) == Some("monitor") {
+        options.monitor = true;
+        args.next();
+    }
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--format" | "--responses" | "--stats" if options.monitor =>
+                return Err(format!("{} can not be used with monitor", arg)),
+            "--format" => options.format = args.next().ok_or("--format needs a value")?.parse()?,
+            "--baud" => options.baud = args.next().ok_or("--baud needs a value")?
+                .parse().map_err(|_| "--baud needs a number")?,
+            "--responses" => options.responses = true,
+            "--stats" => options.stats = true,
+            "--speed" => options.speed = Some(args.next().ok_or("--speed needs a value")?
+                .parse().map_err(|_| "--speed needs a number")?),
+            "--channels" => options.channels = match args.next().as_deref() {
+                Some("14") => 14,
+                Some("18") => 18,
+                _ => return Err("--channels needs 14 or 18".to_string())
+            },
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ if source.is_none() => source = Some(arg),
+            _ => return Err(format!("unexpected argument {}", arg))
+        }
+    }
+    options.source = source.ok_or("no source given")?;
+    Ok(Some(options))
+}
+
+
+fn stats_line(stats: &LinkStats, elapsed: Option<Duration>) -> String {
+    let mut line = format!("bytes={} messages={} frames={} resync_bytes={} checksum_errors={}",
+                           stats.bytes, stats.messages, stats.set_msgs, stats.resync_bytes, stats.checksum_errors);
+    if let Some(elapsed) = elapsed {
+        let rate = stats.set_msgs as f64 / elapsed.as_secs_f64().max(1e-3);
+        line.push_str(&format!(" frame_rate={:.1}/s", rate));
+    }
+    line
+}
+
+
+fn since(now: &LinkStats, before: &LinkStats) -> LinkStats {
+    LinkStats {
+        bytes: now.bytes.wrapping_sub(before.bytes),
+        messages: now.messages.wrapping_sub(before.messages),
+        set_msgs: now.set_msgs.wrapping_sub(before.set_msgs),
+        resync_bytes: now.resync_bytes.wrapping_sub(before.resync_bytes),
+        checksum_errors: now.checksum_errors.wrapping_sub(before.checksum_errors),
+    }
+}
+
+
+fn run(options: &Options, source: &mut Source, out: &mut dyn Write) -> io::Result<LinkStats> {
+    let mut decoder: StreamDecoder = if options.responses { StreamDecoder::for_responses() } else { StreamDecoder::new() };
+    // Periodic statistics cover the last interval only.
+    let mut reported = LinkStats::default();
+    let mut last_report = Instant::now();
+    let mut buffer = [0u8; 256];
+
+    if let Some(header) = format::header(options.format) {
+        writeln!(out, "{}", header)?;
+    }
+    loop {
+        let n = source.read(&mut buffer)?;
+        if n == 0 && !source.is_live() {
+            break;
+        }
+        for &byte in &buffer[..n] {
+            decoder.feed(byte);
+            while let Some(msg) = decoder.pop() {
+                writeln!(out, "{}", format::line(options.format, &msg))?;
+            }
+        }
+        out.flush()?;
+        if options.stats && source.is_live() && last_report.elapsed() >= Duration::from_secs(1) {
+            let stats = decoder.stats();
+            eprintln!("{}", stats_line(&since(&stats, &reported), Some(last_report.elapsed())));
+            reported = stats;
+            last_report = Instant::now();
+        }
+    }
+    Ok(decoder.stats())
+}
+
+
+fn main() -> ExitCode {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        },
+        Err(e) => {
+            eprintln!("rustibus: {}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    let speed = options.speed.unwrap_or(if options.monitor { 1.0 } else { 0.0 });
+    let mut source = match Source::open(&options.source, options.baud, speed) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("rustibus: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    if options.monitor {
+        return match monitor::run(&options.source, source, options.channels) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("rustibus: {}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+    let stdout = io::stdout();
+    match run(&options, &mut source, &mut stdout.lock()) {
+        Ok(stats) => {
+            eprintln!("{}", stats_line(&stats, None));
+            ExitCode::SUCCESS
+        },
+        // The reader went away, e.g. `rustibus ... | head`.
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("rustibus: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> impl Iterator<Item=String> + '_ {
+        line.split_whitespace().map(String::from)
+    }
+
+    #[test]
+    fn test_args() {
+        assert_eq!(parse_args(args("--format csv --baud 9600 --stats /dev/ttyUSB0")), Ok(Some(Options {
+            source: "/dev/ttyUSB0".into(), format: Format::Csv, baud: 9600, responses: false, stats: true,
+            monitor: false, channels: 14, speed: None })));
+        assert_eq!(parse_args(args("--speed 0.5 x")).unwrap().unwrap().speed, Some(0.5));
+        let monitor = parse_args(args("monitor --channels 18 -")).unwrap().unwrap();
+        assert!(monitor.monitor);
+        assert_eq!(monitor.channels, 18);
+        assert!(parse_args(args("monitor --channels 16 -")).is_err());
+        assert!(parse_args(args("monitor --format csv -")).is_err());
+        assert!(parse_args(args("monitor --responses -")).is_err());
+        assert_eq!(parse_args(args("-")).unwrap().unwrap().format, Format::Table);
+        assert_eq!(parse_args(args("--help")), Ok(None));
+        assert!(parse_args(args("")).is_err());
+        assert!(parse_args(args("--baud fast x")).is_err());
+        assert!(parse_args(args("--verbose x")).is_err());
+        assert!(parse_args(args("a b")).is_err());
+    }
+}
@@ -0,0 +1,348 @@
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use crossterm::{cursor, event, execute, queue, style, terminal};
+use crossterm::event::{Event, KeyCode, KeyEventKind, KeyModifiers};
+
+use rustibus::RustIBus::{IBusMsg, IBusSensor, IBusSensorLength, unpackChannels, MAX_CHANNELS};
+use rustibus::stream::{LinkStats, StreamDecoder};
+
+use crate::source::Source;
+
+
+/// The servo range drawn by the channel bars.
+const MIN: u16 = 1000;
+const MAX: u16 = 2000;
+
+const REDRAW: Duration = Duration::from_millis(50);
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+
+#[derive(Default)]
+struct Sensor {
+    kind: Option<(IBusSensor, IBusSensorLength)>,
+    value: Option<u32>,
+}
+
+#[derive(PartialEq, Debug, Default, Clone, Copy)]
+struct Rates {
+    frames: f64,
+    checksum_errors: f64,
+    resync_bytes: f64,
+}
+
+
+/// What the monitor shows, built from the bytes seen on the bus.
+///
+/// On a sensor port, requests and responses share a wire. The bytes are decoded as both, so
+/// sensors show up once they answer a type or value request. The link health is that of the
+/// receiver's messages, so on a sensor port the responses also count as resync bytes.
+pub struct Monitor {
+    requests: StreamDecoder,
+    responses: StreamDecoder,
+    channels: usize,
+    values: Option<[u16; MAX_CHANNELS]>,
+    sensors: BTreeMap<u8, Sensor>,
+    window_start: Instant,
+    window_stats: LinkStats,
+    rates: Rates,
+}
+
+impl Monitor {
+    pub fn new(channels: usize, now: Instant) -> Self {
+        Monitor {
+            requests: StreamDecoder::new(),
+            responses: StreamDecoder::for_responses(),
+            channels,
+            values: None,
+            sensors: BTreeMap::new(),
+            window_start: now,
+            window_stats: LinkStats::default(),
+            rates: Rates::default(),
+        }
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.requests.feed(byte);
+            self.responses.feed(byte);
+            while let Some(msg) = self.requests.pop() {
+                self.handle(msg);
+            }
+            while let Some(msg) = self.responses.pop() {
+                self.handle(msg);
+            }
+        }
+    }
+
+    fn handle(&mut self, msg: IBusMsg) {
+        match msg {
+            IBusMsg::SetMsg(data) => {
+                self.values = Some(if self.channels > 14 {
+                    unpackChannels(&data)
+                } else {
+                    let mut values = [0u16; MAX_CHANNELS];
+                    values[..14].copy_from_slice(&data);
+                    values
+                });
+            },
+            IBusMsg::TypeResponse(addr, sensor, length) =>
+                self.sensors.entry(addr).or_default().kind = Some((sensor, length)),
+            IBusMsg::ValueResponseShort(addr, value) =>
+                self.sensors.entry(addr).or_default().value = Some(value as u32),
+            IBusMsg::ValueResponseLong(addr, value) =>
+                self.sensors.entry(addr).or_default().value = Some(value),
+            // Discovery requests and responses are the same bytes, so they tell nothing.
+            _ => ()
+        }
+    }
+
+    /// Updates the rates once per rate window.
+    pub fn tick(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed < RATE_WINDOW {
+            return;
+        }
+        let stats = self.requests.stats();
+        let per_second = |now: u32, before: u32| now.wrapping_sub(before) as f64 / elapsed.as_secs_f64();
+        self.rates = Rates {
+            frames: per_second(stats.set_msgs, self.window_stats.set_msgs),
+            checksum_errors: per_second(stats.checksum_errors, self.window_stats.checksum_errors),
+            resync_bytes: per_second(stats.resync_bytes, self.window_stats.resync_bytes),
+        };
+        self.window_stats = stats;
+        self.window_start = now;
+    }
+
+    /// The screen contents, as lines of at most `width` characters.
+    pub fn render(&self, title: &str, width: usize) -> Vec<String> {
+        let stats = self.requests.stats();
+        let mut lines = vec![
+            format!("rustibus monitor - {}  (q to quit)", title),
+            format!("frames {:.1}/s   checksum errors {:.1}/s ({:.1}%)   resync {:.0} B/s",
+                    self.rates.frames, self.rates.checksum_errors,
+                    percentage(self.rates.checksum_errors, self.rates.frames + self.rates.checksum_errors),
+                    self.rates.resync_bytes),
+            format!("total: {} frames, {} checksum errors, {} resync bytes",
+                    stats.set_msgs, stats.checksum_errors, stats.resync_bytes),
+            String::new(),
+        ];
+        match self.values {
+            Some(values) => {
+                let bar_width = width.saturating_sub(14).max(10);
+                for (i, value) in values[..self.channels].iter().enumerate() {
+                    lines.push(format!("ch{:<3} {:>5} {}", i + 1, value, bar(*value, bar_width)));
+                }
+            },
+            None => lines.push("waiting for servo frames...".to_string()),
+        }
+        lines.push(String::new());
+        if self.sensors.is_empty() {
+            lines.push("no sensors seen".to_string());
+        } else {
+            lines.push("addr  type     value".to_string());
+            for (addr, sensor) in &self.sensors {
+                let kind = sensor.kind.map(|(s, _)| format!("{:?}", s)).unwrap_or_else(|| "?".to_string());
+                let value = match (sensor.value, sensor.kind) {
+                    (Some(v), Some((s, _))) => format!("{}{}", v, physical(s, v)),
+                    (Some(v), None) => v.to_string(),
+                    (None, _) => "-".to_string(),
+                };
+                lines.push(format!("{:>4}  {:<8} {}", addr, kind, value));
+            }
+        }
+        lines.into_iter().map(|l| l.chars().take(width).collect()).collect()
+    }
+}
+
+
+fn percentage(part: f64, total: f64) -> f64 {
+    if total > 0.0 { 100.0 * part / total } else { 0.0 }
+}
+
+/// A bar filled in proportion to the position of `value` in the servo range.
+/// Values outside of the range are marked at the end they exceed.
+fn bar(value: u16, width: usize) -> String {
+    let inner = width - 2;
+    let clamped = value.clamp(MIN, MAX);
+    let filled = ((clamped - MIN) as usize * inner + (MAX - MIN) as usize / 2) / (MAX - MIN) as usize;
+    let left = if value < MIN { '<' } else { '|' };
+    let right = if value > MAX { '>' } else { '|' };
+    format!("{}{}{}{}", left, "#".repeat(filled), " ".repeat(inner - filled), right)
+}
+
+/// The value of a sensor in physical units, for the sensor types that have them.
+fn physical(sensor: IBusSensor, value: u32) -> String {
+    match sensor {
+        IBusSensor::TEMP => format!(" ({:.1} °C)", value as f32 / 10.0 - 40.0),
+        IBusSensor::INTV | IBusSensor::EXTV => format!(" ({:.2} V)", value as f32 / 100.0),
+        IBusSensor::RPM => " rpm".to_string(),
+        _ => String::new(),
+    }
+}
+
+
+/// Restores the terminal when the monitor stops, also on errors.
+struct Screen;
+
+impl Screen {
+    fn enter() -> io::Result<Screen> {
+        terminal::enable_raw_mode()?;
+        execute!(io::stdout(), terminal::EnterAlternateScreen, cursor::Hide)?;
+        Ok(Screen)
+    }
+
+    fn draw(&self, lines: &[String]) -> io::Result<()> {
+        let (_, height) = terminal::size()?;
+        let mut out = io::stdout().lock();
+        for (row, line) in lines.iter().take(height as usize).enumerate() {
+            queue!(out, cursor::MoveTo(0, row as u16), style::Print(line),
+                   terminal::Clear(terminal::ClearType::UntilNewLine))?;
+        }
+        queue!(out, terminal::Clear(terminal::ClearType::FromCursorDown))?;
+        out.flush()
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = execute!(io::stdout(), cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+
+fn quit_requested() -> io::Result<bool> {
+    while event::poll(Duration::ZERO)? {
+        if let Event::Key(key) = event::read()? {
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(true),
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(true),
+                _ => ()
+            }
+        }
+    }
+    Ok(false)
+}
+
+
+/// Runs the monitor until the user quits. The source is read on its own thread, so
+/// the screen keeps updating while a read blocks.
+pub fn run(title: &str, mut source: Source, channels: usize) -> io::Result<()> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut buffer = [0u8; 256];
+        loop {
+            match source.read(&mut buffer) {
+                Ok(0) if !source.is_live() => break,
+                Ok(n) => if tx.send(buffer[..n].to_vec()).is_err() { break },
+                Err(_) => break,
+            }
+        }
+    });
+
+    let screen = Screen::enter()?;
+    let mut monitor = Monitor::new(channels, Instant::now());
+    let mut ended = false;
+    loop {
+        let deadline = Instant::now() + REDRAW;
+        while !ended {
+            match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(bytes) => monitor.feed(&bytes),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => ended = true,
+            }
+        }
+        if ended {
+            std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+        }
+        monitor.tick(Instant::now());
+        let (width, _) = terminal::size()?;
+        let mut lines = monitor.render(title, width as usize);
+        if ended {
+            lines.push(String::new());
+            lines.push("end of input".to_string());
+        }
+        screen.draw(&lines)?;
+        if quit_requested()? {
+            return Ok(());
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustibus::RustIBus::{pushIBusMsg, packChannels};
+
+    fn encode(msg: &IBusMsg) -> Vec<u8> {
+        let mut buffer = [0u8; 32];
+        let n = pushIBusMsg(msg, &mut buffer) as usize;
+        buffer[..n].to_vec()
+    }
+
+    #[test]
+    fn test_bar() {
+        assert_eq!(bar(1000, 12), "|          |");
+        assert_eq!(bar(1500, 12), "|#####     |");
+        assert_eq!(bar(2000, 12), "|##########|");
+        assert_eq!(bar(900, 12), "<          |");
+        assert_eq!(bar(2100, 12), "|##########>");
+    }
+
+    #[test]
+    fn test_monitor() {
+        let start = Instant::now();
+        let mut monitor = Monitor::new(14, start);
+        assert!(monitor.render("test", 40).contains(&"waiting for servo frames...".to_string()));
+
+        let mut data = [1500u16; 14];
+        data[0] = 1000;
+        data[1] = 2000;
+        let mut bytes = Vec::new();
+        for _ in 0..10 {
+            bytes.extend(encode(&IBusMsg::SetMsg(data)));
+        }
+        let mut bad = encode(&IBusMsg::SetMsg(data));
+        bad[3] ^= 0x01;
+        bytes.extend(bad);
+        // A sensor port: requests interleaved with responses
+        bytes.extend(encode(&IBusMsg::TypeRequest(1)));
+        bytes.extend(encode(&IBusMsg::TypeResponse(1, IBusSensor::TEMP, IBusSensorLength::Short)));
+        bytes.extend(encode(&IBusMsg::ValueRequest(1)));
+        bytes.extend(encode(&IBusMsg::ValueResponseShort(1, 650)));
+        bytes.extend(encode(&IBusMsg::ValueResponseShort(3, 1234)));
+        monitor.feed(&bytes);
+        monitor.tick(start + Duration::from_secs(2));
+
+        let lines = monitor.render("test", 40);
+        assert!(monitor.render("test", 80)[1].starts_with("frames 5.0/s   checksum errors 0.5/s (9.1%)   resync "));
+        assert_eq!(lines[1], "frames 5.0/s   checksum errors 0.5/s (9.");
+        assert!(lines[2].starts_with("total: 10 frames, 1 checksum errors,"));
+        assert_eq!(lines[4], format!("ch1    1000 |{}|", " ".repeat(24)));
+        assert_eq!(lines[5], format!("ch2    2000 |{}|", "#".repeat(24)));
+        assert_eq!(lines[6], format!("ch3    1500 |{}{}|", "#".repeat(12), " ".repeat(12)));
+        assert_eq!(lines.len(), 4 + 14 + 1 + 3);
+        assert_eq!(lines[19], "addr  type     value");
+        assert_eq!(lines[20], "   1  TEMP     650 (25.0 °C)");
+        assert_eq!(lines[21], "   3  ?        1234");
+    }
+
+    #[test]
+    fn test_18channels() {
+        let mut values = [1500u16; MAX_CHANNELS];
+        values[17] = 1900;
+        let mut monitor = Monitor::new(18, Instant::now());
+        monitor.feed(&encode(&IBusMsg::SetMsg(packChannels(&values))));
+        let lines = monitor.render("test", 80);
+        assert!(lines[4].starts_with("ch1    1500 "));
+        assert!(lines[21].starts_with("ch18   1900 "));
+    }
+}
@@ -0,0 +1,98 @@
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read};
+use std::time::{Duration, Instant};
+
+use rustibus::capture::CaptureReader;
+
+
+/// How long a read from a serial device waits, so statistics keep coming on a silent link.
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+
+#[cfg(unix)]
+fn is_device(path: &str) -> Result<bool, String> {
+    use std::os::unix::fs::FileTypeExt;
+    let metadata = std::fs::metadata(path).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(metadata.file_type().is_char_device())
+}
+
+#[cfg(not(unix))]
+fn is_device(path: &str) -> Result<bool, String> {
+    // COM ports are not files
+    Ok(!std::path::Path::new(path).is_file())
+}
+
+
+/// Where the raw bytes come from.
+pub enum Source {
+    Stdin(io::Stdin),
+    File(File),
+    Serial(Box<dyn serialport::SerialPort>),
+    /// A capture file, played back at `speed` times the recorded pace (0 for no delays).
+    Capture { records: VecDeque<(u64, Vec<u8>)>, speed: f64, start: Option<Instant> },
+}
+
+impl Source {
+    /// Opens `path`: `-` for stdin, a character device as a serial port at `baud`, or a file.
+    /// Capture files are recognised by their header and replayed at `speed`.
+    pub fn open(path: &str, baud: u32, speed: f64) -> Result<Source, String> {
+        if path == "-" {
+            return Ok(Source::Stdin(io::stdin()));
+        }
+        if is_device(path)? {
+            let port = serialport::new(path, baud)
+                .timeout(READ_TIMEOUT)
+                .open()
+                .map_err(|e| format!("{}: {}", path, e))?;
+            Ok(Source::Serial(port))
+        } else {
+            let mut file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+            let mut magic = [0u8; 4];
+            let is_capture = file.read_exact(&mut magic).is_ok() && CaptureReader::is_capture(&magic);
+            if is_capture {
+                let data = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+                let reader = CaptureReader::new(&data).map_err(|e| format!("{}: {:?}", path, e))?;
+                let records = reader.map(|r| r.map(|r| (r.time_us, r.bytes.to_vec())))
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| format!("{}: {:?}", path, e))?;
+                Ok(Source::Capture { records, speed, start: None })
+            } else {
+                File::open(path).map(Source::File).map_err(|e| format!("{}: {}", path, e))
+            }
+        }
+    }
+
+    /// Live sources never end; their statistics are shown while running.
+    pub fn is_live(&self) -> bool {
+        matches!(self, Source::Serial(_))
+    }
+
+    /// Reads the next bytes. Returns `Ok(0)` at the end of a file, and also when a serial
+    /// port had nothing to read for a while.
+    pub fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Source::Stdin(stdin) => stdin.read(buffer),
+            Source::File(file) => file.read(buffer),
+            Source::Serial(port) => match port.read(buffer) {
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(0),
+                other => other
+            },
+            Source::Capture { records, speed, start } => {
+                let Some((time_us, mut bytes)) = records.pop_front() else { return Ok(0) };
+                let start = *start.get_or_insert_with(Instant::now);
+                if *speed > 0.0 {
+                    let due = start + Duration::from_secs_f64(time_us as f64 / 1e6 / *speed);
+                    std::thread::sleep(due.saturating_duration_since(Instant::now()));
+                }
+                let n = bytes.len().min(buffer.len());
+                buffer[..n].copy_from_slice(&bytes[..n]);
+                if n < bytes.len() {
+                    records.push_front((time_us, bytes.split_off(n)));
+                }
+                Ok(n)
+            }
+        }
+    }
+}
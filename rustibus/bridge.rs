@@ -0,0 +1,334 @@
+//! Connects a receiver of one protocol to a flight controller that expects another.
+//!
+//! The bridge decodes the incoming stream and sends the latest channel frame in the other
+//! protocol at that protocol's own rate. Channel values keep their stick position: 1000 - 2000 us
+//! in IBus is 172 - 1811 in SBUS, rather than the 988 - 2012 us of `sbus::raw_to_us`.
+
+use crate::RustIBus::{IBusMsg, pushIBusMsg, packChannels, MAX_CHANNELS};
+use crate::rc::{ChannelFrame, RcInput, CENTER_US};
+use crate::sbus::{SbusDecoder, SbusFrame, CHANNELS, FRAME_LENGTH, RAW_MIN};
+use crate::stream::StreamDecoder;
+use crate::transmitter::ChannelCount;
+
+
+/// A raw SBUS value in microseconds as the bridge sends it: 172 - 1811 map to 1000 - 2000.
+const fn sbus_to_us(raw: u16) -> u16 {
+    let offset = raw as i32 - RAW_MIN as i32;
+    (1000 + (offset * 2000 + 1639).div_euclid(3278)) as u16
+}
+
+/// The reverse of `sbus_to_us`, limited to the 11 bits of a channel.
+fn us_to_sbus(us: u16) -> u16 {
+    let offset = us as i32 - 1000;
+    let raw = RAW_MIN as i32 + (offset * 3278 + 1000).div_euclid(2000);
+    raw.clamp(0, 0x7ff) as u16
+}
+
+
+/// An SBUS receiver as the input of a bridge: an `SbusDecoder` whose channel frames are
+/// scaled as the bridge sends them, so full stick in SBUS is full stick in IBus.
+pub struct SbusInput<const SIZE: usize = 64>(pub SbusDecoder<SIZE>);
+
+impl<const SIZE: usize> RcInput for SbusInput<SIZE> {
+    fn feed(&mut self, byte: u8) { self.0.feed(byte) }
+
+    fn pop_frame(&mut self) -> Option<ChannelFrame> {
+        let sbus = self.0.pop()?;
+        // Channels 17 and 18 are digital, and come out the same either way
+        let mut values = [0u16; CHANNELS + 2];
+        values.copy_from_slice(sbus.to_channel_frame().channels());
+        for (value, raw) in values.iter_mut().zip(sbus.channels.iter()) {
+            *value = sbus_to_us(*raw);
+        }
+        let mut frame = ChannelFrame::new(&values);
+        frame.failsafe = sbus.failsafe;
+        frame.frame_lost = sbus.frame_lost;
+        Some(frame)
+    }
+}
+
+
+/// What the bridge sends.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Output {
+    /// IBus servo messages with 14 or 18 channels, every 7 ms.
+    IBus(ChannelCount),
+    /// SBUS frames every 14 ms.
+    Sbus,
+}
+
+impl Output {
+    pub const fn period_us(&self) -> u32 {
+        match self {
+            Output::IBus(_) => 7_000,
+            Output::Sbus => 14_000,
+        }
+    }
+}
+
+
+/// Re-encodes the channel frames of an `RcInput` in the `Output` protocol.
+///
+/// Hand every received byte to `feed`, and call `poll` often with a microsecond timestamp;
+/// when a frame is due, `poll` writes it and returns its length.
+///
+/// Failsafe is carried across both ways. When no frames arrive for `timeout_us`, or the input
+/// flags failsafe, SBUS output keeps sending the last values with the failsafe and frame lost
+/// flags set, while IBus output stops, as an IBus receiver does when it loses the transmitter.
+pub struct Bridge<I: RcInput> {
+    input: I,
+    output: Output,
+    period_us: u32,
+    timeout_us: u32,
+    latest: Option<ChannelFrame>,
+    /// When the last frame came in, until the timeout passes.
+    received_us: Option<u32>,
+    next_us: u32,
+    frames: u32,
+}
+
+/// Reads IBus from a receiver and sends SBUS.
+pub type IBusToSbus = Bridge<StreamDecoder<64>>;
+/// Reads SBUS from a receiver and sends IBus.
+pub type SbusToIBus = Bridge<SbusInput<64>>;
+
+impl IBusToSbus {
+    pub fn ibus_to_sbus() -> Self {
+        Bridge::new(StreamDecoder::new(), Output::Sbus)
+    }
+}
+
+impl SbusToIBus {
+    pub fn sbus_to_ibus(count: ChannelCount) -> Self {
+        Bridge::new(SbusInput(SbusDecoder::new()), Output::IBus(count))
+    }
+}
+
+impl<I: RcInput> Bridge<I> {
+    /// Frames stop being fresh after this time without input, in microseconds.
+    pub const DEFAULT_TIMEOUT_US: u32 = 100_000;
+
+    pub fn new(input: I, output: Output) -> Self {
+        Bridge {
+            input,
+            output,
+            period_us: output.period_us(),
+            timeout_us: Self::DEFAULT_TIMEOUT_US,
+            latest: None,
+            received_us: None,
+            next_us: 0,
+            frames: 0,
+        }
+    }
+
+    /// Send frames every `period_us` instead of the protocol's usual rate, e.g. 7 ms for
+    /// high speed SBUS.
+    pub fn with_period(mut self, period_us: u32) -> Self {
+        self.period_us = period_us.max(1);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout_us: u32) -> Self {
+        self.timeout_us = timeout_us;
+        self
+    }
+
+    pub fn feed(&mut self, byte: u8) {
+        self.input.feed(byte);
+    }
+
+    /// Take in the frames received so far and write the next output frame into `out`
+    /// if it is due. Returns the number of bytes written; `out` needs 32 bytes.
+    pub fn poll(&mut self, now_us: u32, out: &mut [u8]) -> usize {
+        while let Some(frame) = self.input.pop_frame() {
+            if self.latest.is_none() {
+                self.next_us = now_us;
+            }
+            self.latest = Some(frame);
+            self.received_us = Some(now_us);
+        }
+        let Some(mut frame) = self.latest else { return 0 };
+        let late = now_us.wrapping_sub(self.next_us);
+        if (late as i32) < 0 {
+            return 0;
+        }
+        // Catch up after a long pause instead of sending a burst of frames
+        let base = if late >= self.period_us { now_us } else { self.next_us };
+        self.next_us = base.wrapping_add(self.period_us);
+
+        // Latch the timeout, or the old time would look recent again once the timer wraps
+        match self.received_us {
+            Some(received) if now_us.wrapping_sub(received) <= self.timeout_us => (),
+            _ => {
+                self.received_us = None;
+                frame.failsafe = true;
+                frame.frame_lost = true;
+            }
+        }
+        let length = match self.output {
+            Output::Sbus if out.len() >= FRAME_LENGTH => {
+                let mut sbus = SbusFrame::from_channel_frame(&frame);
+                for (raw, us) in sbus.channels.iter_mut().zip(frame.channels()) {
+                    *raw = us_to_sbus(*us);
+                }
+                out[..FRAME_LENGTH].copy_from_slice(&sbus.encode());
+                FRAME_LENGTH
+            }
+            Output::IBus(count) if !frame.failsafe => {
+                let mut values = [CENTER_US; MAX_CHANNELS];
+                for (value, channel) in values.iter_mut().zip(frame.channels()) {
+                    *value = *channel;
+                }
+                let data = match count {
+                    ChannelCount::Fourteen => values[..14].try_into().unwrap(),
+                    ChannelCount::Eighteen => packChannels(&values),
+                };
+                pushIBusMsg(&IBusMsg::SetMsg(data), out) as usize
+            }
+            _ => 0
+        };
+        if length > 0 {
+            self.frames = self.frames.wrapping_add(1);
+        }
+        length
+    }
+
+    /// The channel frame last received, if any.
+    pub fn latest(&self) -> Option<&ChannelFrame> { self.latest.as_ref() }
+
+    /// The number of frames sent so far.
+    pub fn frames(&self) -> u32 { self.frames }
+
+    pub fn input(&mut self) -> &mut I { &mut self.input }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sbus::RAW_MAX;
+    use crate::testkit::{decode_msgs, encode};
+
+    fn ibus_frame(first: u16) -> alloc::vec::Vec<u8> {
+        let mut data = [1500u16; 14];
+        data[0] = first;
+        encode(&IBusMsg::SetMsg(data))
+    }
+
+    #[test]
+    fn test_scaling() {
+        assert_eq!(sbus_to_us(RAW_MIN), 1000);
+        assert_eq!(sbus_to_us(992), 1500);
+        assert_eq!(sbus_to_us(RAW_MAX), 2000);
+        assert_eq!(sbus_to_us(0), 895);
+        assert_eq!(sbus_to_us(0x7ff), 2144);
+        for us in 895..=2144 {
+            assert_eq!(sbus_to_us(us_to_sbus(us)), us);
+        }
+        assert_eq!(us_to_sbus(1000), RAW_MIN);
+        assert_eq!(us_to_sbus(2000), RAW_MAX);
+        assert_eq!(us_to_sbus(0), 0);
+        assert_eq!(us_to_sbus(u16::MAX), 0x7ff);
+    }
+
+    #[test]
+    fn test_ibus_to_sbus() {
+        let mut bridge = Bridge::ibus_to_sbus();
+        let mut out = [0u8; 32];
+        assert_eq!(bridge.poll(0, &mut out), 0);
+
+        for b in ibus_frame(1000) {
+            bridge.feed(b);
+        }
+        assert_eq!(bridge.poll(1_000, &mut out), FRAME_LENGTH);
+        let frame = SbusFrame::decode(out[..FRAME_LENGTH].try_into().unwrap()).unwrap();
+        assert_eq!(frame.channels[0], RAW_MIN);
+        assert_eq!(frame.channels[1], 992);
+        assert!(!frame.failsafe);
+
+        // Nothing new before the next period, then the latest frame again
+        assert_eq!(bridge.poll(8_000, &mut out), 0);
+        for b in ibus_frame(2000) {
+            bridge.feed(b);
+        }
+        assert_eq!(bridge.poll(15_000, &mut out), FRAME_LENGTH);
+        let frame = SbusFrame::decode(out[..FRAME_LENGTH].try_into().unwrap()).unwrap();
+        assert_eq!(frame.channels[0], RAW_MAX);
+
+        // The receiver went quiet
+        assert_eq!(bridge.poll(29_000, &mut out), FRAME_LENGTH);
+        assert_eq!(bridge.poll(120_000, &mut out), FRAME_LENGTH);
+        let frame = SbusFrame::decode(out[..FRAME_LENGTH].try_into().unwrap()).unwrap();
+        assert!(frame.failsafe && frame.frame_lost);
+        assert_eq!(frame.channels[0], RAW_MAX);
+        assert_eq!(bridge.frames(), 4);
+    }
+
+    #[test]
+    fn test_timeout_wrap() {
+        let mut bridge = Bridge::ibus_to_sbus();
+        let mut out = [0u8; 32];
+        for b in ibus_frame(1500) {
+            bridge.feed(b);
+        }
+        assert_eq!(bridge.poll(0, &mut out), FRAME_LENGTH);
+        // Quiet for longer than it takes the timer to wrap around
+        for s in 1..=4_294u64 {
+            bridge.poll((s * 1_000_000) as u32, &mut out);
+        }
+        assert_eq!(bridge.poll(50_000, &mut out), FRAME_LENGTH);
+        let frame = SbusFrame::decode(out[..FRAME_LENGTH].try_into().unwrap()).unwrap();
+        assert!(frame.failsafe && frame.frame_lost);
+
+        // Until the receiver is back
+        for b in ibus_frame(1500) {
+            bridge.feed(b);
+        }
+        assert_eq!(bridge.poll(1_100_000, &mut out), FRAME_LENGTH);
+        let frame = SbusFrame::decode(out[..FRAME_LENGTH].try_into().unwrap()).unwrap();
+        assert!(!frame.failsafe);
+    }
+
+    #[test]
+    fn test_sbus_to_ibus() {
+        let mut bridge = Bridge::sbus_to_ibus(ChannelCount::Fourteen);
+        let mut out = [0u8; 32];
+        let mut frame = SbusFrame { channels: [992; CHANNELS], ..SbusFrame::default() };
+        frame.channels[2] = RAW_MAX;
+        for b in frame.encode() {
+            bridge.feed(b);
+        }
+        assert_eq!(bridge.poll(0, &mut out), 32);
+        let mut expected = [1500u16; 14];
+        expected[2] = 2000;
+        assert_eq!(decode_msgs(&out), [IBusMsg::SetMsg(expected)]);
+        assert_eq!(bridge.poll(7_000, &mut out), 32);
+
+        // The receiver lost the transmitter: IBus goes quiet until it is back
+        frame.failsafe = true;
+        for b in frame.encode() {
+            bridge.feed(b);
+        }
+        assert_eq!(bridge.poll(14_000, &mut out), 0);
+        frame.failsafe = false;
+        for b in frame.encode() {
+            bridge.feed(b);
+        }
+        assert_eq!(bridge.poll(21_000, &mut out), 32);
+    }
+
+    #[test]
+    fn test_18channels() {
+        let mut bridge = Bridge::sbus_to_ibus(ChannelCount::Eighteen);
+        let mut out = [0u8; 32];
+        let frame = SbusFrame { channels: [RAW_MIN; CHANNELS], ch17: true, ..SbusFrame::default() };
+        for b in frame.encode() {
+            bridge.feed(b);
+        }
+        assert_eq!(bridge.poll(0, &mut out), 32);
+        let [IBusMsg::SetMsg(data)] = decode_msgs(&out)[..] else { panic!() };
+        let values = crate::RustIBus::unpackChannels(&data);
+        assert_eq!(values[..16], [1000; 16]);
+        assert_eq!(values[16..], [2000, 1000]);
+    }
+}
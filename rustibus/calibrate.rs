@@ -0,0 +1,327 @@
+//! Learns the end points and centers of the sticks of a transmitter.
+//!
+//! Start a `Calibrator`, have the user move all sticks to their end points, call
+//! `capture_centers` once the sticks are released, and take the `Calibration` when it is done.
+//! The calibration fits in a small blob of bytes, e.g. to keep it in flash.
+
+use crate::RustIBus::{IBusMsg, MAX_CHANNELS};
+use crate::process::ChannelProcessor;
+use crate::rc::ChannelFrame;
+
+
+/// Channels that moved less than this are left at the default range, e.g. unused channels.
+pub const MIN_TRAVEL_US: u16 = 300;
+/// The number of frames the centers are averaged over, 224 ms at the IBus frame rate.
+pub const CENTER_FRAMES: u16 = 32;
+/// How far a channel may move while its center is taken.
+pub const CENTER_TOLERANCE_US: u16 = 10;
+
+pub const BLOB_VERSION: u8 = 1;
+/// Version and channel count, three values per channel and a CRC.
+pub const BLOB_LENGTH: usize = 2 + 6 * MAX_CHANNELS + 2;
+
+
+/// CRC-16 with polynomial 0x1021 and initial value 0xffff (CCITT-FALSE).
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct ChannelCalibration {
+    pub min_us: u16,
+    pub center_us: u16,
+    pub max_us: u16,
+}
+
+impl ChannelCalibration {
+    pub const DEFAULT: ChannelCalibration = ChannelCalibration { min_us: 1000, center_us: 1500, max_us: 2000 };
+
+    pub fn is_valid(&self) -> bool { self.min_us < self.center_us && self.center_us < self.max_us }
+}
+
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum BlobError {
+    /// The blob is shorter than `BLOB_LENGTH`.
+    Length,
+    /// The blob was written by another version, or the flash is erased.
+    Version,
+    Checksum,
+}
+
+
+/// The learned range of every channel.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Calibration {
+    pub channels: [ChannelCalibration; MAX_CHANNELS],
+}
+
+impl Calibration {
+    pub const fn new() -> Self {
+        Calibration { channels: [ChannelCalibration::DEFAULT; MAX_CHANNELS] }
+    }
+
+    /// Sets the end points and centers of the channels of `processor`.
+    /// Channels with an invalid calibration keep their settings.
+    pub fn apply<const N: usize>(&self, processor: &mut ChannelProcessor<N>) {
+        for (i, calibration) in self.channels.iter().enumerate() {
+            if let (Some(config), true) = (processor.config(i), calibration.is_valid()) {
+                config.min_us = calibration.min_us;
+                config.center_us = calibration.center_us;
+                config.max_us = calibration.max_us;
+            }
+        }
+    }
+
+    /// The calibration as bytes: the version, the number of channels, the minimum, center and
+    /// maximum of each channel as little endian 16 bit values, and a CRC-16 over all of these.
+    pub fn to_bytes(&self) -> [u8; BLOB_LENGTH] {
+        let mut blob = [0u8; BLOB_LENGTH];
+        blob[0] = BLOB_VERSION;
+        blob[1] = MAX_CHANNELS as u8;
+        for (i, channel) in self.channels.iter().enumerate() {
+            let at = 2 + 6 * i;
+            blob[at..at + 2].copy_from_slice(&channel.min_us.to_le_bytes());
+            blob[at + 2..at + 4].copy_from_slice(&channel.center_us.to_le_bytes());
+            blob[at + 4..at + 6].copy_from_slice(&channel.max_us.to_le_bytes());
+        }
+        let crc = crc16(&blob[..BLOB_LENGTH - 2]);
+        blob[BLOB_LENGTH - 2..].copy_from_slice(&crc.to_le_bytes());
+        blob
+    }
+
+    pub fn from_bytes(blob: &[u8]) -> Result<Calibration, BlobError> {
+        if blob.len() < BLOB_LENGTH {
+            return Err(BlobError::Length);
+        }
+        if blob[0] != BLOB_VERSION || blob[1] != MAX_CHANNELS as u8 {
+            return Err(BlobError::Version);
+        }
+        let crc = u16::from_le_bytes([blob[BLOB_LENGTH - 2], blob[BLOB_LENGTH - 1]]);
+        if crc16(&blob[..BLOB_LENGTH - 2]) != crc {
+            return Err(BlobError::Checksum);
+        }
+        let mut calibration = Calibration::new();
+        for (i, channel) in calibration.channels.iter_mut().enumerate() {
+            let at = 2 + 6 * i;
+            let value = |offset: usize| u16::from_le_bytes([blob[at + offset], blob[at + offset + 1]]);
+            *channel = ChannelCalibration { min_us: value(0), center_us: value(2), max_us: value(4) };
+        }
+        Ok(calibration)
+    }
+}
+
+impl Default for Calibration {
+    fn default() -> Self { Self::new() }
+}
+
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Phase {
+    /// Recording the end points while the user moves the sticks.
+    Endpoints,
+    /// Waiting for the sticks to rest, and averaging their centers.
+    Centers,
+    Done,
+}
+
+
+/// The calibration procedure, fed with the received frames.
+pub struct Calibrator {
+    phase: Phase,
+    min: [u16; MAX_CHANNELS],
+    max: [u16; MAX_CHANNELS],
+    first: [u16; MAX_CHANNELS],
+    sum: [u32; MAX_CHANNELS],
+    frames: u16,
+    calibration: Calibration,
+}
+
+impl Calibrator {
+    /// Starts recording end points.
+    pub const fn new() -> Self {
+        Calibrator {
+            phase: Phase::Endpoints,
+            min: [u16::MAX; MAX_CHANNELS],
+            max: [0; MAX_CHANNELS],
+            first: [0; MAX_CHANNELS],
+            sum: [0; MAX_CHANNELS],
+            frames: 0,
+            calibration: Calibration::new(),
+        }
+    }
+
+    pub fn phase(&self) -> Phase { self.phase }
+
+    /// The end points are done: take the centers from the next frames in which no stick moves.
+    pub fn capture_centers(&mut self) {
+        if self.phase == Phase::Endpoints {
+            self.phase = Phase::Centers;
+            self.frames = 0;
+        }
+    }
+
+    /// Handle a frame of channel values and return the phase the calibration is in.
+    pub fn feed(&mut self, channels: &[u16]) -> Phase {
+        let channels = &channels[..channels.len().min(MAX_CHANNELS)];
+        match self.phase {
+            Phase::Endpoints => {
+                for (i, value) in channels.iter().enumerate() {
+                    self.min[i] = self.min[i].min(*value);
+                    self.max[i] = self.max[i].max(*value);
+                }
+            }
+            Phase::Centers => {
+                // Start over when any stick still moves
+                if self.frames > 0 && channels.iter().zip(self.first.iter())
+                    .any(|(value, first)| value.abs_diff(*first) > CENTER_TOLERANCE_US) {
+                    self.frames = 0;
+                }
+                if self.frames == 0 {
+                    self.first = [0; MAX_CHANNELS];
+                    self.first[..channels.len()].copy_from_slice(channels);
+                    self.sum = [0; MAX_CHANNELS];
+                }
+                for (sum, value) in self.sum.iter_mut().zip(channels) {
+                    *sum += *value as u32;
+                }
+                self.frames += 1;
+                if self.frames == CENTER_FRAMES {
+                    self.finish();
+                }
+            }
+            Phase::Done => ()
+        }
+        self.phase
+    }
+
+    /// Handle the channels of a set message, unpacking 18 channel frames; other messages are ignored.
+    pub fn feed_msg(&mut self, msg: &IBusMsg) -> Phase {
+        match msg {
+            IBusMsg::SetMsg(data) => self.feed(ChannelFrame::from_set_msg(data).channels()),
+            _ => self.phase
+        }
+    }
+
+    fn finish(&mut self) {
+        for i in 0..MAX_CHANNELS {
+            let learned = ChannelCalibration {
+                min_us: self.min[i],
+                center_us: ((self.sum[i] + CENTER_FRAMES as u32 / 2) / CENTER_FRAMES as u32) as u16,
+                max_us: self.max[i],
+            };
+            let moved = self.max[i] >= self.min[i] && self.max[i] - self.min[i] >= MIN_TRAVEL_US;
+            self.calibration.channels[i] = if moved && learned.is_valid() { learned }
+                                           else { ChannelCalibration::DEFAULT };
+        }
+        self.phase = Phase::Done;
+    }
+
+    /// The result, once done.
+    pub fn calibration(&self) -> Option<Calibration> {
+        match self.phase {
+            Phase::Done => Some(self.calibration),
+            _ => None
+        }
+    }
+}
+
+impl Default for Calibrator {
+    fn default() -> Self { Self::new() }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(values: &[u16]) -> IBusMsg {
+        let mut data = [1500u16; 14];
+        data[..values.len()].copy_from_slice(values);
+        IBusMsg::SetMsg(data)
+    }
+
+    #[test]
+    fn test_calibrator() {
+        let mut calibrator = Calibrator::new();
+        // Sticks around the box; channel 3 is not touched
+        for values in [[1520, 1500, 1510], [1020, 1980, 1510], [1995, 1050, 1510], [1500, 1500, 1510]] {
+            assert_eq!(calibrator.feed_msg(&frame(&values)), Phase::Endpoints);
+        }
+        assert_eq!(calibrator.calibration(), None);
+        calibrator.capture_centers();
+
+        // A stick coming back to the center restarts the averaging
+        for _ in 0..10 {
+            calibrator.feed_msg(&frame(&[1480, 1500, 1510]));
+        }
+        for i in 0..CENTER_FRAMES - 1 {
+            assert_eq!(calibrator.feed_msg(&frame(&[1510 + i % 2, 1495, 1510])), Phase::Centers);
+        }
+        assert_eq!(calibrator.feed_msg(&frame(&[1511, 1495, 1510])), Phase::Done);
+
+        let calibration = calibrator.calibration().unwrap();
+        assert_eq!(calibration.channels[0], ChannelCalibration { min_us: 1020, center_us: 1511, max_us: 1995 });
+        assert_eq!(calibration.channels[1], ChannelCalibration { min_us: 1050, center_us: 1495, max_us: 1980 });
+        assert_eq!(calibration.channels[2], ChannelCalibration::DEFAULT);
+        assert_eq!(calibration.channels[17], ChannelCalibration::DEFAULT);
+
+        let mut processor = ChannelProcessor::<4>::new();
+        calibration.apply(&mut processor);
+        assert_eq!(processor.process(0, 1995), 1.0);
+        assert_eq!(processor.process(1, 1495), 0.0);
+    }
+
+    #[test]
+    fn test_18channels() {
+        let frame = |first: u16, last: u16| {
+            let mut values = [1500u16; MAX_CHANNELS];
+            values[0] = first;
+            values[17] = last;
+            IBusMsg::SetMsg(crate::RustIBus::packChannels(&values))
+        };
+        let mut calibrator = Calibrator::new();
+        for (first, last) in [(1000, 1100), (2000, 1900), (1500, 1500)] {
+            calibrator.feed_msg(&frame(first, last));
+        }
+        calibrator.capture_centers();
+        for _ in 0..CENTER_FRAMES {
+            calibrator.feed_msg(&frame(1500, 1500));
+        }
+        let calibration = calibrator.calibration().unwrap();
+        assert_eq!(calibration.channels[0], ChannelCalibration { min_us: 1000, center_us: 1500, max_us: 2000 });
+        // Channels whose packed words carry bits of channels 15 - 18 stayed in range
+        assert_eq!(calibration.channels[1], ChannelCalibration::DEFAULT);
+        assert_eq!(calibration.channels[17], ChannelCalibration { min_us: 1100, center_us: 1500, max_us: 1900 });
+    }
+
+    #[test]
+    fn test_blob() {
+        let mut calibration = Calibration::new();
+        calibration.channels[0] = ChannelCalibration { min_us: 1020, center_us: 1511, max_us: 1995 };
+        calibration.channels[17] = ChannelCalibration { min_us: 988, center_us: 1500, max_us: 2012 };
+        let blob = calibration.to_bytes();
+        assert_eq!(blob.len(), 112);
+        assert_eq!(blob[..8], [BLOB_VERSION, 18, 0xfc, 0x03, 0xe7, 0x05, 0xcb, 0x07]);
+        assert_eq!(Calibration::from_bytes(&blob), Ok(calibration));
+
+        let mut bad = blob;
+        bad[40] ^= 0x01;
+        assert_eq!(Calibration::from_bytes(&bad), Err(BlobError::Checksum));
+        assert_eq!(Calibration::from_bytes(&[0xff; BLOB_LENGTH]), Err(BlobError::Version));
+        assert_eq!(Calibration::from_bytes(&blob[..100]), Err(BlobError::Length));
+    }
+
+    #[test]
+    fn test_crc16() {
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+    }
+}
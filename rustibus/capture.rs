@@ -0,0 +1,398 @@
+
+use crate::serial::Direction;
+
+
+/// The first bytes of every capture.
+pub const MAGIC: [u8; 4] = *b"IBCP";
+pub const VERSION: u8 = 1;
+/// Magic, version and flags.
+pub const HEADER_LENGTH: usize = 6;
+/// The most bytes a single record holds; longer writes are split over several records.
+pub const MAX_RECORD_BYTES: usize = 0x7f;
+
+const FLAG_DIRECTION: u8 = 0x01;
+const TRANSMIT: u8 = 0x80;
+
+
+// The format is a header followed by records. A record is the time since the previous record
+// in microseconds (LEB128), a tag byte with the number of bytes in the low 7 bits and, if the
+// capture has directions, the direction in the high bit (set for transmit), then the bytes.
+
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum CaptureError {
+    /// The buffer can not hold the record.
+    Full,
+    /// The data does not start with a capture header of a known version.
+    BadHeader,
+    /// The data ends halfway a record.
+    Truncated,
+}
+
+
+/// Bytes seen on the bus at a given time, counted in microseconds from the start of the capture.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Record<'a> {
+    pub time_us: u64,
+    /// `None` if the capture was made without directions.
+    pub direction: Option<Direction>,
+    pub bytes: &'a [u8],
+}
+
+
+/// Writes a capture into a buffer, e.g. in RAM or a flash page, without allocating.
+pub struct CaptureWriter<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+    directions: bool,
+    last_time: u64,
+}
+
+impl<'a> CaptureWriter<'a> {
+    /// Start a capture in `buffer`. With `directions`, every record stores the bus direction.
+    pub fn new(buffer: &'a mut [u8], directions: bool) -> Result<Self, CaptureError> {
+        if buffer.len() < HEADER_LENGTH {
+            return Err(CaptureError::Full);
+        }
+        buffer[..4].copy_from_slice(&MAGIC);
+        buffer[4] = VERSION;
+        buffer[5] = if directions { FLAG_DIRECTION } else { 0 };
+        Ok(CaptureWriter { buffer, len: HEADER_LENGTH, directions, last_time: 0 })
+    }
+
+    /// Add the bytes seen at `time_us`. The direction is ignored if the capture has none.
+    /// Times before the previous record are stored as the time of the previous record.
+    /// If the buffer is full, nothing is written; without bytes, there is nothing to write.
+    pub fn record(&mut self, time_us: u64, direction: Direction, bytes: &[u8]) -> Result<(), CaptureError> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        let mut delta = time_us.saturating_sub(self.last_time);
+        // Check that everything fits before writing anything
+        let chunks = bytes.len().div_ceil(MAX_RECORD_BYTES);
+        let overhead = varint_length(delta) + chunks.saturating_sub(1) + chunks;
+        if self.len + overhead + bytes.len() > self.buffer.len() {
+            return Err(CaptureError::Full);
+        }
+        for chunk in bytes.chunks(MAX_RECORD_BYTES) {
+            self.len += write_varint(delta, &mut self.buffer[self.len..]);
+            let mut tag = chunk.len() as u8;
+            if self.directions && direction == Direction::Transmit {
+                tag |= TRANSMIT;
+            }
+            self.buffer[self.len] = tag;
+            self.buffer[self.len + 1..self.len + 1 + chunk.len()].copy_from_slice(chunk);
+            self.len += 1 + chunk.len();
+            delta = 0;
+        }
+        self.last_time = self.last_time.max(time_us);
+        Ok(())
+    }
+
+    /// The capture written so far.
+    pub fn as_bytes(&self) -> &[u8] { &self.buffer[..self.len] }
+
+    pub fn len(&self) -> usize { self.len }
+    pub fn is_empty(&self) -> bool { self.len == HEADER_LENGTH }
+
+    /// Free space, in bytes.
+    pub fn space(&self) -> usize { self.buffer.len() - self.len }
+}
+
+
+fn varint_length(mut value: u64) -> usize {
+    let mut length = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        length += 1;
+    }
+    length
+}
+
+fn write_varint(mut value: u64, buffer: &mut [u8]) -> usize {
+    let mut i = 0;
+    while value >= 0x80 {
+        buffer[i] = (value & 0x7f) as u8 | 0x80;
+        value >>= 7;
+        i += 1;
+    }
+    buffer[i] = value as u8;
+    i + 1
+}
+
+fn read_varint(data: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for (i, b) in data.iter().enumerate().take(10) {
+        value |= ((b & 0x7f) as u64) << (7 * i);
+        if b & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+
+/// Reads the records of a capture.
+pub struct CaptureReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    directions: bool,
+    time_us: u64,
+}
+
+impl<'a> CaptureReader<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, CaptureError> {
+        if data.len() < HEADER_LENGTH || data[..4] != MAGIC || data[4] != VERSION {
+            return Err(CaptureError::BadHeader);
+        }
+        Ok(CaptureReader { data, pos: HEADER_LENGTH, directions: data[5] & FLAG_DIRECTION != 0, time_us: 0 })
+    }
+
+    /// Tells whether `data` starts like a capture, as opposed to raw bus bytes.
+    pub fn is_capture(data: &[u8]) -> bool {
+        data.len() >= 4 && data[..4] == MAGIC
+    }
+
+    pub fn has_directions(&self) -> bool { self.directions }
+}
+
+impl<'a> Iterator for CaptureReader<'a> {
+    type Item = Result<Record<'a>, CaptureError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.data.len() {
+            return None;
+        }
+        let rest = &self.data[self.pos..];
+        let record = read_varint(rest).and_then(|(delta, n)| {
+            let tag = *rest.get(n)?;
+            let length = (tag & 0x7f) as usize;
+            let bytes = rest.get(n + 1..n + 1 + length)?;
+            Some((delta, tag, bytes, n + 1 + length))
+        });
+        match record {
+            Some((delta, tag, bytes, length)) => {
+                self.pos += length;
+                self.time_us = self.time_us.saturating_add(delta);
+                let direction = match (self.directions, tag & TRANSMIT != 0) {
+                    (false, _) => None,
+                    (true, false) => Some(Direction::Receive),
+                    (true, true) => Some(Direction::Transmit),
+                };
+                Some(Ok(Record { time_us: self.time_us, direction, bytes }))
+            },
+            None => {
+                // Stop after reporting the damage once
+                self.pos = self.data.len();
+                Some(Err(CaptureError::Truncated))
+            }
+        }
+    }
+}
+
+
+/// Plays a capture back against a clock, at the recorded pace multiplied by `speed`.
+///
+/// Call `poll` with the current time; it returns the records that are due, one at a time.
+/// The clock is counted from the first poll, so replay can run on a simulated clock in
+/// tests as well as on a hardware timer.
+pub struct Replay<'a> {
+    reader: CaptureReader<'a>,
+    speed: f32,
+    start_us: Option<u64>,
+    pending: Option<Result<Record<'a>, CaptureError>>,
+}
+
+impl<'a> Replay<'a> {
+    /// A speed of 1.0 is real time, 2.0 twice as fast. A speed of 0 or less plays everything at once.
+    pub fn new(reader: CaptureReader<'a>, speed: f32) -> Self {
+        Replay { reader, speed, start_us: None, pending: None }
+    }
+
+    /// The time relative to the first poll at which the next record is due, if any is left.
+    pub fn next_due(&mut self) -> Option<u64> {
+        if self.pending.is_none() {
+            self.pending = self.reader.next();
+        }
+        match self.pending {
+            Some(Ok(record)) => Some(self.scaled(record.time_us)),
+            Some(Err(_)) => Some(0),
+            None => None
+        }
+    }
+
+    fn scaled(&self, time_us: u64) -> u64 {
+        // In f64, as an f32 loses microseconds after some 16 seconds
+        if self.speed > 0.0 { (time_us as f64 / self.speed as f64) as u64 } else { 0 }
+    }
+
+    /// The next record if it is due at `now_us`.
+    pub fn poll(&mut self, now_us: u64) -> Option<Result<Record<'a>, CaptureError>> {
+        let start = *self.start_us.get_or_insert(now_us);
+        let due = self.next_due()?;
+        if now_us.saturating_sub(start) >= due {
+            self.pending.take()
+        } else {
+            None
+        }
+    }
+
+    pub fn is_done(&mut self) -> bool { self.next_due().is_none() }
+}
+
+
+/// Replays a capture at `speed` times real time (see `Replay::new`), handing every record
+/// to `sink`, e.g. to feed a decoder. Blocks until the capture has been played.
+#[cfg(feature = "std")]
+pub fn replay<F: FnMut(&Record)>(capture: &[u8], speed: f32, mut sink: F) -> Result<(), CaptureError> {
+    use std::time::{Duration, Instant};
+
+    let mut replay = Replay::new(CaptureReader::new(capture)?, speed);
+    let start = Instant::now();
+    while let Some(due) = replay.next_due() {
+        let now = start.elapsed().as_micros() as u64;
+        if due > now {
+            std::thread::sleep(Duration::from_micros(due - now));
+        }
+        if let Some(record) = replay.poll(start.elapsed().as_micros() as u64) {
+            sink(&record?);
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RustIBus::IBusMsg;
+    use crate::testkit::{decode_msgs, encode};
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut buffer = [0u8; 128];
+        let mut writer = CaptureWriter::new(&mut buffer, true).unwrap();
+        assert!(writer.is_empty());
+        let request = encode(&IBusMsg::ValueRequest(1));
+        let response = encode(&IBusMsg::ValueResponseShort(1, 400));
+        writer.record(1_000, Direction::Receive, &request).unwrap();
+        writer.record(1_300, Direction::Transmit, &response).unwrap();
+        writer.record(20_000_000, Direction::Receive, &[0x55]).unwrap();
+        // 6 header, 2 + 1 + 4, 2 + 1 + 6, 4 + 1 + 1
+        assert_eq!(writer.len(), 6 + 7 + 9 + 6);
+        let capture = writer.as_bytes().to_vec();
+
+        let reader = CaptureReader::new(&capture).unwrap();
+        assert!(reader.has_directions());
+        let records: Vec<Record> = reader.map(|r| r.unwrap()).collect();
+        assert_eq!(records, [
+            Record { time_us: 1_000, direction: Some(Direction::Receive), bytes: &request },
+            Record { time_us: 1_300, direction: Some(Direction::Transmit), bytes: &response },
+            Record { time_us: 20_000_000, direction: Some(Direction::Receive), bytes: &[0x55] },
+        ]);
+    }
+
+    #[test]
+    fn test_empty_record() {
+        let mut buffer = [0u8; 32];
+        let mut writer = CaptureWriter::new(&mut buffer, false).unwrap();
+        writer.record(1_000, Direction::Receive, &[1]).unwrap();
+        // Nothing to record, and the next record keeps its own time
+        writer.record(5_000, Direction::Receive, &[]).unwrap();
+        writer.record(3_000, Direction::Receive, &[2]).unwrap();
+        let capture = writer.as_bytes().to_vec();
+
+        let times: Vec<u64> = CaptureReader::new(&capture).unwrap().map(|r| r.unwrap().time_us).collect();
+        assert_eq!(times, [1_000, 3_000]);
+    }
+
+    #[test]
+    fn test_long_records() {
+        let mut buffer = [0u8; 512];
+        let mut writer = CaptureWriter::new(&mut buffer, false).unwrap();
+        let frames: Vec<u8> = (0..10).flat_map(|i| encode(&IBusMsg::SetMsg([1000 + i; 14]))).collect();
+        writer.record(7_000, Direction::Transmit, &frames).unwrap();
+        let capture = writer.as_bytes().to_vec();
+
+        let reader = CaptureReader::new(&capture).unwrap();
+        assert!(!reader.has_directions());
+        let mut bytes = Vec::new();
+        for record in reader {
+            let record = record.unwrap();
+            assert_eq!(record.time_us, 7_000);
+            assert_eq!(record.direction, None);
+            assert!(record.bytes.len() <= MAX_RECORD_BYTES);
+            bytes.extend_from_slice(record.bytes);
+        }
+        assert_eq!(decode_msgs(&bytes).len(), 10);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut tiny = [0u8; 4];
+        assert_eq!(CaptureWriter::new(&mut tiny, false).err(), Some(CaptureError::Full));
+        let mut buffer = [0u8; 15];
+        let mut writer = CaptureWriter::new(&mut buffer, false).unwrap();
+        writer.record(5, Direction::Receive, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(writer.record(6, Direction::Receive, &[1, 2, 3, 4]), Err(CaptureError::Full));
+        assert_eq!(writer.len(), 12);
+        // Time going backwards is kept at the last time
+        writer.record(1, Direction::Receive, &[9]).unwrap();
+        assert_eq!(writer.space(), 0);
+        let capture = writer.as_bytes().to_vec();
+        let records: Vec<Record> = CaptureReader::new(&capture).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(records[1], Record { time_us: 5, direction: None, bytes: &[9] });
+
+        assert_eq!(CaptureReader::new(b"IBUS\x01\x00").err(), Some(CaptureError::BadHeader));
+        assert!(!CaptureReader::is_capture(&[0x20, 0x40, 0xdc, 0x05]));
+        assert!(CaptureReader::is_capture(&capture));
+        let mut reader = CaptureReader::new(&capture[..capture.len() - 1]).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        assert_eq!(reader.next(), Some(Err(CaptureError::Truncated)));
+        assert_eq!(reader.next(), None);
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut buffer = [0u8; 64];
+        let mut writer = CaptureWriter::new(&mut buffer, false).unwrap();
+        writer.record(0, Direction::Receive, &[1]).unwrap();
+        writer.record(1_000, Direction::Receive, &[2]).unwrap();
+        writer.record(4_000, Direction::Receive, &[3]).unwrap();
+        let capture = writer.as_bytes().to_vec();
+
+        // Twice as fast, on a clock starting at 500
+        let mut replay = Replay::new(CaptureReader::new(&capture).unwrap(), 2.0);
+        assert_eq!(replay.poll(500).unwrap().unwrap().bytes, [1]);
+        assert_eq!(replay.poll(999), None);
+        assert_eq!(replay.next_due(), Some(500));
+        assert_eq!(replay.poll(1_000).unwrap().unwrap().bytes, [2]);
+        assert_eq!(replay.poll(2_499), None);
+        assert_eq!(replay.poll(2_500).unwrap().unwrap().bytes, [3]);
+        assert!(replay.is_done());
+        assert_eq!(replay.poll(1_000_000), None);
+
+        let mut all = Replay::new(CaptureReader::new(&capture).unwrap(), 0.0);
+        assert_eq!(all.poll(0).unwrap().unwrap().bytes, [1]);
+        assert_eq!(all.poll(0).unwrap().unwrap().bytes, [2]);
+        assert_eq!(all.poll(0).unwrap().unwrap().bytes, [3]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_replay_realtime() {
+        let mut buffer = [0u8; 64];
+        let mut writer = CaptureWriter::new(&mut buffer, false).unwrap();
+        writer.record(0, Direction::Receive, &[1]).unwrap();
+        writer.record(50_000, Direction::Receive, &[2]).unwrap();
+        let capture = writer.as_bytes().to_vec();
+
+        let start = std::time::Instant::now();
+        let mut bytes = Vec::new();
+        replay(&capture, 1.0, |r| bytes.extend_from_slice(r.bytes)).unwrap();
+        assert_eq!(bytes, [1, 2]);
+        assert!(start.elapsed() >= std::time::Duration::from_millis(50));
+        assert_eq!(replay(b"nope", 1.0, |_| ()), Err(CaptureError::BadHeader));
+    }
+}
@@ -0,0 +1,440 @@
+//! CRSF, the protocol of TBS Crossfire and ExpressLRS receivers.
+//!
+//! A frame is an address byte, a length byte counting the bytes that follow, a frame type,
+//! the payload and a CRC8 (DVB-S2) over the type and payload. Multi-byte values are big endian,
+//! except for the packed channels, which are 16 channels of 11 bits as in SBUS.
+//! Receivers run at 420000 Baud and send channels and link statistics; the flight controller
+//! answers with telemetry frames on the same connection.
+
+use crate::deque::Deque;
+use crate::rc::{ChannelFrame, RcInput};
+use crate::sbus::{pack_channels, raw_to_us, unpack_channels, CHANNELS};
+
+
+/// The address frames to and from a flight controller start with.
+pub const ADDRESS_FLIGHT_CONTROLLER: u8 = 0xc8;
+pub const ADDRESS_RADIO: u8 = 0xea;
+pub const ADDRESS_RECEIVER: u8 = 0xec;
+pub const ADDRESS_TRANSMITTER: u8 = 0xee;
+
+/// The longest frame, address and length byte included.
+pub const MAX_FRAME_LENGTH: usize = 64;
+const MIN_LENGTH: u8 = 2;
+const MAX_LENGTH: u8 = MAX_FRAME_LENGTH as u8 - 2;
+
+pub const TYPE_GPS: u8 = 0x02;
+pub const TYPE_VARIO: u8 = 0x07;
+pub const TYPE_BATTERY: u8 = 0x08;
+pub const TYPE_BARO_ALTITUDE: u8 = 0x09;
+pub const TYPE_LINK_STATISTICS: u8 = 0x14;
+pub const TYPE_RC_CHANNELS: u8 = 0x16;
+pub const TYPE_ATTITUDE: u8 = 0x1e;
+
+
+/// CRC8 with polynomial 0xd5, as used by DVB-S2 and CRSF.
+pub fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0xd5 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn is_address(byte: u8) -> bool {
+    matches!(byte, ADDRESS_FLIGHT_CONTROLLER | ADDRESS_RADIO | ADDRESS_RECEIVER | ADDRESS_TRANSMITTER)
+}
+
+
+/// The quality of the radio link, as reported by the receiver about once per second.
+#[derive(PartialEq, Debug, Default, Clone, Copy)]
+pub struct LinkStatistics {
+    /// Signal strength at the receiver antennas, in -dBm.
+    pub uplink_rssi_1: u8,
+    pub uplink_rssi_2: u8,
+    /// Percentage of packets received.
+    pub uplink_link_quality: u8,
+    /// In dB.
+    pub uplink_snr: i8,
+    pub active_antenna: u8,
+    pub rf_mode: u8,
+    /// Transmitter power as an index: 0 mW, 10 mW, 25 mW, 100 mW, 500 mW, 1 W, 2 W, 250 mW, 50 mW.
+    pub uplink_tx_power: u8,
+    /// Signal strength at the transmitter, in -dBm.
+    pub downlink_rssi: u8,
+    pub downlink_link_quality: u8,
+    pub downlink_snr: i8,
+}
+
+#[derive(PartialEq, Debug, Default, Clone, Copy)]
+pub struct Battery {
+    /// In 0.1 V.
+    pub voltage: u16,
+    /// In 0.1 A.
+    pub current: u16,
+    /// Used capacity in mAh, 24 bits.
+    pub capacity: u32,
+    /// Remaining charge in percent.
+    pub remaining: u8,
+}
+
+#[derive(PartialEq, Debug, Default, Clone, Copy)]
+pub struct Gps {
+    /// In degrees * 10^7.
+    pub latitude: i32,
+    pub longitude: i32,
+    /// In 0.1 km/h.
+    pub groundspeed: u16,
+    /// In 0.01 degrees.
+    pub heading: u16,
+    /// In meters, plus 1000.
+    pub altitude: u16,
+    pub satellites: u8,
+}
+
+#[derive(PartialEq, Debug, Default, Clone, Copy)]
+pub struct Attitude {
+    /// In 0.0001 radians.
+    pub pitch: i16,
+    pub roll: i16,
+    pub yaw: i16,
+}
+
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum CrsfMsg {
+    /// Raw 11 bit channel values, see `sbus::raw_to_us`.
+    RcChannels([u16; CHANNELS]),
+    LinkStatistics(LinkStatistics),
+    Battery(Battery),
+    Gps(Gps),
+    /// Vertical speed in cm/s.
+    Vario(i16),
+    /// Barometric altitude in decimeters, plus 10000.
+    BaroAltitude(u16),
+    Attitude(Attitude),
+    /// A frame with a valid CRC of a type that is not decoded, or too short for its type.
+    Other(u8),
+}
+
+impl CrsfMsg {
+    /// Decodes the type and payload of a frame whose CRC has been checked.
+    fn decode(frame_type: u8, payload: &[u8]) -> CrsfMsg {
+        let p = payload;
+        let u16_at = |i: usize| u16::from_be_bytes([p[i], p[i + 1]]);
+        let i32_at = |i: usize| i32::from_be_bytes([p[i], p[i + 1], p[i + 2], p[i + 3]]);
+        match (frame_type, p.len()) {
+            (TYPE_RC_CHANNELS, 22..) => CrsfMsg::RcChannels(unpack_channels(p[..22].try_into().unwrap())),
+            (TYPE_LINK_STATISTICS, 10..) => CrsfMsg::LinkStatistics(LinkStatistics {
+                uplink_rssi_1: p[0],
+                uplink_rssi_2: p[1],
+                uplink_link_quality: p[2],
+                uplink_snr: p[3] as i8,
+                active_antenna: p[4],
+                rf_mode: p[5],
+                uplink_tx_power: p[6],
+                downlink_rssi: p[7],
+                downlink_link_quality: p[8],
+                downlink_snr: p[9] as i8,
+            }),
+            (TYPE_BATTERY, 8..) => CrsfMsg::Battery(Battery {
+                voltage: u16_at(0),
+                current: u16_at(2),
+                capacity: u32::from_be_bytes([0, p[4], p[5], p[6]]),
+                remaining: p[7],
+            }),
+            (TYPE_GPS, 15..) => CrsfMsg::Gps(Gps {
+                latitude: i32_at(0),
+                longitude: i32_at(4),
+                groundspeed: u16_at(8),
+                heading: u16_at(10),
+                altitude: u16_at(12),
+                satellites: p[14],
+            }),
+            (TYPE_VARIO, 2..) => CrsfMsg::Vario(u16_at(0) as i16),
+            (TYPE_BARO_ALTITUDE, 2..) => CrsfMsg::BaroAltitude(u16_at(0)),
+            (TYPE_ATTITUDE, 6..) => CrsfMsg::Attitude(Attitude {
+                pitch: u16_at(0) as i16,
+                roll: u16_at(2) as i16,
+                yaw: u16_at(4) as i16,
+            }),
+            _ => CrsfMsg::Other(frame_type),
+        }
+    }
+
+    /// Writes the type and payload to `out`, returning their length.
+    fn encode_payload(&self, out: &mut [u8; MAX_FRAME_LENGTH]) -> usize {
+        let mut n = 1;
+        let mut put = |bytes: &[u8]| {
+            out[n..n + bytes.len()].copy_from_slice(bytes);
+            n += bytes.len();
+        };
+        let frame_type = match self {
+            CrsfMsg::RcChannels(channels) => {
+                put(&pack_channels(channels));
+                TYPE_RC_CHANNELS
+            }
+            CrsfMsg::LinkStatistics(s) => {
+                put(&[s.uplink_rssi_1, s.uplink_rssi_2, s.uplink_link_quality, s.uplink_snr as u8,
+                      s.active_antenna, s.rf_mode, s.uplink_tx_power, s.downlink_rssi,
+                      s.downlink_link_quality, s.downlink_snr as u8]);
+                TYPE_LINK_STATISTICS
+            }
+            CrsfMsg::Battery(b) => {
+                put(&b.voltage.to_be_bytes());
+                put(&b.current.to_be_bytes());
+                put(&b.capacity.min(0xff_ffff).to_be_bytes()[1..]);
+                put(&[b.remaining]);
+                TYPE_BATTERY
+            }
+            CrsfMsg::Gps(g) => {
+                put(&g.latitude.to_be_bytes());
+                put(&g.longitude.to_be_bytes());
+                put(&g.groundspeed.to_be_bytes());
+                put(&g.heading.to_be_bytes());
+                put(&g.altitude.to_be_bytes());
+                put(&[g.satellites]);
+                TYPE_GPS
+            }
+            CrsfMsg::Vario(speed) => {
+                put(&speed.to_be_bytes());
+                TYPE_VARIO
+            }
+            CrsfMsg::BaroAltitude(altitude) => {
+                put(&altitude.to_be_bytes());
+                TYPE_BARO_ALTITUDE
+            }
+            CrsfMsg::Attitude(a) => {
+                put(&a.pitch.to_be_bytes());
+                put(&a.roll.to_be_bytes());
+                put(&a.yaw.to_be_bytes());
+                TYPE_ATTITUDE
+            }
+            CrsfMsg::Other(frame_type) => *frame_type,
+        };
+        out[0] = frame_type;
+        n
+    }
+
+    /// Writes the message as a complete frame to `buffer`, starting with `address`.
+    /// Returns the length of the frame, or 0 if the buffer is too small.
+    /// `Other` is written without a payload.
+    pub fn encode(&self, address: u8, buffer: &mut [u8]) -> usize {
+        let mut body = [0u8; MAX_FRAME_LENGTH];
+        let n = self.encode_payload(&mut body);
+        if buffer.len() < n + 3 {
+            return 0;
+        }
+        buffer[0] = address;
+        buffer[1] = n as u8 + 1;
+        buffer[2..2 + n].copy_from_slice(&body[..n]);
+        buffer[2 + n] = crc8(&body[..n]);
+        n + 3
+    }
+
+    /// Writes a telemetry frame for the flight controller to send to the receiver.
+    pub fn encode_telemetry(&self, buffer: &mut [u8]) -> usize {
+        self.encode(ADDRESS_FLIGHT_CONTROLLER, buffer)
+    }
+}
+
+
+#[derive(PartialEq, Debug, Default, Clone, Copy)]
+pub struct CrsfStats {
+    /// Frames with a valid CRC, of any type.
+    pub frames: u32,
+    /// Of which channel frames.
+    pub rc_frames: u32,
+    /// Complete frames whose CRC did not match.
+    pub crc_errors: u32,
+    /// Bytes skipped while looking for the start of a frame.
+    pub resync_bytes: u32,
+}
+
+
+/// Decodes a CRSF byte stream, one byte at a time.
+///
+/// `SIZE` must hold at least one complete frame, so at least 65 bytes.
+pub struct CrsfDecoder<const SIZE: usize = 128> {
+    buffer: Deque<SIZE>,
+    link: Option<LinkStatistics>,
+    stats: CrsfStats,
+}
+
+impl<const SIZE: usize> CrsfDecoder<SIZE> {
+    pub const fn new() -> Self {
+        // The buffer holds SIZE - 1 bytes
+        const { assert!(SIZE > MAX_FRAME_LENGTH, "SIZE must hold a complete frame") };
+        CrsfDecoder { buffer: Deque::new(), link: None, stats: CrsfStats {
+            frames: 0, rc_frames: 0, crc_errors: 0, resync_bytes: 0 } }
+    }
+
+    pub fn feed(&mut self, byte: u8) {
+        self.buffer.push(byte);
+    }
+
+    /// The next message in the bytes fed so far, if any.
+    pub fn pop(&mut self) -> Option<CrsfMsg> {
+        while !Deque::is_empty(&self.buffer) {
+            if !is_address(self.buffer[0]) {
+                self.skip();
+                continue;
+            }
+            if self.buffer.len() < 2 {
+                return None;
+            }
+            let length = self.buffer[1];
+            if !(MIN_LENGTH..=MAX_LENGTH).contains(&length) {
+                self.skip();
+                continue;
+            }
+            let total = length as usize + 2;
+            if self.buffer.len() < total {
+                return None;
+            }
+            let mut frame = [0u8; MAX_FRAME_LENGTH];
+            for (i, byte) in frame[..total].iter_mut().enumerate() {
+                *byte = self.buffer[i];
+            }
+            if crc8(&frame[2..total - 1]) != frame[total - 1] {
+                self.stats.crc_errors = self.stats.crc_errors.wrapping_add(1);
+                self.skip();
+                continue;
+            }
+            for _ in 0..total {
+                self.buffer.pop();
+            }
+            let msg = CrsfMsg::decode(frame[2], &frame[3..total - 1]);
+            self.stats.frames = self.stats.frames.wrapping_add(1);
+            match msg {
+                CrsfMsg::RcChannels(_) => self.stats.rc_frames = self.stats.rc_frames.wrapping_add(1),
+                CrsfMsg::LinkStatistics(link) => self.link = Some(link),
+                _ => ()
+            }
+            return Some(msg);
+        }
+        None
+    }
+
+    fn skip(&mut self) {
+        self.buffer.pop();
+        self.stats.resync_bytes = self.stats.resync_bytes.wrapping_add(1);
+    }
+
+    /// The link statistics last received, if any.
+    pub fn link_statistics(&self) -> Option<LinkStatistics> { self.link }
+
+    pub fn stats(&self) -> CrsfStats { self.stats }
+
+    pub fn reset_stats(&mut self) { self.stats = CrsfStats::default(); }
+}
+
+impl<const SIZE: usize> Default for CrsfDecoder<SIZE> {
+    fn default() -> Self { Self::new() }
+}
+
+impl<const SIZE: usize> RcInput for CrsfDecoder<SIZE> {
+    fn feed(&mut self, byte: u8) { CrsfDecoder::feed(self, byte) }
+
+    /// CRSF has no failsafe flag: receivers stop sending channels when the link is lost.
+    fn pop_frame(&mut self) -> Option<ChannelFrame> {
+        while let Some(msg) = self.pop() {
+            if let CrsfMsg::RcChannels(raw) = msg {
+                let mut values = [0u16; CHANNELS];
+                for (value, raw) in values.iter_mut().zip(raw.iter()) {
+                    *value = raw_to_us(*raw);
+                }
+                return Some(ChannelFrame::new(&values));
+            }
+        }
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn encoded(msgs: &[CrsfMsg]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for msg in msgs {
+            let mut buffer = [0u8; MAX_FRAME_LENGTH];
+            let n = msg.encode(ADDRESS_FLIGHT_CONTROLLER, &mut buffer);
+            bytes.extend_from_slice(&buffer[..n]);
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_crc8() {
+        assert_eq!(crc8(b"123456789"), 0xbc);
+        assert_eq!(crc8(&[]), 0);
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut buffer = [0u8; MAX_FRAME_LENGTH];
+        let battery = Battery { voltage: 168, current: 123, capacity: 0x012345, remaining: 80 };
+        let n = CrsfMsg::Battery(battery).encode_telemetry(&mut buffer);
+        assert_eq!(buffer[..n - 1], [0xc8, 10, TYPE_BATTERY, 0, 168, 0, 123, 0x01, 0x23, 0x45, 80]);
+        assert_eq!(buffer[n - 1], crc8(&buffer[2..n - 1]));
+
+        let n = CrsfMsg::RcChannels([992; CHANNELS]).encode(ADDRESS_FLIGHT_CONTROLLER, &mut buffer);
+        assert_eq!(n, 26);
+        assert_eq!(buffer[1], 24);
+        assert_eq!(CrsfMsg::Vario(-5).encode_telemetry(&mut buffer[..5]), 0);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut channels = [0u16; CHANNELS];
+        for (i, channel) in channels.iter_mut().enumerate() {
+            *channel = 172 + i as u16 * 100;
+        }
+        let msgs = [
+            CrsfMsg::RcChannels(channels),
+            CrsfMsg::LinkStatistics(LinkStatistics { uplink_rssi_1: 60, uplink_link_quality: 100,
+                                                     uplink_snr: -3, downlink_snr: 9, ..LinkStatistics::default() }),
+            CrsfMsg::Battery(Battery { voltage: 252, current: 15, capacity: 1500, remaining: 42 }),
+            CrsfMsg::Gps(Gps { latitude: 521_234_567, longitude: -41_234_567, groundspeed: 250,
+                               heading: 27_000, altitude: 1_100, satellites: 9 }),
+            CrsfMsg::Vario(-120),
+            CrsfMsg::BaroAltitude(10_250),
+            CrsfMsg::Attitude(Attitude { pitch: -1000, roll: 2000, yaw: 31_415 }),
+            CrsfMsg::Other(0x7f),
+        ];
+        let mut decoder = CrsfDecoder::<128>::new();
+        let mut decoded = Vec::new();
+        for b in encoded(&msgs) {
+            decoder.feed(b);
+            decoded.extend(core::iter::from_fn(|| decoder.pop()));
+        }
+        assert_eq!(decoded, msgs);
+        assert_eq!(decoder.link_statistics().map(|l| l.uplink_snr), Some(-3));
+    }
+
+    #[test]
+    fn test_resync() {
+        let mut bytes = alloc::vec![0x00, 0xc8, 0xff];
+        let mut bad = encoded(&[CrsfMsg::Vario(10)]);
+        bad[4] ^= 0x01;
+        bytes.extend(bad);
+        bytes.extend(encoded(&[CrsfMsg::RcChannels([992; CHANNELS])]));
+        let mut decoder = CrsfDecoder::<128>::new();
+        let mut frames = Vec::new();
+        for b in bytes {
+            RcInput::feed(&mut decoder, b);
+            frames.extend(core::iter::from_fn(|| decoder.pop_frame()));
+        }
+        assert_eq!(frames, [ChannelFrame::new(&[1500; CHANNELS])]);
+        let stats = decoder.stats();
+        assert_eq!(stats.frames, 1);
+        assert_eq!(stats.rc_frames, 1);
+        assert_eq!(stats.crc_errors, 1);
+        assert_eq!(stats.resync_bytes, 3 + 6);
+    }
+}
@@ -66,7 +66,7 @@ impl<const SIZE: usize> Index<usize> for Deque<SIZE> {
         }
         // The index is counted from the tail, so [0] returns the oldest value.
         let mut offset = self.tail.load(Relaxed) + i;
-        if offset > SIZE {
+        if offset >= SIZE {
             offset -= SIZE;
         }
         unsafe { &self.data[offset] }
@@ -229,6 +229,20 @@ mod tests {
         assert_eq!(d[9], 11u8);
     }
 
+    #[test]
+    fn test_index_wrap() {
+        // An index landing exactly on the end of the storage must wrap to 0 rather than
+        // read past it
+        let mut d = Deque::<11>::new();
+        d.load(&[1u8, 2u8, 3u8, 4u8, 5u8, 6u8, 7u8, 8u8, 9u8, 10u8]);
+        for i in 11u8..30u8 {
+            d.pop();
+            d.push(i);
+            assert_eq!(d[9], i);
+            assert_eq!(d[0], i - 9);
+        }
+    }
+
     #[test]
     fn test_iterator() {
         let mut d = Deque::<11>::new();
@@ -0,0 +1,223 @@
+//! Finds out which protocol an unknown receiver speaks.
+//!
+//! The protocols run at different UART settings, so the port must be set up for the protocol
+//! being tried (see `Protocol::baud_rate`); bytes read at the wrong settings simply do not
+//! score. The detector runs the decoders of all protocols on the same bytes and counts the
+//! frames each of them finds.
+
+use crate::RustIBus::IBusMsg;
+use crate::crsf::{CrsfDecoder, CrsfMsg};
+use crate::sbus::SbusDecoder;
+use crate::stream::StreamDecoder;
+
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Protocol {
+    IBus,
+    Sbus,
+    Crsf,
+}
+
+impl Protocol {
+    pub const fn baud_rate(&self) -> u32 {
+        match self {
+            Protocol::IBus => 115_200,
+            Protocol::Sbus => 100_000,
+            Protocol::Crsf => 420_000,
+        }
+    }
+
+    /// SBUS is sent inverted, with even parity and two stop bits.
+    pub const fn is_inverted(&self) -> bool { matches!(self, Protocol::Sbus) }
+}
+
+
+/// The number of frames found for each protocol.
+#[derive(PartialEq, Debug, Default, Clone, Copy)]
+pub struct Scores {
+    /// Servo messages: length 0x20, command 0x40 and a valid checksum.
+    pub ibus: u16,
+    /// Frames with a 0x0f header and a valid footer, directly following the previous frame.
+    pub sbus: u16,
+    /// Frames with a valid CRC.
+    pub crsf: u16,
+}
+
+
+/// Scores a byte stream for each protocol and reports the first protocol that reaches
+/// the threshold, as long as no other protocol scores as well.
+pub struct ProtocolDetector {
+    ibus: StreamDecoder<64>,
+    sbus: SbusDecoder<64>,
+    crsf: CrsfDecoder<128>,
+    sbus_skipped: Option<u32>,
+    scores: Scores,
+    threshold: u16,
+    detected: Option<Protocol>,
+}
+
+impl ProtocolDetector {
+    /// The number of frames needed by default: about 50 ms of traffic for any of the protocols.
+    pub const DEFAULT_THRESHOLD: u16 = 5;
+
+    pub const fn new() -> Self {
+        ProtocolDetector {
+            ibus: StreamDecoder::new(),
+            sbus: SbusDecoder::new(),
+            crsf: CrsfDecoder::new(),
+            sbus_skipped: None,
+            scores: Scores { ibus: 0, sbus: 0, crsf: 0 },
+            threshold: Self::DEFAULT_THRESHOLD,
+            detected: None,
+        }
+    }
+
+    /// Require `frames` frames before reporting a protocol; at least 1.
+    pub const fn with_threshold(mut self, frames: u16) -> Self {
+        self.threshold = if frames == 0 { 1 } else { frames };
+        self
+    }
+
+    /// Handle one received byte, and return the protocol if it has been detected.
+    /// Once detected, the protocol is reported until `reset`.
+    pub fn feed(&mut self, byte: u8) -> Option<Protocol> {
+        if self.detected.is_some() {
+            return self.detected;
+        }
+        self.ibus.feed(byte);
+        self.sbus.feed(byte);
+        self.crsf.feed(byte);
+
+        // The IBus checks are those of `popIBusMsg`: length, command code and checksum
+        while let Some(msg) = self.ibus.pop() {
+            if let IBusMsg::SetMsg(_) = msg {
+                self.scores.ibus = self.scores.ibus.saturating_add(1);
+            }
+        }
+        // Without a checksum, a single SBUS frame proves little; frames following each
+        // other every 25 bytes do.
+        while self.sbus.pop().is_some() {
+            let skipped = self.sbus.stats().resync_bytes;
+            if self.sbus_skipped == Some(skipped) {
+                self.scores.sbus = self.scores.sbus.saturating_add(1);
+            }
+            self.sbus_skipped = Some(skipped);
+        }
+        while let Some(msg) = self.crsf.pop() {
+            if !matches!(msg, CrsfMsg::Other(_)) {
+                self.scores.crsf = self.scores.crsf.saturating_add(1);
+            }
+        }
+
+        let s = self.scores;
+        let candidates = [(Protocol::IBus, s.ibus), (Protocol::Sbus, s.sbus), (Protocol::Crsf, s.crsf)];
+        for (protocol, score) in candidates {
+            if score >= self.threshold && candidates.iter().all(|&(p, other)| p == protocol || other < score) {
+                self.detected = Some(protocol);
+            }
+        }
+        self.detected
+    }
+
+    pub fn detected(&self) -> Option<Protocol> { self.detected }
+
+    pub fn scores(&self) -> Scores { self.scores }
+
+    /// Start over, e.g. after changing the port settings to try another protocol.
+    pub fn reset(&mut self) { *self = Self::new().with_threshold(self.threshold); }
+}
+
+impl Default for ProtocolDetector {
+    fn default() -> Self { Self::new() }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use crate::crsf::{ADDRESS_FLIGHT_CONTROLLER, LinkStatistics};
+    use crate::generator::XorShift32;
+    use crate::sbus::SbusFrame;
+    use crate::transmitter::{ChannelCount, IBusTransmitter};
+
+    fn noise(seed: u32, length: usize) -> Vec<u8> {
+        let mut rng = XorShift32::new(seed);
+        (0..length).map(|_| rng.next_u32() as u8).collect()
+    }
+
+    fn detect(bytes: &[u8]) -> Option<Protocol> {
+        let mut detector = ProtocolDetector::new();
+        bytes.iter().map(|&b| detector.feed(b)).last().flatten()
+    }
+
+    fn ibus(frames: usize) -> Vec<u8> {
+        let mut tx = IBusTransmitter::new(ChannelCount::Fourteen);
+        let mut bytes = Vec::new();
+        for i in 0..frames {
+            tx.set_channel(0, 1000 + i as u16 * 10);
+            let mut frame = [0u8; 32];
+            let n = tx.tick(&mut frame);
+            bytes.extend_from_slice(&frame[..n]);
+        }
+        bytes
+    }
+
+    fn sbus(frames: usize) -> Vec<u8> {
+        let mut frame = SbusFrame::default();
+        (0..frames).flat_map(|i| {
+            frame.channels[0] = 172 + i as u16 * 15;
+            frame.encode()
+        }).collect()
+    }
+
+    fn crsf(frames: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for i in 0..frames {
+            let msg = if i % 4 == 3 { CrsfMsg::LinkStatistics(LinkStatistics::default()) }
+                      else { CrsfMsg::RcChannels([992 + i as u16; 16]) };
+            let mut frame = [0u8; 64];
+            let n = msg.encode(ADDRESS_FLIGHT_CONTROLLER, &mut frame);
+            bytes.extend_from_slice(&frame[..n]);
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_detect() {
+        for (bytes, protocol) in [(ibus(6), Protocol::IBus), (sbus(7), Protocol::Sbus), (crsf(6), Protocol::Crsf)] {
+            // Starting halfway a frame, after some line noise
+            let mut stream = noise(7, 40);
+            stream.extend_from_slice(&bytes[11..]);
+            assert_eq!(detect(&stream), Some(protocol));
+        }
+        assert_eq!(detect(&ibus(4)), None);
+        assert_eq!(detect(&sbus(5)), None);
+    }
+
+    #[test]
+    fn test_noise() {
+        let mut detector = ProtocolDetector::new();
+        for b in noise(0x5eed, 20_000) {
+            assert_eq!(detector.feed(b), None);
+        }
+        let scores = detector.scores();
+        assert!(scores.ibus < 2 && scores.sbus < 2 && scores.crsf < 2, "{:?}", scores);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut detector = ProtocolDetector::new().with_threshold(2);
+        for b in crsf(2) {
+            detector.feed(b);
+        }
+        assert_eq!(detector.detected(), Some(Protocol::Crsf));
+        assert_eq!(detector.feed(0x0f), Some(Protocol::Crsf));
+        detector.reset();
+        assert_eq!(detector.scores(), Scores::default());
+        for b in sbus(3) {
+            detector.feed(b);
+        }
+        assert_eq!(detector.detected(), Some(Protocol::Sbus));
+    }
+}
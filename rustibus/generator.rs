@@ -0,0 +1,250 @@
+
+use crate::RustIBus::{IBusMsg, pushIBusMsg, MAX_LENGTH};
+
+
+/// The value of a servo channel over time.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Waveform {
+    Constant(u16),
+    /// From `min` up to `max` and back down again, in `period_ms`.
+    Sweep { min: u16, max: u16, period_ms: u32 },
+    Sine { center: u16, amplitude: u16, period_ms: u32 },
+    /// `low` for the first half of each period, `high` for the second half.
+    Switch { low: u16, high: u16, period_ms: u32 },
+}
+
+impl Waveform {
+    pub fn value_at(&self, time_us: u64) -> u16 {
+        match *self {
+            Waveform::Constant(value) => value,
+            Waveform::Sweep { min, max, period_ms } => {
+                let period = period_ms.max(1) as u64 * 1000;
+                let half = period / 2;
+                let phase = time_us % period;
+                let ramp = if phase < half { phase } else { period - phase };
+                let span = max.saturating_sub(min) as u64;
+                min + (span * ramp / half.max(1)) as u16
+            },
+            Waveform::Sine { center, amplitude, period_ms } => {
+                let period = period_ms.max(1) as u64 * 1000;
+                let phase = (time_us % period) as f32 / period as f32;
+                let value = center as f32 + amplitude as f32 * sine(phase);
+                (value + 0.5) as u16
+            },
+            Waveform::Switch { low, high, period_ms } => {
+                let period = period_ms.max(1) as u64 * 1000;
+                if time_us % period < period / 2 { low } else { high }
+            }
+        }
+    }
+}
+
+
+/// sin(2 pi phase) for a phase in [0, 1), using Bhaskara's approximation (error below 0.002).
+fn sine(phase: f32) -> f32 {
+    let (x, sign) = if phase < 0.5 { (phase, 1.0) } else { (phase - 0.5, -1.0) };
+    // With x in half turns, sin(pi x') = 16 x'(1 - x') / (5 - 4 x'(1 - x')), x' = 2x.
+    let p = 2.0 * x * (1.0 - 2.0 * x);
+    sign * 16.0 * p / (5.0 - 4.0 * p)
+}
+
+
+/// Faults injected in the generated stream. Probabilities are per byte, in 1/65536.
+#[derive(PartialEq, Debug, Default, Clone, Copy)]
+pub struct Noise {
+    pub bit_flip: u16,
+    pub drop: u16,
+    /// Start the stream halfway a frame, as when connecting to a running receiver.
+    pub partial_start: bool,
+}
+
+
+/// A small deterministic random number generator (xorshift32).
+#[derive(Debug, Clone)]
+pub struct XorShift32(u32);
+
+impl XorShift32 {
+    pub fn new(seed: u32) -> Self { XorShift32(if seed == 0 { 0x9e37_79b9 } else { seed }) }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+
+    /// True with a probability of `chance` / 65536.
+    pub fn chance(&mut self, chance: u16) -> bool { (self.next_u32() >> 16) < chance as u32 }
+}
+
+
+/// Generates the byte stream of an IBus receiver: `SetMsg` frames at a fixed rate, with
+/// configurable channel waveforms and optional noise. The same seed gives the same stream.
+///
+/// As an iterator it returns the bytes of the stream; `time_us` gives the time at which
+/// the current frame started.
+pub struct StreamGenerator {
+    channels: [Waveform; 14],
+    interval_us: u64,
+    noise: Noise,
+    rng: XorShift32,
+    time_us: u64,
+    frames: u32,
+    frame: [u8; MAX_LENGTH as usize],
+    len: usize,
+    pos: usize,
+}
+
+impl StreamGenerator {
+    /// A stream of `rate_hz` frames per second, with all channels centered.
+    pub fn new(rate_hz: u32, seed: u32) -> Self {
+        StreamGenerator {
+            channels: [Waveform::Constant(1500); 14],
+            interval_us: 1_000_000 / rate_hz.max(1) as u64,
+            noise: Noise::default(),
+            rng: XorShift32::new(seed),
+            time_us: 0,
+            frames: 0,
+            frame: [0u8; MAX_LENGTH as usize],
+            len: 0,
+            pos: 0,
+        }
+    }
+
+    /// Drive channel `index` (counting from 0) with `waveform`. The stream has the 14 channels
+    /// of a classic frame; higher indices are ignored.
+    pub fn channel(mut self, index: usize, waveform: Waveform) -> Self {
+        if let Some(channel) = self.channels.get_mut(index) {
+            *channel = waveform;
+        }
+        self
+    }
+
+    pub fn with_noise(mut self, noise: Noise) -> Self {
+        self.noise = noise;
+        self
+    }
+
+    /// The channel values of a frame sent at `time_us`.
+    pub fn values_at(&self, time_us: u64) -> [u16; 14] {
+        let mut values = [0u16; 14];
+        for (value, waveform) in values.iter_mut().zip(self.channels.iter()) {
+            *value = waveform.value_at(time_us);
+        }
+        values
+    }
+
+    /// The start time of the frame being returned.
+    pub fn time_us(&self) -> u64 { self.time_us }
+
+    /// The number of frames generated so far.
+    pub fn frames(&self) -> u32 { self.frames }
+
+    /// Generate the next frame, with noise applied, into `buffer`.
+    /// Returns the start time of the frame and the number of bytes written.
+    pub fn next_frame(&mut self, buffer: &mut [u8; MAX_LENGTH as usize]) -> (u64, usize) {
+        let time_us = self.interval_us * self.frames as u64;
+        self.frames += 1;
+        let mut frame = [0u8; MAX_LENGTH as usize];
+        let n = pushIBusMsg(&IBusMsg::SetMsg(self.values_at(time_us)), &mut frame) as usize;
+
+        let start = if self.noise.partial_start && self.frames == 1 {
+            1 + self.rng.next_u32() as usize % (n - 1)
+        } else {
+            0
+        };
+        let mut len = 0;
+        for byte in &frame[start..n] {
+            if self.rng.chance(self.noise.drop) {
+                continue;
+            }
+            buffer[len] = if self.rng.chance(self.noise.bit_flip) {
+                byte ^ (1 << (self.rng.next_u32() % 8))
+            } else {
+                *byte
+            };
+            len += 1;
+        }
+        (time_us, len)
+    }
+}
+
+impl Iterator for StreamGenerator {
+    type Item = u8;
+    fn next(&mut self) -> Option<u8> {
+        while self.pos >= self.len {
+            let mut frame = [0u8; MAX_LENGTH as usize];
+            let (time_us, len) = self.next_frame(&mut frame);
+            self.frame = frame;
+            self.time_us = time_us;
+            self.len = len;
+            self.pos = 0;
+        }
+        self.pos += 1;
+        Some(self.frame[self.pos - 1])
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use crate::testkit::decode_msgs;
+
+    #[test]
+    fn test_waveforms() {
+        let sweep = Waveform::Sweep { min: 1000, max: 2000, period_ms: 1000 };
+        assert_eq!(sweep.value_at(0), 1000);
+        assert_eq!(sweep.value_at(250_000), 1500);
+        assert_eq!(sweep.value_at(500_000), 2000);
+        assert_eq!(sweep.value_at(750_000), 1500);
+        assert_eq!(sweep.value_at(1_000_000), 1000);
+
+        let sine = Waveform::Sine { center: 1500, amplitude: 500, period_ms: 100 };
+        assert_eq!(sine.value_at(0), 1500);
+        assert_eq!(sine.value_at(25_000), 2000);
+        assert_eq!(sine.value_at(50_000), 1500);
+        assert_eq!(sine.value_at(75_000), 1000);
+        assert!((sine.value_at(8_333) as i32 - 1750).abs() <= 1);
+
+        let switch = Waveform::Switch { low: 1000, high: 2000, period_ms: 2 };
+        assert_eq!(switch.value_at(999), 1000);
+        assert_eq!(switch.value_at(1000), 2000);
+        assert_eq!(switch.value_at(2000), 1000);
+    }
+
+    #[test]
+    fn test_clean_stream() {
+        let mut gen = StreamGenerator::new(100, 1)
+            .channel(0, Waveform::Sweep { min: 1000, max: 2000, period_ms: 100 })
+            .channel(4, Waveform::Switch { low: 1000, high: 2000, period_ms: 40 });
+        let bytes: Vec<u8> = gen.by_ref().take(32 * 10).collect();
+        assert_eq!(gen.time_us(), 90_000);
+        let msgs = decode_msgs(&bytes);
+        assert_eq!(msgs.len(), 10);
+        assert_eq!(msgs[0], IBusMsg::SetMsg([1000, 1500, 1500, 1500, 1000, 1500, 1500, 1500, 1500, 1500, 1500, 1500, 1500, 1500]));
+        assert_eq!(msgs[3], IBusMsg::SetMsg([1600, 1500, 1500, 1500, 2000, 1500, 1500, 1500, 1500, 1500, 1500, 1500, 1500, 1500]));
+
+        // Channels beyond the frame are ignored
+        let gen = StreamGenerator::new(100, 1).channel(17, Waveform::Constant(1000));
+        assert_eq!(gen.values_at(0), [1500; 14]);
+    }
+
+    #[test]
+    fn test_noise() {
+        let noise = Noise { bit_flip: 200, drop: 200, partial_start: true };
+        let a: Vec<u8> = StreamGenerator::new(150, 42).with_noise(noise).take(32 * 200).collect();
+        let b: Vec<u8> = StreamGenerator::new(150, 42).with_noise(noise).take(32 * 200).collect();
+        assert_eq!(a, b);
+
+        // The partial frame at the start is skipped, and frames hit by noise are lost
+        let msgs = decode_msgs(&a);
+        assert!(msgs.len() < 200);
+        assert!(msgs.len() > 150);
+        assert!(msgs.iter().all(|m| *m == IBusMsg::SetMsg([1500; 14])));
+        assert_ne!(a[0], 0x20);
+    }
+}
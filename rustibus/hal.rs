@@ -0,0 +1,198 @@
+
+use embedded_hal::serial::{Read, Write};
+
+use crate::RustIBus::{IBusMsg, popIBusMsg};
+use crate::deque::Deque;
+use crate::half_duplex::{SensorBus, Stats, Timing};
+use crate::registry::SensorResponder;
+use crate::serial::{Direction, HalfDuplex, SerialRead, SerialWrite};
+
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Error<E> {
+    /// The serial port reported an error, e.g. a framing or overrun error.
+    Serial(E),
+}
+
+
+/// Decodes the servo stream of an IBus receiver from any `embedded_hal` serial port.
+pub struct IBusReceiver<R: Read<u8>, const SIZE: usize = 64> {
+    serial: R,
+    buffer: Deque<SIZE>,
+}
+
+impl<R: Read<u8>, const SIZE: usize> IBusReceiver<R, SIZE> {
+    pub fn new(serial: R) -> Self {
+        Self { serial, buffer: Deque::new() }
+    }
+
+    pub fn release(self) -> R { self.serial }
+
+    /// Read the available bytes until a message is complete.
+    /// Returns `WouldBlock` when all received bytes have been used without completing one.
+    pub fn poll(&mut self) -> nb::Result<IBusMsg, Error<R::Error>> {
+        loop {
+            while !self.buffer.is_empty() {
+                let (msg, step) = popIBusMsg(&self.buffer);
+                if step == 0 {
+                    break;
+                }
+                for _ in 0..step {
+                    self.buffer.pop();
+                }
+                if let Some(msg) = msg {
+                    return Ok(msg);
+                }
+            }
+            match self.serial.read() {
+                Ok(byte) => self.buffer.push(byte),
+                Err(nb::Error::WouldBlock) => return Err(nb::Error::WouldBlock),
+                Err(nb::Error::Other(e)) => return Err(nb::Error::Other(Error::Serial(e)))
+            }
+        }
+    }
+}
+
+
+/// Adapts an `embedded_hal` serial port to the traits of this crate.
+/// The port is taken to switch between receiving and transmitting by itself, as UARTs
+/// in single-wire (half-duplex) mode do; the last read error is kept for inspection.
+pub struct HalSerial<RW: Read<u8> + Write<u8>> {
+    serial: RW,
+    error: Option<<RW as Read<u8>>::Error>,
+}
+
+impl<RW: Read<u8> + Write<u8>> HalSerial<RW> {
+    pub fn new(serial: RW) -> Self { Self { serial, error: None } }
+    pub fn take_error(&mut self) -> Option<<RW as Read<u8>>::Error> { self.error.take() }
+    pub fn release(self) -> RW { self.serial }
+}
+
+impl<RW: Read<u8> + Write<u8>> SerialRead for HalSerial<RW> {
+    fn read(&mut self) -> Option<u8> {
+        match self.serial.read() {
+            Ok(byte) => Some(byte),
+            Err(nb::Error::WouldBlock) => None,
+            Err(nb::Error::Other(e)) => {
+                self.error = Some(e);
+                None
+            }
+        }
+    }
+}
+
+impl<RW: Read<u8> + Write<u8>> SerialWrite for HalSerial<RW> {
+    fn write(&mut self, byte: u8) -> bool { self.serial.write(byte).is_ok() }
+    /// A port that fails to flush is not going to finish either, so that counts as complete.
+    fn is_tx_complete(&mut self) -> bool { !matches!(self.serial.flush(), Err(nb::Error::WouldBlock)) }
+}
+
+impl<RW: Read<u8> + Write<u8>> HalfDuplex for HalSerial<RW> {
+    fn set_direction(&mut self, _direction: Direction) {}
+}
+
+
+/// Answers the sensor requests of a receiver through any `embedded_hal` serial port.
+pub struct IBusSensorPort<RW: Read<u8> + Write<u8>, S: SensorResponder, const SIZE: usize = 64> {
+    bus: SensorBus<HalSerial<RW>, S, SIZE>,
+}
+
+impl<RW: Read<u8> + Write<u8>, S: SensorResponder, const SIZE: usize> IBusSensorPort<RW, S, SIZE> {
+    pub fn new(serial: RW, responder: S, timing: Timing) -> Self {
+        Self { bus: SensorBus::new(HalSerial::new(serial), responder, timing) }
+    }
+
+    /// Handle received requests and send any response that is due; see `SensorBus::poll`.
+    pub fn poll(&mut self, now_us: u32) -> Result<(), Error<<RW as Read<u8>>::Error>> {
+        self.bus.poll(now_us);
+        match self.bus.port().take_error() {
+            Some(e) => Err(Error::Serial(e)),
+            None => Ok(())
+        }
+    }
+
+    pub fn stats(&self) -> Stats { self.bus.stats() }
+    pub fn responder(&mut self) -> &mut S { self.bus.responder() }
+    pub fn release(self) -> (RW, S) {
+        let (port, responder) = self.bus.release();
+        (port.release(), responder)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RustIBus::{pushIBusMsg, IBusSensor};
+    use crate::registry::SensorRegistry;
+
+    #[derive(PartialEq, Debug, Clone, Copy)]
+    struct Overrun;
+
+    /// A serial port that returns the scripted bytes, with `None` for an error.
+    struct Script<'a> {
+        rx: &'a [Option<u8>],
+        tx: Deque<64>,
+        flush: nb::Result<(), Overrun>,
+    }
+
+    impl Read<u8> for Script<'_> {
+        type Error = Overrun;
+        fn read(&mut self) -> nb::Result<u8, Overrun> {
+            match self.rx.split_first() {
+                Some((byte, rest)) => {
+                    self.rx = rest;
+                    byte.ok_or(nb::Error::Other(Overrun))
+                },
+                None => Err(nb::Error::WouldBlock)
+            }
+        }
+    }
+
+    impl Write<u8> for Script<'_> {
+        type Error = Overrun;
+        fn write(&mut self, byte: u8) -> nb::Result<(), Overrun> {
+            self.tx.push(byte);
+            Ok(())
+        }
+        fn flush(&mut self) -> nb::Result<(), Overrun> { self.flush }
+    }
+
+    #[test]
+    fn test_receiver() {
+        let rx = [Some(0x55), Some(0x04), Some(0x81), Some(0x7a), Some(0xff), None,
+                  Some(0x04), Some(0x92), Some(0x69), Some(0xff), Some(0x04)];
+        let mut receiver = IBusReceiver::<_>::new(Script { rx: &rx, tx: Deque::new(), flush: Ok(()) });
+        assert_eq!(receiver.poll(), Ok(IBusMsg::DiscoveryRequest(1)));
+        assert_eq!(receiver.poll(), Err(nb::Error::Other(Error::Serial(Overrun))));
+        assert_eq!(receiver.poll(), Ok(IBusMsg::TypeRequest(2)));
+        assert_eq!(receiver.poll(), Err(nb::Error::WouldBlock));
+    }
+
+    #[test]
+    fn test_sensor_port() {
+        let rx = [Some(0x04), Some(0xa1), Some(0x5a), Some(0xff), None];
+        let mut volt = || 1234u32;
+        let mut registry = SensorRegistry::<1>::new();
+        registry.register(IBusSensor::EXTV, &mut volt).unwrap();
+        let mut port = IBusSensorPort::<_, _>::new(Script { rx: &rx, tx: Deque::new(), flush: Ok(()) }, registry, Timing::default());
+        assert_eq!(port.poll(0), Err(Error::Serial(Overrun)));
+        assert_eq!(port.poll(100), Ok(()));
+        assert_eq!(port.stats().responses, 1);
+
+        let (serial, _) = port.release();
+        let mut expected = [0u8; 6];
+        pushIBusMsg(&IBusMsg::ValueResponseShort(1, 1234), &mut expected);
+        assert!(serial.tx.iter().eq(expected.iter().copied()));
+    }
+
+    #[test]
+    fn test_tx_complete() {
+        let mut serial = HalSerial::new(Script { rx: &[], tx: Deque::new(), flush: Err(nb::Error::WouldBlock) });
+        assert!(!serial.is_tx_complete());
+        let mut serial = HalSerial::new(Script { flush: Err(nb::Error::Other(Overrun)), ..serial.release() });
+        assert!(serial.is_tx_complete());
+        let mut serial = HalSerial::new(Script { flush: Ok(()), ..serial.release() });
+        assert!(serial.is_tx_complete());
+    }
+}
@@ -0,0 +1,310 @@
+
+use crate::RustIBus::{IBusMsg, popIBusMsg, pushIBusMsg, MAX_LENGTH};
+use crate::deque::Deque;
+use crate::registry::SensorResponder;
+use crate::serial::{Direction, HalfDuplex};
+
+
+/// When a response may be sent, counted in microseconds from the end of the request.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Timing {
+    /// Time the receiver needs to release the wire before we may drive it.
+    pub min_turnaround_us: u32,
+    /// A response that can not start within this time is dropped, as the receiver
+    /// will no longer be listening for it.
+    pub max_turnaround_us: u32,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Timing { min_turnaround_us: 100, max_turnaround_us: 1000 }
+    }
+}
+
+
+#[derive(PartialEq, Debug, Default, Clone, Copy)]
+pub struct Stats {
+    /// Sensor requests received, for any address.
+    pub requests: u32,
+    /// Responses sent.
+    pub responses: u32,
+    /// Responses dropped because they could not be sent within the turnaround window.
+    pub late: u32,
+    /// Echoed bytes that did not match what was sent, e.g. because someone else drove the wire.
+    pub collisions: u32,
+}
+
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum State {
+    Idle,
+    Waiting { start: u32, deadline: u32 },
+    Sending,
+    Draining,
+}
+
+
+/// Drops the bytes we sent ourselves from what is read back from a single wire.
+pub(crate) struct EchoFilter {
+    sent: [u8; MAX_LENGTH as usize],
+    len: usize,
+    pos: usize,
+}
+
+impl EchoFilter {
+    pub(crate) const fn new() -> Self {
+        EchoFilter { sent: [0u8; MAX_LENGTH as usize], len: 0, pos: 0 }
+    }
+
+    /// Expect the echo of `bytes`, forgetting any echo still outstanding.
+    pub(crate) fn expect(&mut self, bytes: &[u8]) {
+        self.len = bytes.len().min(self.sent.len());
+        self.sent[..self.len].copy_from_slice(&bytes[..self.len]);
+        self.pos = 0;
+    }
+
+    /// Returns the byte if it is not an echo of ours, and whether it was expected to be one.
+    pub(crate) fn filter(&mut self, byte: u8) -> (Option<u8>, bool) {
+        if self.pos >= self.len {
+            return (Some(byte), false);
+        }
+        if byte == self.sent[self.pos] {
+            self.pos += 1;
+            return (None, false);
+        }
+        // Not our own byte: stop expecting echoes and pass it on.
+        self.pos = self.len;
+        (Some(byte), true)
+    }
+}
+
+
+/// True if `time` has been reached at `now`, allowing for the timer to wrap around.
+fn reached(now: u32, time: u32) -> bool {
+    (now.wrapping_sub(time) as i32) >= 0
+}
+
+
+/// Drives a sensor responder on a single-wire IBus sensor port.
+///
+/// Bytes read from the port are decoded, and requests are handed to the responder. A response
+/// is sent within the turnaround window given by `Timing`, after which the port is switched back
+/// to receiving. Everything we send is read back on the same wire; those echoes are dropped
+/// before they reach the decoder.
+///
+/// `poll` must be called often, with a microsecond timestamp: at least every byte time
+/// (87 us at 115200 Baud) while a request is coming in or a response is going out.
+pub struct SensorBus<P: HalfDuplex, R: SensorResponder, const SIZE: usize = 64> {
+    port: P,
+    responder: R,
+    timing: Timing,
+    state: State,
+    rx: Deque<SIZE>,
+    tx: [u8; MAX_LENGTH as usize],
+    tx_len: usize,
+    tx_pos: usize,
+    echo: EchoFilter,
+    awaiting: Option<u32>,
+    stats: Stats,
+}
+
+impl<P: HalfDuplex, R: SensorResponder, const SIZE: usize> SensorBus<P, R, SIZE> {
+    pub fn new(mut port: P, responder: R, timing: Timing) -> Self {
+        port.set_direction(Direction::Receive);
+        Self {
+            port,
+            responder,
+            timing,
+            state: State::Idle,
+            rx: Deque::new(),
+            tx: [0u8; MAX_LENGTH as usize],
+            tx_len: 0,
+            tx_pos: 0,
+            echo: EchoFilter::new(),
+            awaiting: None,
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> Stats { self.stats }
+    pub fn responder(&mut self) -> &mut R { &mut self.responder }
+    pub fn port(&mut self) -> &mut P { &mut self.port }
+
+    /// Stop driving the bus and return the port and the responder.
+    pub fn release(self) -> (P, R) { (self.port, self.responder) }
+
+    /// Handle all received bytes and continue any response that is due.
+    pub fn poll(&mut self, now_us: u32) {
+        self.receive(now_us);
+
+        // A responder may answer a request later, e.g. after asking another bus.
+        if let Some(response) = self.responder.poll(now_us) {
+            match self.awaiting.take() {
+                Some(request_us) if self.state == State::Idle => self.schedule(&response, request_us),
+                _ => self.stats.late += 1
+            }
+        }
+        if let Some(request_us) = self.awaiting {
+            if !reached(request_us.wrapping_add(self.timing.max_turnaround_us), now_us) {
+                self.awaiting = None;
+            }
+        }
+
+        if let State::Waiting { start, deadline } = self.state {
+            if !reached(deadline, now_us) {
+                self.stats.late += 1;
+                self.state = State::Idle;
+            } else if reached(now_us, start) {
+                self.port.set_direction(Direction::Transmit);
+                self.echo.expect(&self.tx[..self.tx_len]);
+                self.state = State::Sending;
+            }
+        }
+        if self.state == State::Sending {
+            while self.tx_pos < self.tx_len && self.port.write(self.tx[self.tx_pos]) {
+                self.tx_pos += 1;
+            }
+            if self.tx_pos == self.tx_len {
+                self.stats.responses += 1;
+                self.state = State::Draining;
+            }
+        }
+        if self.state == State::Draining && self.port.is_tx_complete() {
+            self.port.set_direction(Direction::Receive);
+            self.state = State::Idle;
+        }
+    }
+
+    fn receive(&mut self, now_us: u32) {
+        while let Some(byte) = self.port.read() {
+            let (byte, collision) = self.echo.filter(byte);
+            if collision {
+                self.stats.collisions += 1;
+            }
+            if let Some(byte) = byte {
+                self.rx.push(byte);
+            }
+        }
+
+        while !self.rx.is_empty() {
+            let (msg, step) = popIBusMsg(&self.rx);
+            if step == 0 {
+                break;
+            }
+            for _ in 0..step {
+                self.rx.pop();
+            }
+            if let Some(msg) = msg {
+                self.handle(&msg, now_us);
+            }
+        }
+    }
+
+    fn handle(&mut self, msg: &IBusMsg, now_us: u32) {
+        match msg {
+            IBusMsg::DiscoveryRequest(_) | IBusMsg::TypeRequest(_) | IBusMsg::ValueRequest(_) => (),
+            _ => return
+        }
+        self.stats.requests += 1;
+        if self.state != State::Idle {
+            return;
+        }
+        match self.responder.respond(msg) {
+            Some(response) => {
+                self.awaiting = None;
+                self.schedule(&response, now_us);
+            },
+            None => self.awaiting = Some(now_us)
+        }
+    }
+
+    fn schedule(&mut self, response: &IBusMsg, request_us: u32) {
+        self.tx_len = pushIBusMsg(response, &mut self.tx) as usize;
+        self.tx_pos = 0;
+        self.state = State::Waiting {
+            start: request_us.wrapping_add(self.timing.min_turnaround_us),
+            deadline: request_us.wrapping_add(self.timing.max_turnaround_us),
+        };
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RustIBus::{IBusSensor, IBusSensorLength};
+    use crate::registry::SensorRegistry;
+    use crate::testkit::{Fault, MockSerial};
+
+    #[test]
+    fn test_response_and_echo() {
+        let mut temp = || 650u32;
+        let mut registry = SensorRegistry::<2>::new();
+        registry.register(IBusSensor::TEMP, &mut temp).unwrap();
+        let port = MockSerial::new().with_echo().strict();
+        let mut bus: SensorBus<_, _> = SensorBus::new(port, registry, Timing::default());
+        assert_eq!(bus.port().direction(), Direction::Receive);
+
+        bus.port().inject_msg(&IBusMsg::TypeRequest(1));
+        bus.poll(1000);
+        bus.poll(1099);
+        assert!(bus.port().transmitted().is_empty());
+        bus.poll(1100);
+        assert_eq!(bus.port().take_responses(), [IBusMsg::TypeResponse(1, IBusSensor::TEMP, IBusSensorLength::Short)]);
+        assert_eq!(bus.port().direction(), Direction::Receive);
+
+        // The echo of the response is dropped, the next request is handled normally
+        bus.port().inject_msg(&IBusMsg::ValueRequest(1));
+        bus.poll(2000);
+        assert!(bus.rx.is_empty());
+        bus.poll(2200);
+        assert_eq!(bus.port().take_responses(), [IBusMsg::ValueResponseShort(1, 650)]);
+
+        // Requests for other sensors are not answered
+        bus.port().inject_msg(&IBusMsg::DiscoveryRequest(2));
+        bus.poll(3000);
+        bus.poll(3500);
+        assert!(bus.port().transmitted().is_empty());
+
+        assert_eq!(bus.stats(), Stats { requests: 3, responses: 2, late: 0, collisions: 0 });
+    }
+
+    #[test]
+    fn test_late_response() {
+        let mut temp = || 650u32;
+        let mut registry = SensorRegistry::<2>::new();
+        registry.register(IBusSensor::TEMP, &mut temp).unwrap();
+        let timing = Timing { min_turnaround_us: 50, max_turnaround_us: 500 };
+        let mut bus: SensorBus<_, _> = SensorBus::new(MockSerial::new().with_echo().strict(), registry, timing);
+
+        bus.port().inject_msg(&IBusMsg::DiscoveryRequest(1));
+        bus.poll(u32::MAX - 100);
+        bus.poll(400);
+        assert!(bus.port().transmitted().is_empty());
+        assert_eq!(bus.port().direction(), Direction::Receive);
+        assert_eq!(bus.stats().late, 1);
+
+        // The timer wrapped around, the next request is answered right at the deadline
+        bus.port().inject_msg(&IBusMsg::DiscoveryRequest(1));
+        bus.poll(1000);
+        bus.poll(1500);
+        assert_eq!(bus.port().take_responses(), [IBusMsg::DiscoveryResponse(1)]);
+    }
+
+    #[test]
+    fn test_collision() {
+        let mut temp = || 650u32;
+        let mut registry = SensorRegistry::<2>::new();
+        registry.register(IBusSensor::TEMP, &mut temp).unwrap();
+        let mut bus: SensorBus<_, _> = SensorBus::new(MockSerial::new().with_echo().strict(), registry, Timing::default());
+
+        bus.port().inject_msg(&IBusMsg::DiscoveryRequest(1));
+        // The second byte of the echo is garbled
+        bus.port().fault(Fault::Flip(5, 0xff));
+        bus.poll(0);
+        bus.poll(100);
+        bus.poll(200);
+        assert_eq!(bus.stats().collisions, 1);
+        assert_eq!(bus.stats().responses, 1);
+    }
+}
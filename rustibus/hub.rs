@@ -0,0 +1,291 @@
+
+use crate::RustIBus::{IBusMsg, popIBusResponse, pushIBusMsg, MAX_LENGTH};
+use crate::deque::Deque;
+use crate::half_duplex::{EchoFilter, Timing};
+use crate::registry::{SensorResponder, MAX_ADDRESS};
+use crate::serial::{Direction, HalfDuplex};
+
+
+/// A sensor responder that answers for its local sensors, and relays all other requests to a
+/// chain of sensors on a second, downstream IBus port.
+///
+/// The local sensors take the first addresses; the downstream sensors follow, so downstream
+/// address 1 is presented to the receiver as the first address after the local sensors.
+/// Responses from downstream arrive later than the request, so the hub answers them through
+/// `SensorResponder::poll`; use it with a driver such as `half_duplex::SensorBus`, and give the
+/// hub the same `Timing` with `with_timing`.
+///
+/// A relayed answer is only of use while the receiver still waits for it, so the hub waits
+/// for downstream no longer than `max_turnaround_us` of the upstream `Timing`. The whole
+/// downstream round trip has to fit in that window: the forwarded request, the turnaround of
+/// the downstream sensor and its response, about 1 ms for a short value at 115200 Baud.
+/// Raise `max_turnaround_us` as far as the receiver allows.
+///
+/// Requests that come in while a relayed request is still being sent or waits for its answer
+/// are not relayed. Type responses for sensor types that `IBusSensor` does not know are not
+/// relayed either.
+pub struct SensorHub<L: SensorResponder, D: HalfDuplex, const SIZE: usize = 64> {
+    local: L,
+    offset: u8,
+    port: D,
+    rx: Deque<SIZE>,
+    echo: EchoFilter,
+    tx: [u8; MAX_LENGTH as usize],
+    tx_len: usize,
+    tx_pos: usize,
+    transmitting: bool,
+    pending: Option<IBusMsg>,
+    sent_us: u32,
+    now_us: u32,
+    timeout_us: u32,
+}
+
+impl<L: SensorResponder, D: HalfDuplex, const SIZE: usize> SensorHub<L, D, SIZE> {
+    /// Create a hub around the `local` sensors, relaying to the sensors on `port`.
+    /// The number of local addresses is found by asking `local` for discovery from address 1 up.
+    pub fn new(mut local: L, mut port: D) -> Self {
+        let mut offset = 0u8;
+        while offset < MAX_ADDRESS && local.respond(&IBusMsg::DiscoveryRequest(offset + 1)).is_some() {
+            offset += 1;
+        }
+        port.set_direction(Direction::Receive);
+        Self {
+            local,
+            offset,
+            port,
+            rx: Deque::new(),
+            echo: EchoFilter::new(),
+            tx: [0u8; MAX_LENGTH as usize],
+            tx_len: 0,
+            tx_pos: 0,
+            transmitting: false,
+            pending: None,
+            sent_us: 0,
+            now_us: 0,
+            timeout_us: Timing::default().max_turnaround_us,
+        }
+    }
+
+    /// Wait for downstream as long as the upstream `timing` allows an answer to start.
+    pub fn with_timing(mut self, timing: Timing) -> Self {
+        self.timeout_us = timing.max_turnaround_us;
+        self
+    }
+
+    /// The number of addresses taken by the local sensors.
+    pub fn local_count(&self) -> u8 { self.offset }
+    pub fn local(&mut self) -> &mut L { &mut self.local }
+    pub fn port(&mut self) -> &mut D { &mut self.port }
+
+    fn forward(&mut self, request: IBusMsg) {
+        self.tx_len = pushIBusMsg(&request, &mut self.tx) as usize;
+        self.tx_pos = 0;
+        self.echo.expect(&self.tx[..self.tx_len]);
+        self.port.set_direction(Direction::Transmit);
+        self.transmitting = true;
+        self.pending = Some(request);
+        self.sent_us = self.now_us;
+        self.send();
+    }
+
+    fn send(&mut self) {
+        while self.tx_pos < self.tx_len && self.port.write(self.tx[self.tx_pos]) {
+            self.tx_pos += 1;
+        }
+    }
+
+    /// Translate a downstream response to the request it answers, at its upstream address.
+    fn relay(&self, response: &IBusMsg) -> Option<IBusMsg> {
+        let offset = self.offset;
+        match (self.pending?, *response) {
+            (IBusMsg::DiscoveryRequest(a), IBusMsg::DiscoveryResponse(b)) if a == b =>
+                Some(IBusMsg::DiscoveryResponse(b + offset)),
+            (IBusMsg::TypeRequest(a), IBusMsg::TypeResponse(b, sensor, length)) if a == b =>
+                Some(IBusMsg::TypeResponse(b + offset, sensor, length)),
+            (IBusMsg::ValueRequest(a), IBusMsg::ValueResponseShort(b, value)) if a == b =>
+                Some(IBusMsg::ValueResponseShort(b + offset, value)),
+            (IBusMsg::ValueRequest(a), IBusMsg::ValueResponseLong(b, value)) if a == b =>
+                Some(IBusMsg::ValueResponseLong(b + offset, value)),
+            _ => None
+        }
+    }
+}
+
+impl<L: SensorResponder, D: HalfDuplex, const SIZE: usize> SensorResponder for SensorHub<L, D, SIZE> {
+    fn respond(&mut self, msg: &IBusMsg) -> Option<IBusMsg> {
+        if let Some(response) = self.local.respond(msg) {
+            return Some(response);
+        }
+        let addr = match *msg {
+            IBusMsg::DiscoveryRequest(addr) | IBusMsg::TypeRequest(addr) | IBusMsg::ValueRequest(addr) => addr,
+            _ => return None
+        };
+        // One request at a time: the downstream port is busy until it is answered or timed out
+        if addr <= self.offset || addr > MAX_ADDRESS || self.transmitting || self.pending.is_some() {
+            return None;
+        }
+        let downstream = addr - self.offset;
+        self.forward(match *msg {
+            IBusMsg::DiscoveryRequest(_) => IBusMsg::DiscoveryRequest(downstream),
+            IBusMsg::TypeRequest(_) => IBusMsg::TypeRequest(downstream),
+            _ => IBusMsg::ValueRequest(downstream),
+        });
+        None
+    }
+
+    fn poll(&mut self, now_us: u32) -> Option<IBusMsg> {
+        self.now_us = now_us;
+        // Only one request is answered at a time, so the local sensors and downstream
+        // never both have an answer here
+        let local = self.local.poll(now_us);
+
+        if self.transmitting {
+            self.send();
+            if self.tx_pos == self.tx_len && self.port.is_tx_complete() {
+                self.port.set_direction(Direction::Receive);
+                self.transmitting = false;
+            }
+        }
+
+        while let Some(byte) = self.port.read() {
+            if let (Some(byte), _) = self.echo.filter(byte) {
+                self.rx.push(byte);
+            }
+        }
+
+        let mut relayed = None;
+        while !self.rx.is_empty() {
+            let (msg, step) = popIBusResponse(&self.rx);
+            if step == 0 {
+                break;
+            }
+            for _ in 0..step {
+                self.rx.pop();
+            }
+            if let Some(response) = msg.and_then(|m| self.relay(&m)) {
+                self.pending = None;
+                relayed = Some(response);
+            }
+        }
+
+        if self.pending.is_some() && now_us.wrapping_sub(self.sent_us) > self.timeout_us {
+            self.pending = None;
+        }
+        local.or(relayed)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RustIBus::{IBusSensor, IBusSensorLength};
+    use crate::half_duplex::{SensorBus, Timing};
+    use crate::registry::SensorRegistry;
+    use crate::testkit::{wire, SimReceiver};
+
+    #[test]
+    fn test_relay() {
+        let mut temp = || 650u32;
+        let mut local = SensorRegistry::<2>::new();
+        local.register(IBusSensor::TEMP, &mut temp).unwrap();
+
+        let mut volt = || 1200u32;
+        let mut press = || 101_325u32;
+        let mut chained = SensorRegistry::<2>::new();
+        chained.register(IBusSensor::EXTV, &mut volt).unwrap();
+        chained.register(IBusSensor::PRESS, &mut press).unwrap();
+
+        let (receiver_end, hub_up) = wire();
+        let (hub_down, sensor_port) = wire();
+
+        let timing = Timing { min_turnaround_us: 100, max_turnaround_us: 3000 };
+        let hub = SensorHub::<_, _>::new(local, hub_down).with_timing(timing);
+        assert_eq!(hub.local_count(), 1);
+        let mut hub: SensorBus<_, _> = SensorBus::new(hub_up, hub, timing);
+        let mut sensors: SensorBus<_, _> = SensorBus::new(sensor_port, chained, Timing::default());
+        let mut receiver = SimReceiver::new(receiver_end).with_timeout(4000);
+        let mut node = |now| {
+            hub.poll(now);
+            sensors.poll(now);
+        };
+
+        assert_eq!(receiver.discover(&mut node), [
+            (1, IBusSensor::TEMP, IBusSensorLength::Short),
+            (2, IBusSensor::EXTV, IBusSensorLength::Short),
+            (3, IBusSensor::PRESS, IBusSensorLength::Long),
+        ]);
+        assert_eq!(receiver.request(&IBusMsg::ValueRequest(1), &mut node), Some(IBusMsg::ValueResponseShort(1, 650)));
+        assert_eq!(receiver.request(&IBusMsg::ValueRequest(2), &mut node), Some(IBusMsg::ValueResponseShort(2, 1200)));
+        assert_eq!(receiver.request(&IBusMsg::ValueRequest(3), &mut node), Some(IBusMsg::ValueResponseLong(3, 101_325)));
+        assert_eq!(receiver.request(&IBusMsg::ValueRequest(4), &mut node), None);
+        assert_eq!(hub.stats().late, 0);
+    }
+
+    /// A local sensor at address 1 that answers value requests on the next poll.
+    struct Deferred(Option<IBusMsg>);
+
+    impl SensorResponder for Deferred {
+        fn respond(&mut self, msg: &IBusMsg) -> Option<IBusMsg> {
+            match *msg {
+                IBusMsg::DiscoveryRequest(1) => Some(IBusMsg::DiscoveryResponse(1)),
+                IBusMsg::ValueRequest(1) => {
+                    self.0 = Some(IBusMsg::ValueResponseShort(1, 42));
+                    None
+                },
+                _ => None
+            }
+        }
+
+        fn poll(&mut self, _now_us: u32) -> Option<IBusMsg> { self.0.take() }
+    }
+
+    #[test]
+    fn test_local_poll() {
+        let (hub_down, _) = wire();
+        let mut hub = SensorHub::<_, _>::new(Deferred(None), hub_down);
+        assert_eq!(hub.local_count(), 1);
+        assert_eq!(hub.respond(&IBusMsg::ValueRequest(1)), None);
+        assert_eq!(hub.poll(100), Some(IBusMsg::ValueResponseShort(1, 42)));
+        assert_eq!(hub.poll(200), None);
+    }
+
+    #[test]
+    fn test_busy() {
+        let mut volt = || 1200u32;
+        let mut chained = SensorRegistry::<2>::new();
+        chained.register(IBusSensor::EXTV, &mut volt).unwrap();
+        let (hub_down, sensor_port) = wire();
+        let mut hub = SensorHub::<_, _>::new(SensorRegistry::<1>::new(), hub_down);
+        let mut sensors: SensorBus<_, _> = SensorBus::new(sensor_port, chained, Timing::default());
+
+        hub.poll(0);
+        assert_eq!(hub.respond(&IBusMsg::ValueRequest(1)), None);
+        // A second request before the first is answered is not relayed
+        assert_eq!(hub.respond(&IBusMsg::DiscoveryRequest(2)), None);
+        let mut relayed = None;
+        for now in (0..2000).step_by(10) {
+            sensors.poll(now);
+            relayed = relayed.or(hub.poll(now));
+        }
+        assert_eq!(relayed, Some(IBusMsg::ValueResponseShort(1, 1200)));
+        assert_eq!(sensors.stats().requests, 1);
+    }
+
+    #[test]
+    fn test_timeout() {
+        // Downstream never answers: the next request may go out once the upstream window closed
+        let (hub_down, _) = wire();
+        let timing = Timing { min_turnaround_us: 100, max_turnaround_us: 1500 };
+        let mut hub = SensorHub::<_, _>::new(SensorRegistry::<1>::new(), hub_down).with_timing(timing);
+        hub.poll(0);
+        hub.respond(&IBusMsg::ValueRequest(1));
+        hub.poll(1_500);
+        assert!(hub.pending.is_some());
+        hub.poll(1_501);
+        assert!(hub.pending.is_none());
+
+        let hub = SensorHub::<_, _>::new(SensorRegistry::<1>::new(), wire().0);
+        assert_eq!(hub.timeout_us, Timing::default().max_turnaround_us);
+    }
+}
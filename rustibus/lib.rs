@@ -1,5 +1,11 @@
 
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
+
+// Lets generated code refer to `::rustibus` from within this crate as well.
+extern crate self as rustibus;
+
+#[cfg(any(test, feature = "testkit"))]
+extern crate alloc;
 
 
 pub mod RustIBus {
@@ -8,10 +14,10 @@ pub mod RustIBus {
     use core::iter::ExactSizeIterator;
 
 
-    const SET: u8 = 0x40;
-    const DISCOVER: u8 = 0x80;
-    const TYPE: u8 = 0x90;
-    const VALUE: u8 = 0xa0;
+    pub(crate) const SET: u8 = 0x40;
+    pub(crate) const DISCOVER: u8 = 0x80;
+    pub(crate) const TYPE: u8 = 0x90;
+    pub(crate) const VALUE: u8 = 0xa0;
 
     #[derive(PartialEq, Debug, Clone, Copy)]
     #[repr(u8)]
@@ -31,8 +37,44 @@ pub mod RustIBus {
         Long = 0x04,
     }
 
+    impl TryFrom<u8> for IBusSensor {
+        type Error = u8;
+        fn try_from(value: u8) -> Result<Self, u8> {
+            match value {
+                0x00 => Ok(IBusSensor::INTV),
+                0x01 => Ok(IBusSensor::TEMP),
+                0x02 => Ok(IBusSensor::RPM),
+                0x03 => Ok(IBusSensor::EXTV),
+                0x41 => Ok(IBusSensor::PRESS),
+                0xfd => Ok(IBusSensor::SERVO),
+                _ => Err(value)
+            }
+        }
+    }
+
+    impl TryFrom<u8> for IBusSensorLength {
+        type Error = u8;
+        fn try_from(value: u8) -> Result<Self, u8> {
+            match value {
+                0x02 => Ok(IBusSensorLength::Short),
+                0x04 => Ok(IBusSensorLength::Long),
+                _ => Err(value)
+            }
+        }
+    }
+
+    impl IBusSensor {
+        /// The value length a sensor of this type normally reports.
+        pub const fn default_length(&self) -> IBusSensorLength {
+            match self {
+                IBusSensor::PRESS => IBusSensorLength::Long,
+                _ => IBusSensorLength::Short
+            }
+        }
+    }
+
 
-    #[derive(PartialEq, Debug)]
+    #[derive(PartialEq, Debug, Clone, Copy)]
     pub enum IBusMsg {
         DiscoveryRequest(u8),
         DiscoveryResponse(u8),
@@ -44,22 +86,74 @@ pub mod RustIBus {
         ValueResponseLong(u8, u32)
     }
 
+    impl IBusMsg {
+        /// A value response for sensor `addr`, of the size given by `length`.
+        /// Values too large for a short response are sent as `u16::MAX`.
+        pub fn value_response(addr: u8, length: IBusSensorLength, value: u32) -> IBusMsg {
+            match length {
+                IBusSensorLength::Short => IBusMsg::ValueResponseShort(addr, value.min(u16::MAX as u32) as u16),
+                IBusSensorLength::Long => IBusMsg::ValueResponseLong(addr, value)
+            }
+        }
+    }
+
 
-    const MAX_LENGTH: u8 = 0x20;
+    pub const MAX_LENGTH: u8 = 0x20;
     const MIN_LENGTH: u8 = 0x04;
 
+    /// The number of channels in a frame using the 18 channel extension.
+    pub const MAX_CHANNELS: usize = 18;
+
+
+    /// Pack 18 channel values of 12 bits into the 14 words of a set message.
+    /// Channels 15 to 18 are spread over the otherwise unused high nibbles of three channels
+    /// each, as newer FlySky receivers do: channel 15 over channels 1-3, 16 over 4-6, etc.
+    pub fn packChannels(values: &[u16; MAX_CHANNELS]) -> [u16; 14] {
+        let mut data = [0u16; 14];
+        for i in 0..14 {
+            data[i] = values[i] & 0x0fff;
+        }
+        for i in 0..4 {
+            let extra = values[14 + i] & 0x0fff;
+            for j in 0..3 {
+                data[3 * i + j] |= ((extra >> (4 * j)) & 0x0f) << 12;
+            }
+        }
+        data
+    }
+
+    /// The reverse of `packChannels`. Frames from receivers without the extension unpack
+    /// to 14 channels followed by 4 zero channels.
+    pub fn unpackChannels(data: &[u16; 14]) -> [u16; MAX_CHANNELS] {
+        let mut values = [0u16; MAX_CHANNELS];
+        for i in 0..14 {
+            values[i] = data[i] & 0x0fff;
+        }
+        for i in 0..4 {
+            for j in 0..3 {
+                values[14 + i] |= (data[3 * i + j] >> 12) << (4 * j);
+            }
+        }
+        values
+    }
 
-    fn checkForResync<T: Index<usize, Output=u8> + ExactSizeIterator>(buffer: &T) -> bool {
+
+    fn checkForResync<T: Index<usize, Output=u8> + ExactSizeIterator>(buffer: &T, responses: bool) -> bool {
         /// Check if the buffer contains a valid IBus message.
         /// Checks for buffer length, valid command code and the CRC.
         /// Returns true if a single byte can be consumed, to try and resync.
+        // Nothing to check in an empty buffer
+        if buffer.len() == 0 {
+            return false;
+        }
         // Check for a correct length character
-        if (buffer.len() > 0) && ((buffer[0] < MIN_LENGTH) || (buffer[0] > MAX_LENGTH)) {
+        if (buffer[0] < MIN_LENGTH) || (buffer[0] > MAX_LENGTH) {
             return true;
         }
 
-        // If enough bytes have been received, check the message contents
-        if (buffer.len() as u8) < buffer[0] {
+        // If enough bytes have been received, check the message contents.
+        // The length is at least MIN_LENGTH here, so the command byte is there as well.
+        if buffer.len() < 2 || buffer.len() < buffer[0] as usize {
             // We can't check the CRC yet
             return false;
         }
@@ -68,7 +162,10 @@ pub mod RustIBus {
         // The high nibble is the command, the low nibble the address.
         match buffer[1] & 0xf0 {
             SET => (),
-            TYPE | VALUE | DISCOVER => { if buffer[0] != 0x04 { return true; } },
+            DISCOVER => { if buffer[0] != 0x04 { return true; } },
+            TYPE => { if buffer[0] != (if responses { 0x06 } else { 0x04 }) { return true; } },
+            VALUE if responses => { if buffer[0] != 0x06 && buffer[0] != 0x08 { return true; } },
+            VALUE => { if buffer[0] != 0x04 { return true; } },
             _ => {return true;}
         };
 
@@ -97,13 +194,13 @@ pub mod RustIBus {
 
     pub fn popIBusMsg<T: Index<usize, Output=u8> + ExactSizeIterator>(buffer: &T) -> (Option<IBusMsg>, u8) {
         // Find a correct message
-        while checkForResync(buffer) {
+        while checkForResync(buffer, false) {
             // Remove the first character in an attempt to re-synchronize.
             return (None, 1);
         }
 
         // Ensure a message has been received. If not, ask for more.
-        if buffer.len() < 2 || (buffer.len() as u8) < buffer[0usize] {
+        if buffer.len() < 2 || buffer.len() < buffer[0usize] as usize {
             return (None, 0);
         }
 
@@ -121,39 +218,115 @@ pub mod RustIBus {
         return (msg, length);
     }
 
+    /// Like `popIBusMsg`, but for the sensor side of the bus: discovery, type and value
+    /// messages are read as the responses of sensors instead of the requests of a receiver.
+    /// A type response for a sensor type that is not known is consumed without returning a message.
+    pub fn popIBusResponse<T: Index<usize, Output=u8> + ExactSizeIterator>(buffer: &T) -> (Option<IBusMsg>, u8) {
+        if checkForResync(buffer, true) {
+            return (None, 1);
+        }
+        if buffer.len() < 2 || buffer.len() < buffer[0usize] as usize {
+            return (None, 0);
+        }
+
+        let length = buffer[0];
+        let cmnd = buffer[1] & 0xf0;
+        let addr = buffer[1] & 0x0f;
+        let msg = match cmnd {
+            DISCOVER => Some(IBusMsg::DiscoveryResponse(addr)),
+            SET => Some(popSetMsg(length, buffer)),
+            TYPE => match (IBusSensor::try_from(buffer[2]), IBusSensorLength::try_from(buffer[3])) {
+                (Ok(sensor), Ok(size)) => Some(IBusMsg::TypeResponse(addr, sensor, size)),
+                _ => None
+            },
+            VALUE if length == 0x06 =>
+                Some(IBusMsg::ValueResponseShort(addr, buffer[2] as u16 | (buffer[3] as u16) << 8)),
+            VALUE =>
+                Some(IBusMsg::ValueResponseLong(addr, buffer[2] as u32 | (buffer[3] as u32) << 8
+                    | (buffer[4] as u32) << 16 | (buffer[5] as u32) << 24)),
+            _ => None
+        };
+        (msg, length)
+    }
+
 
-// fn pushMsg(msg: &[u8], buffer: &mut Buffer) {
-//     let length = (msg.len() & 0xff) as u8 + 3;
-//     if length < MIN_LENGTH || length > MAX_LENGTH { return; }
-//     let mut crc: u16 = 0xffff;
-//     buffer.push(length);
-//     crc -= length as u16;
-//     for b in msg {
-//         buffer.push(*b);
-//         crc -= *b as u16;
-//     }
-//     buffer.push((crc & 0xff) as u8);
-//     buffer.push((crc >> 8) as u8);
-// }
-//
-//
-// pub fn pushIBusMsg(msg: &IBusMsg, buffer: &mut Buffer) {
-//     match msg {
-//         IBusMsg::DiscoveryResponse(addr) =>
-//             pushMsg(&[DISCOVER + addr], buffer),
-//         IBusMsg::TypeResponse(addr, sensortype, length) =>
-//             pushMsg(&[TYPE+addr, *sensortype as u8, *length as u8], buffer),
-//         IBusMsg::ValueResponseShort(addr, value) =>
-//             pushMsg(&[VALUE+addr, (value&0xff) as u8, (value>>8) as u8], buffer),
-//         IBusMsg::ValueResponseLong(addr, value) =>
-//             pushMsg(&[VALUE+addr, (value&0xff) as u8, ((value>>8) & 0xff) as u8, ((value>>16) & 0xff) as u8, ((value>>24) & 0xff) as u8], buffer),
-//         _ => panic!()
-//     }
-// }
+    fn pushMsg(msg: &[u8], buffer: &mut [u8]) -> u8 {
+        let length = msg.len() + 3;
+        if length < MIN_LENGTH as usize || length > MAX_LENGTH as usize || length > buffer.len() { return 0; }
+        let mut crc: u16 = 0xffff;
+        buffer[0] = length as u8;
+        crc -= length as u16;
+        for (i, b) in msg.iter().enumerate() {
+            buffer[i + 1] = *b;
+            crc -= *b as u16;
+        }
+        buffer[length - 2] = (crc & 0xff) as u8;
+        buffer[length - 1] = (crc >> 8) as u8;
+        length as u8
+    }
+
+
+    /// Encode a message into the start of the buffer, including the length and the CRC.
+    /// Returns the number of bytes written, or 0 if the buffer is too small.
+    pub fn pushIBusMsg(msg: &IBusMsg, buffer: &mut [u8]) -> u8 {
+        match msg {
+            IBusMsg::DiscoveryRequest(addr) =>
+                pushMsg(&[DISCOVER + addr], buffer),
+            IBusMsg::TypeRequest(addr) =>
+                pushMsg(&[TYPE + addr], buffer),
+            IBusMsg::ValueRequest(addr) =>
+                pushMsg(&[VALUE + addr], buffer),
+            IBusMsg::DiscoveryResponse(addr) =>
+                pushMsg(&[DISCOVER + addr], buffer),
+            IBusMsg::TypeResponse(addr, sensortype, length) =>
+                pushMsg(&[TYPE+addr, *sensortype as u8, *length as u8], buffer),
+            IBusMsg::ValueResponseShort(addr, value) =>
+                pushMsg(&[VALUE+addr, (value&0xff) as u8, (value>>8) as u8], buffer),
+            IBusMsg::ValueResponseLong(addr, value) =>
+                pushMsg(&[VALUE+addr, (value&0xff) as u8, ((value>>8) & 0xff) as u8, ((value>>16) & 0xff) as u8, ((value>>24) & 0xff) as u8], buffer),
+            IBusMsg::SetMsg(data) => {
+                let mut msg = [0u8; 2 * 14 + 1];
+                msg[0] = SET;
+                for (i, v) in data.iter().enumerate() {
+                    msg[1 + 2 * i] = (v & 0xff) as u8;
+                    msg[2 + 2 * i] = (v >> 8) as u8;
+                }
+                pushMsg(&msg, buffer)
+            }
+        }
+    }
 }
 
 
 mod deque;
+pub mod rpm;
+pub mod registry;
+mod macros;
+pub mod telemetry;
+pub mod serial;
+pub mod half_duplex;
+pub mod hub;
+pub mod generator;
+pub mod transmitter;
+pub mod stream;
+pub mod rc;
+pub mod sbus;
+pub mod crsf;
+pub mod detect;
+pub mod bridge;
+pub mod pulse;
+pub mod process;
+pub mod calibrate;
+pub mod switch;
+pub mod capture;
+#[cfg(feature = "embedded-hal")]
+pub mod hal;
+#[cfg(feature = "embedded-hal")]
+pub mod servo;
+#[cfg(feature = "async")]
+pub mod asynch;
+#[cfg(any(test, feature = "testkit"))]
+pub mod testkit;
 
 #[cfg(test)]
 mod tests {
@@ -209,6 +382,17 @@ mod tests {
             0x3E8, 0x5DC, 0x5DC, 0x5DC, 0x5DC, 0x5DC, 0x5DC])), 0x20));
     }
 
+    #[test]
+    fn test_longbuffer() {
+        // Buffers of more than 255 bytes must not confuse the length checks
+        let mut data = [0x55u8; 256];
+        data[..4].copy_from_slice(&[0x04, 0x81, 0x7a, 0xff]);
+        let mut buffer = Deque::<512>::new();
+        buffer.load(&data);
+        assert_eq!(popIBusMsg(&buffer), (Some(IBusMsg::DiscoveryRequest(0x1)), 4));
+        assert_eq!(popIBusResponse(&buffer), (Some(IBusMsg::DiscoveryResponse(0x1)), 4));
+    }
+
     #[test]
     fn test_parseshortmsgs() {
         let mut buffer = Buffer::new();
@@ -223,18 +407,238 @@ mod tests {
         buffer.clear();
     }
 
-    // #[test]
-    // fn test_pushshortmsgs() {
-    //     let mut buffer = Buffer::new();
-    //     pushIBusMsg(&IBusMsg::DiscoveryResponse(0x01), &mut buffer);
-    //     assert_eq!(buffer.iter().collect::<Vec<u8>>(), [0x04, 0x81, 0x7a, 0xff]);
-    //     buffer.clear();
-    //
-    //     pushIBusMsg(&IBusMsg::TypeResponse(0x02, IBusSensor::PRESS, IBusSensorLength::Long), &mut buffer);
-    //     assert_eq!(buffer.iter().collect::<Vec<u8>>(), [0x06, 0x92, 0x41, 0x04, 0x22, 0xff]);
-    //     buffer.clear();
-    //
-    //     pushIBusMsg(&IBusMsg::ValueResponseLong(0x03, 0x12345678), &mut buffer);
-    //     assert_eq!(buffer.iter().collect::<Vec<u8>>(), [0x08, 0xa3, 0x78, 0x56, 0x34, 0x12, 0x40, 0xfe]);
-    // }
+    #[test]
+    fn test_pushshortmsgs() {
+        let mut buffer = [0u8; 32];
+        assert_eq!(pushIBusMsg(&IBusMsg::DiscoveryResponse(0x01), &mut buffer), 4);
+        assert_eq!(buffer[..4], [0x04, 0x81, 0x7a, 0xff]);
+
+        assert_eq!(pushIBusMsg(&IBusMsg::TypeResponse(0x02, IBusSensor::PRESS, IBusSensorLength::Long), &mut buffer), 6);
+        assert_eq!(buffer[..6], [0x06, 0x92, 0x41, 0x04, 0x22, 0xff]);
+
+        assert_eq!(pushIBusMsg(&IBusMsg::ValueResponseLong(0x03, 0x12345678), &mut buffer), 8);
+        assert_eq!(buffer[..8], [0x08, 0xa3, 0x78, 0x56, 0x34, 0x12, 0x40, 0xfe]);
+
+        assert_eq!(pushIBusMsg(&IBusMsg::ValueRequest(0x03), &mut buffer), 4);
+        assert_eq!(buffer[..4], [0x04, 0xa3, 0x58, 0xff]);
+
+        // A buffer that is too small is left alone
+        assert_eq!(pushIBusMsg(&IBusMsg::ValueResponseLong(0x03, 0x12345678), &mut buffer[..7]), 0);
+    }
+
+    #[test]
+    fn test_value_response() {
+        assert_eq!(IBusMsg::value_response(1, IBusSensorLength::Short, 65535), IBusMsg::ValueResponseShort(1, 65535));
+        assert_eq!(IBusMsg::value_response(1, IBusSensorLength::Short, 65536), IBusMsg::ValueResponseShort(1, 65535));
+        assert_eq!(IBusMsg::value_response(1, IBusSensorLength::Short, 70000), IBusMsg::ValueResponseShort(1, 65535));
+        assert_eq!(IBusMsg::value_response(2, IBusSensorLength::Long, 70000), IBusMsg::ValueResponseLong(2, 70000));
+    }
+
+    #[test]
+    fn test_parseresponses() {
+        let mut buffer = Buffer::new();
+        buffer.load(&[0x04, 0x81, 0x7a, 0xff]);
+        assert_eq!(popIBusResponse(&buffer), (Some(IBusMsg::DiscoveryResponse(0x01)), 4));
+        buffer.clear();
+        buffer.load(&[0x06, 0x92, 0x41, 0x04, 0x22, 0xff]);
+        assert_eq!(popIBusResponse(&buffer), (Some(IBusMsg::TypeResponse(0x02, IBusSensor::PRESS, IBusSensorLength::Long)), 6));
+        buffer.clear();
+        buffer.load(&[0x08, 0xa3, 0x78, 0x56, 0x34, 0x12, 0x40, 0xfe]);
+        assert_eq!(popIBusResponse(&buffer), (Some(IBusMsg::ValueResponseLong(0x03, 0x12345678)), 8));
+        buffer.clear();
+        let mut data = [0u8; 32];
+        let n = pushIBusMsg(&IBusMsg::ValueResponseShort(0x04, 0x1234), &mut data);
+        buffer.load(&data[..n as usize]);
+        assert_eq!(popIBusResponse(&buffer), (Some(IBusMsg::ValueResponseShort(0x04, 0x1234)), 6));
+        buffer.clear();
+        // An unknown sensor type is skipped as a whole
+        buffer.load(&[0x06, 0x92, 0x42, 0x04, 0x21, 0xff]);
+        assert_eq!(popIBusResponse(&buffer), (None, 6));
+        buffer.clear();
+        // A request is not a valid response and vice versa
+        buffer.load(&[0x04, 0xa3, 0x58, 0xff]);
+        assert_eq!(popIBusResponse(&buffer), (None, 1));
+        buffer.clear();
+        buffer.load(&[0x06, 0x92, 0x41, 0x04, 0x22, 0xff]);
+        assert_eq!(popIBusMsg(&buffer), (None, 1));
+    }
+
+    #[test]
+    fn test_pushsetmsg() {
+        let mut buffer = [0u8; 32];
+        assert_eq!(pushIBusMsg(&IBusMsg::SetMsg([
+            0x5DB, 0x5Dc, 0x554, 0x5DC, 0x3E8, 0x7D0, 0x5D2,
+            0x3E8, 0x5DC, 0x5DC, 0x5DC, 0x5DC, 0x5DC, 0x5DC]), &mut buffer), 0x20);
+        assert_eq!(buffer, [0x20, 0x40, 0xDB, 0x05, 0xDC, 0x05, 0x54, 0x05,
+                            0xDC, 0x05, 0xE8, 0x03, 0xD0, 0x07, 0xD2, 0x05,
+                            0xE8, 0x03, 0xDC, 0x05, 0xDC, 0x05, 0xDC, 0x05,
+                            0xDC, 0x05, 0xDC, 0x05, 0xDC, 0x05, 0xDA, 0xF3]);
+    }
+
+    #[test]
+    fn test_18channels() {
+        let mut values = [0u16; MAX_CHANNELS];
+        for (i, v) in values.iter_mut().enumerate() {
+            *v = 1000 + 50 * i as u16;
+        }
+        values[17] = 0xabc;
+        let data = packChannels(&values);
+        assert_eq!(data[0], 0x4000 | 1000);
+        assert_eq!(data[9] >> 12, 0xc);
+        assert_eq!(data[10] >> 12, 0xb);
+        assert_eq!(data[11] >> 12, 0xa);
+        assert_eq!(data[12], 1600);
+        assert_eq!(unpackChannels(&data), values);
+        // Plain 14 channel frames have nothing in the high nibbles
+        let plain = unpackChannels(&[1500; 14]);
+        assert_eq!(plain[..14], [1500; 14]);
+        assert_eq!(plain[14..], [0; 4]);
+    }
+
+    #[test]
+    fn test_short_buffers() {
+        // Neither an empty buffer nor a lone length byte may be read past its end
+        let mut buffer = Buffer::new();
+        assert_eq!(popIBusMsg(&buffer), (None, 0));
+        assert_eq!(popIBusResponse(&buffer), (None, 0));
+        for first in 0..=255u8 {
+            buffer.clear();
+            buffer.push(first);
+            let skip = if (0x04..=0x20).contains(&first) { 0 } else { 1 };
+            assert_eq!(popIBusMsg(&buffer), (None, skip));
+            assert_eq!(popIBusResponse(&buffer), (None, skip));
+        }
+    }
+
+
+    use alloc::vec::Vec;
+    use proptest::prelude::*;
+    use crate::stream::StreamDecoder;
+    use crate::testkit::{decode_msgs, decode_responses, encode};
+
+    fn request() -> impl Strategy<Value=IBusMsg> {
+        prop_oneof![
+            (0u8..16).prop_map(IBusMsg::DiscoveryRequest),
+            (0u8..16).prop_map(IBusMsg::TypeRequest),
+            (0u8..16).prop_map(IBusMsg::ValueRequest),
+            any::<[u16; 14]>().prop_map(IBusMsg::SetMsg),
+        ]
+    }
+
+    fn response() -> impl Strategy<Value=IBusMsg> {
+        let sensor = prop::sample::select(alloc::vec![IBusSensor::INTV, IBusSensor::TEMP, IBusSensor::RPM,
+                                                      IBusSensor::EXTV, IBusSensor::PRESS, IBusSensor::SERVO]);
+        let length = prop::sample::select(alloc::vec![IBusSensorLength::Short, IBusSensorLength::Long]);
+        prop_oneof![
+            (0u8..16).prop_map(IBusMsg::DiscoveryResponse),
+            (0u8..16, sensor, length).prop_map(|(addr, s, l)| IBusMsg::TypeResponse(addr, s, l)),
+            (0u8..16, any::<u16>()).prop_map(|(addr, v)| IBusMsg::ValueResponseShort(addr, v)),
+            (0u8..16, any::<u32>()).prop_map(|(addr, v)| IBusMsg::ValueResponseLong(addr, v)),
+        ]
+    }
+
+    /// Bytes that can not be taken for the start of a message.
+    fn noise() -> impl Strategy<Value=Vec<u8>> {
+        prop::collection::vec(prop_oneof![0u8..0x04, 0x21u8..=0xff], 0..40)
+    }
+
+    /// Arbitrary bytes, or the start of another message as after a dropped byte.
+    fn garbage() -> impl Strategy<Value=Vec<u8>> {
+        prop_oneof![
+            prop::collection::vec(any::<u8>(), 0..40),
+            (request(), 1usize..32).prop_map(|(msg, n)| {
+                let bytes = encode(&msg);
+                bytes[..n.min(bytes.len() - 1)].to_vec()
+            }),
+        ]
+    }
+
+    /// The messages in `bytes`, with the span of bytes each was decoded from.
+    fn decode_spans(bytes: &[u8]) -> Vec<(usize, usize, IBusMsg)> {
+        let mut found = Vec::new();
+        let mut start = 0;
+        let mut buffer = Buffer::new();
+        while start < bytes.len() {
+            buffer.clear();
+            buffer.load(&bytes[start..bytes.len().min(start + MAX_LENGTH as usize)]);
+            let (msg, step) = popIBusMsg(&buffer);
+            if step == 0 {
+                break;
+            }
+            if let Some(msg) = msg {
+                found.push((start, start + step as usize, msg));
+            }
+            start += step as usize;
+        }
+        found
+    }
+
+    proptest! {
+        #[test]
+        fn prop_roundtrip_requests(msg in request()) {
+            prop_assert_eq!(decode_msgs(&encode(&msg)), [msg]);
+        }
+
+        #[test]
+        fn prop_roundtrip_responses(msg in response()) {
+            prop_assert_eq!(decode_responses(&encode(&msg)), [msg]);
+        }
+
+        #[test]
+        fn prop_recover_from_noise(parts in prop::collection::vec((noise(), request()), 0..8), tail in noise()) {
+            let mut bytes = Vec::new();
+            for (noise, msg) in &parts {
+                bytes.extend_from_slice(noise);
+                bytes.extend(encode(msg));
+            }
+            bytes.extend(tail);
+            let msgs: Vec<IBusMsg> = parts.iter().map(|(_, msg)| *msg).collect();
+            prop_assert_eq!(decode_msgs(&bytes), msgs);
+        }
+
+        #[test]
+        fn prop_recover_from_garbage(parts in prop::collection::vec((garbage(), request()), 0..8)) {
+            let mut bytes = Vec::new();
+            let mut sent = Vec::new();
+            for (garbage, msg) in &parts {
+                bytes.extend_from_slice(garbage);
+                let start = bytes.len();
+                bytes.extend(encode(msg));
+                sent.push((start, bytes.len(), *msg));
+            }
+            // A stray length byte near the end waits for bytes that would follow in a live stream
+            bytes.extend([0u8; MAX_LENGTH as usize]);
+            let decoded = decode_spans(&bytes);
+            // A message can only be lost to garbage that happens to pass the CRC check together
+            // with its first bytes; that shows up as a message that was never sent, over its bytes.
+            for &(start, end, msg) in &sent {
+                let found = decoded.contains(&(start, end, msg));
+                let overlapped = decoded.iter().any(|&(s, e, m)| !sent.contains(&(s, e, m)) && s < end && start < e);
+                prop_assert!(found || overlapped, "sent {:?}, decoded {:?}", sent, decoded);
+            }
+        }
+
+        #[test]
+        fn prop_no_panic(bytes in prop::collection::vec(any::<u8>(), 0..300)) {
+            // The ring buffer of the stream decoder must find the same messages as the slices
+            let mut requests = StreamDecoder::<64>::new();
+            let mut responses = StreamDecoder::<64>::for_responses();
+            let (mut from_requests, mut from_responses) = (Vec::new(), Vec::new());
+            for &byte in &bytes {
+                requests.feed(byte);
+                responses.feed(byte);
+                from_requests.extend(core::iter::from_fn(|| requests.pop()));
+                from_responses.extend(core::iter::from_fn(|| responses.pop()));
+            }
+            prop_assert_eq!(from_requests, decode_msgs(&bytes));
+            prop_assert_eq!(from_responses, decode_responses(&bytes));
+            // Each buffer length from 0 to the full message, on the ring buffer
+            let mut buffer = Buffer::new();
+            for &byte in bytes.iter().take(63) {
+                popIBusMsg(&buffer);
+                popIBusResponse(&buffer);
+                buffer.push(byte);
+            }
+        }
+    }
 }
@@ -0,0 +1,147 @@
+
+/// Define a fixed table of IBus sensors as a zero-sized responder type.
+///
+/// Each entry is `name: SENSOR => provider`, where `SENSOR` is an `IBusSensor` variant and
+/// `provider` is called as `provider()` to get the current value as a `u32`. The value length
+/// follows from the sensor type, unless it is given explicitly as `name: SENSOR[Long] => provider`.
+/// Sensors get addresses from 1 in the order listed, available as associated constants.
+/// The table is named `IBusSensors` unless it starts with e.g. `pub struct MySensors;`.
+///
+/// ```
+/// use rustibus::ibus_sensors;
+/// use rustibus::RustIBus::{IBusMsg, IBusSensor, IBusSensorLength};
+///
+/// fn read_temp() -> u32 { 400 + 255 }
+/// fn read_volt() -> u32 { 1200 }
+///
+/// ibus_sensors! {
+///     temp: TEMP => read_temp,
+///     volt: EXTV => read_volt,
+/// }
+///
+/// assert_eq!(IBusSensors::volt, 2);
+/// assert_eq!(IBusSensors::respond(&IBusMsg::TypeRequest(1)),
+///            Some(IBusMsg::TypeResponse(1, IBusSensor::TEMP, IBusSensorLength::Short)));
+/// assert_eq!(IBusSensors::respond(&IBusMsg::ValueRequest(2)),
+///            Some(IBusMsg::ValueResponseShort(2, 1200)));
+/// ```
+///
+/// A table with more sensors than there are addresses is rejected at compile time:
+///
+/// ```compile_fail
+/// use rustibus::ibus_sensors;
+/// fn zero() -> u32 { 0 }
+/// ibus_sensors! {
+///     s1: TEMP => zero, s2: TEMP => zero, s3: TEMP => zero, s4: TEMP => zero,
+///     s5: TEMP => zero, s6: TEMP => zero, s7: TEMP => zero, s8: TEMP => zero,
+///     s9: TEMP => zero, s10: TEMP => zero, s11: TEMP => zero, s12: TEMP => zero,
+///     s13: TEMP => zero, s14: TEMP => zero, s15: TEMP => zero, s16: TEMP => zero,
+/// }
+/// ```
+#[macro_export]
+macro_rules! ibus_sensors {
+    (@addr $table:ident; $n:expr; ) => {};
+    (@addr $table:ident; $n:expr; $first:ident $($rest:ident)*) => {
+        #[allow(non_upper_case_globals)]
+        impl $table {
+            pub const $first: u8 = $n;
+        }
+        $crate::ibus_sensors!(@addr $table; $n + 1; $($rest)*);
+    };
+
+    (@len $sensor:ident) => {
+        $crate::RustIBus::IBusSensor::$sensor.default_length()
+    };
+    (@len $sensor:ident $len:ident) => {
+        $crate::RustIBus::IBusSensorLength::$len
+    };
+
+    ($vis:vis struct $table:ident;
+     $($name:ident : $sensor:ident $([$len:ident])? => $read:expr),+ $(,)?) => {
+        #[derive(Debug, Clone, Copy)]
+        $vis struct $table;
+
+        $crate::ibus_sensors!(@addr $table; 1u8; $($name)+);
+
+        impl $table {
+            /// The number of sensors in the table.
+            pub const COUNT: usize = [$(stringify!($name)),+].len();
+
+            /// Answer a request for one of the sensors in the table.
+            /// Returns `None` if the message is not a request for one of our addresses.
+            pub fn respond(msg: &$crate::RustIBus::IBusMsg) -> Option<$crate::RustIBus::IBusMsg> {
+                use $crate::RustIBus::IBusMsg;
+                match *msg {
+                    IBusMsg::DiscoveryRequest(addr) if addr >= 1 && addr as usize <= Self::COUNT =>
+                        Some(IBusMsg::DiscoveryResponse(addr)),
+                    IBusMsg::TypeRequest(addr) => {
+                        $(if addr == Self::$name {
+                            return Some(IBusMsg::TypeResponse(addr, $crate::RustIBus::IBusSensor::$sensor,
+                                                              $crate::ibus_sensors!(@len $sensor $($len)?)));
+                        })+
+                        None
+                    },
+                    IBusMsg::ValueRequest(addr) => {
+                        $(if addr == Self::$name {
+                            let value: u32 = ($read)();
+                            return Some(IBusMsg::value_response(addr, $crate::ibus_sensors!(@len $sensor $($len)?), value));
+                        })+
+                        None
+                    },
+                    _ => None
+                }
+            }
+        }
+
+        impl $crate::registry::SensorResponder for $table {
+            fn respond(&mut self, msg: &$crate::RustIBus::IBusMsg) -> Option<$crate::RustIBus::IBusMsg> {
+                $table::respond(msg)
+            }
+        }
+
+        const _: () = assert!($table::COUNT <= $crate::registry::MAX_ADDRESS as usize,
+                              "An IBus sensor table can hold at most 15 sensors");
+    };
+
+    ($($name:ident : $sensor:ident $([$len:ident])? => $read:expr),+ $(,)?) => {
+        $crate::ibus_sensors!{ pub struct IBusSensors; $($name : $sensor $([$len])? => $read),+ }
+    };
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::RustIBus::{IBusMsg, IBusSensor, IBusSensorLength};
+    use core::sync::atomic::{AtomicU32, Ordering::Relaxed};
+
+    static PRESSURE: AtomicU32 = AtomicU32::new(101_325);
+
+    fn read_temp() -> u32 { 650 }
+
+    ibus_sensors! {
+        struct Sensors;
+        temp: TEMP => read_temp,
+        press: PRESS => || PRESSURE.load(Relaxed),
+        rpm: RPM[Long] => || 70_000,
+    }
+
+    #[test]
+    fn test_table() {
+        assert_eq!(Sensors::COUNT, 3);
+        assert_eq!((Sensors::temp, Sensors::press, Sensors::rpm), (1, 2, 3));
+
+        assert_eq!(Sensors::respond(&IBusMsg::DiscoveryRequest(0)), None);
+        assert_eq!(Sensors::respond(&IBusMsg::DiscoveryRequest(3)), Some(IBusMsg::DiscoveryResponse(3)));
+        assert_eq!(Sensors::respond(&IBusMsg::DiscoveryRequest(4)), None);
+        assert_eq!(Sensors::respond(&IBusMsg::TypeRequest(2)),
+                   Some(IBusMsg::TypeResponse(2, IBusSensor::PRESS, IBusSensorLength::Long)));
+        assert_eq!(Sensors::respond(&IBusMsg::TypeRequest(3)),
+                   Some(IBusMsg::TypeResponse(3, IBusSensor::RPM, IBusSensorLength::Long)));
+        assert_eq!(Sensors::respond(&IBusMsg::TypeRequest(4)), None);
+        assert_eq!(Sensors::respond(&IBusMsg::ValueRequest(1)), Some(IBusMsg::ValueResponseShort(1, 650)));
+        PRESSURE.store(99_000, Relaxed);
+        assert_eq!(Sensors::respond(&IBusMsg::ValueRequest(2)), Some(IBusMsg::ValueResponseLong(2, 99_000)));
+        assert_eq!(Sensors::respond(&IBusMsg::ValueRequest(3)), Some(IBusMsg::ValueResponseLong(3, 70_000)));
+        assert_eq!(Sensors::respond(&IBusMsg::SetMsg([1500; 14])), None);
+    }
+}
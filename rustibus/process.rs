@@ -0,0 +1,206 @@
+//! Turns raw channel values into normalised stick positions.
+//!
+//! All processing is done in integers, so it is cheap on microcontrollers without an FPU too;
+//! the `f32` results are the fixed point results scaled to -1.0 - 1.0.
+
+use crate::RustIBus::IBusMsg;
+use crate::rc::ChannelFrame;
+
+
+/// Full deflection in the fixed point output: -`FULL_SCALE` - `FULL_SCALE`.
+pub const FULL_SCALE: i16 = i16::MAX;
+
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct ChannelConfig {
+    /// The calibrated end points and center of the channel, in microseconds.
+    pub min_us: u16,
+    pub center_us: u16,
+    pub max_us: u16,
+    pub reverse: bool,
+    /// Values within this distance of the center read as exactly 0, in microseconds.
+    /// The output still reaches full scale at the end points.
+    pub deadband_us: u16,
+    /// How much softer the curve is around the center, in percent: 0 is linear,
+    /// 100 fully cubic.
+    pub expo: u8,
+    /// The output at full deflection, in percent, with the high and low rate of a dual rate switch.
+    pub rate: u8,
+    pub low_rate: u8,
+}
+
+impl ChannelConfig {
+    /// The 1000 - 2000 us range, linear and without deadband.
+    pub const fn new() -> Self {
+        ChannelConfig {
+            min_us: 1000, center_us: 1500, max_us: 2000,
+            reverse: false, deadband_us: 0, expo: 0, rate: 100, low_rate: 100,
+        }
+    }
+
+    /// The position of `value_us`, scaled to `FULL_SCALE`.
+    pub fn apply(&self, value_us: u16, low_rate: bool) -> i16 {
+        let offset = value_us as i32 - self.center_us as i32;
+        let span = if offset >= 0 { self.max_us as i32 - self.center_us as i32 }
+                   else { self.center_us as i32 - self.min_us as i32 };
+        let deadband = self.deadband_us as i32;
+        let distance = offset.abs() - deadband;
+        if distance <= 0 || span <= deadband {
+            return 0;
+        }
+        let full = FULL_SCALE as i32;
+        let mut x = (distance * full / (span - deadband)).min(full);
+
+        let expo = self.expo.min(100) as i32;
+        if expo > 0 {
+            let cubed = (x as i64 * x as i64 / full as i64 * x as i64 / full as i64) as i32;
+            x = ((100 - expo) * x + expo * cubed) / 100;
+        }
+        let rate = if low_rate { self.low_rate } else { self.rate }.min(100) as i32;
+        x = x * rate / 100;
+
+        if (offset < 0) != self.reverse { -x as i16 } else { x as i16 }
+    }
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self { Self::new() }
+}
+
+
+/// Normalises the channels of a receiver, each with its own `ChannelConfig`.
+pub struct ChannelProcessor<const N: usize = 14> {
+    configs: [ChannelConfig; N],
+    low_rates: bool,
+}
+
+impl<const N: usize> ChannelProcessor<N> {
+    pub const fn new() -> Self {
+        ChannelProcessor { configs: [ChannelConfig::new(); N], low_rates: false }
+    }
+
+    pub const fn with_configs(configs: [ChannelConfig; N]) -> Self {
+        ChannelProcessor { configs, low_rates: false }
+    }
+
+    pub fn config(&mut self, channel: usize) -> Option<&mut ChannelConfig> { self.configs.get_mut(channel) }
+
+    pub fn configs(&self) -> &[ChannelConfig; N] { &self.configs }
+
+    /// Switch all channels between their high and low rates.
+    pub fn set_low_rates(&mut self, low: bool) { self.low_rates = low; }
+
+    /// The position of a channel as a fixed point value; 0 for channels beyond `N`.
+    pub fn process_fixed(&self, channel: usize, value_us: u16) -> i16 {
+        self.configs.get(channel).map_or(0, |config| config.apply(value_us, self.low_rates))
+    }
+
+    /// The position of a channel, from -1.0 to 1.0.
+    pub fn process(&self, channel: usize, value_us: u16) -> f32 {
+        self.process_fixed(channel, value_us) as f32 / FULL_SCALE as f32
+    }
+
+    /// The positions of all channels in a frame; missing channels read 0.
+    pub fn process_frame_fixed(&self, values: &[u16]) -> [i16; N] {
+        let mut positions = [0i16; N];
+        for (i, (position, value)) in positions.iter_mut().zip(values).enumerate() {
+            *position = self.process_fixed(i, *value);
+        }
+        positions
+    }
+
+    pub fn process_frame(&self, values: &[u16]) -> [f32; N] {
+        self.process_frame_fixed(values).map(|x| x as f32 / FULL_SCALE as f32)
+    }
+
+    /// The positions of the channels in a set message, or `None` for other messages.
+    /// Frames with 18 channels are unpacked first.
+    pub fn process_msg(&self, msg: &IBusMsg) -> Option<[f32; N]> {
+        match msg {
+            IBusMsg::SetMsg(data) => Some(self.process_frame(ChannelFrame::from_set_msg(data).channels())),
+            _ => None
+        }
+    }
+}
+
+impl<const N: usize> Default for ChannelProcessor<N> {
+    fn default() -> Self { Self::new() }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RustIBus::{packChannels, MAX_CHANNELS};
+
+    #[test]
+    fn test_linear() {
+        let config = ChannelConfig::new();
+        assert_eq!(config.apply(1500, false), 0);
+        assert_eq!(config.apply(2000, false), FULL_SCALE);
+        assert_eq!(config.apply(1000, false), -FULL_SCALE);
+        assert_eq!(config.apply(1750, false), FULL_SCALE / 2);
+        assert_eq!(config.apply(2200, false), FULL_SCALE);
+        assert_eq!(config.apply(0, false), -FULL_SCALE);
+
+        // An off-center stick with uneven end points
+        let config = ChannelConfig { min_us: 1100, center_us: 1520, max_us: 1940, reverse: true, ..ChannelConfig::new() };
+        assert_eq!(config.apply(1520, false), 0);
+        assert_eq!(config.apply(1100, false), FULL_SCALE);
+        assert_eq!(config.apply(1940, false), -FULL_SCALE);
+    }
+
+    #[test]
+    fn test_deadband() {
+        let config = ChannelConfig { deadband_us: 20, ..ChannelConfig::new() };
+        assert_eq!(config.apply(1520, false), 0);
+        assert_eq!(config.apply(1480, false), 0);
+        assert_eq!(config.apply(1521, false), FULL_SCALE / 480);
+        assert_eq!(config.apply(2000, false), FULL_SCALE);
+        assert_eq!(config.apply(1240, false), -FULL_SCALE / 2);
+    }
+
+    #[test]
+    fn test_curves() {
+        let config = ChannelConfig { expo: 100, rate: 80, low_rate: 50, ..ChannelConfig::new() };
+        // Fully cubic: half stick gives an eighth
+        assert_eq!(config.apply(1750, false), (FULL_SCALE / 8) * 8 / 10);
+        assert_eq!(config.apply(2000, false), (FULL_SCALE as i32 * 8 / 10) as i16);
+        assert_eq!(config.apply(1000, true), -FULL_SCALE / 2);
+
+        let config = ChannelConfig { expo: 50, ..ChannelConfig::new() };
+        let half = config.apply(1750, false) as i32;
+        assert_eq!(half, (FULL_SCALE as i32 / 2 + FULL_SCALE as i32 / 8) / 2);
+    }
+
+    #[test]
+    fn test_processor() {
+        let mut processor = ChannelProcessor::<4>::new();
+        processor.config(1).unwrap().reverse = true;
+        processor.config(3).unwrap().low_rate = 50;
+        assert!(processor.config(4).is_none());
+
+        let mut data = [1500u16; 14];
+        data[0] = 2000;
+        data[1] = 2000;
+        data[3] = 1000;
+        assert_eq!(processor.process_msg(&IBusMsg::SetMsg(data)), Some([1.0, -1.0, 0.0, -1.0]));
+        processor.set_low_rates(true);
+        assert_eq!(processor.process_frame_fixed(&data), [FULL_SCALE, -FULL_SCALE, 0, -FULL_SCALE / 2]);
+        assert_eq!(processor.process_frame(&[2000]), [1.0, 0.0, 0.0, 0.0]);
+        assert_eq!(processor.process(9, 2000), 0.0);
+        assert_eq!(processor.process_msg(&IBusMsg::DiscoveryRequest(1)), None);
+    }
+
+    #[test]
+    fn test_18channels() {
+        let processor = ChannelProcessor::<MAX_CHANNELS>::new();
+        let mut values = [1500u16; MAX_CHANNELS];
+        values[4] = 1000;
+        values[17] = 2000;
+        let positions = processor.process_msg(&IBusMsg::SetMsg(packChannels(&values))).unwrap();
+        assert_eq!(positions[4], -1.0);
+        assert_eq!(positions[0], 0.0);
+        assert_eq!(positions[17], 1.0);
+    }
+}
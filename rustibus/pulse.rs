@@ -0,0 +1,222 @@
+//! Pulse timings for driving servos and PPM inputs from received channel values.
+//!
+//! Nothing here touches hardware: the timings come out as tables, ready to load into a timer.
+
+use crate::RustIBus::{IBusMsg, MAX_CHANNELS};
+use crate::rc::{ChannelFrame, CENTER_US};
+
+
+/// The channel values a PPM frame is limited to, in microseconds.
+pub const PPM_MIN_US: u16 = 500;
+pub const PPM_MAX_US: u16 = 2500;
+
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Polarity {
+    /// The output idles low and pulses are high.
+    Normal,
+    /// The output idles high and pulses are low.
+    Inverted,
+}
+
+impl Polarity {
+    /// The output level during a pulse.
+    pub const fn active(&self) -> bool { matches!(self, Polarity::Normal) }
+}
+
+
+/// One step of a pulse train: set the output to `level` and hold it for `duration_us`.
+#[derive(PartialEq, Debug, Default, Clone, Copy)]
+pub struct Segment {
+    pub level: bool,
+    pub duration_us: u32,
+}
+
+
+/// The shape of a PPM frame.
+///
+/// Each channel starts with a pulse of `pulse_us`, and lasts until the pulse of the next
+/// channel: the channel value is the time between the starts of two pulses. After the last
+/// channel, a final pulse is followed by the sync gap that fills up the frame.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct PpmConfig {
+    /// The number of channels in a frame, at most `MAX_CHANNELS`.
+    pub channels: usize,
+    pub frame_us: u32,
+    pub pulse_us: u32,
+    /// The shortest sync gap. When the channels take too long to leave this gap within
+    /// `frame_us`, the frame is stretched.
+    pub min_sync_us: u32,
+    pub polarity: Polarity,
+}
+
+impl Default for PpmConfig {
+    /// The common 8 channel frame of 22.5 ms with 300 us pulses.
+    fn default() -> Self {
+        PpmConfig { channels: 8, frame_us: 22_500, pulse_us: 300, min_sync_us: 4_000, polarity: Polarity::Normal }
+    }
+}
+
+impl PpmConfig {
+    /// The timings of a frame with the given channel values in microseconds.
+    /// Missing channels are centered, and values are limited to `PPM_MIN_US` - `PPM_MAX_US`.
+    pub fn timings(&self, channels: &[u16]) -> PpmTimings {
+        let count = self.channels.min(MAX_CHANNELS);
+        let active = self.polarity.active();
+        let mut timings = PpmTimings { segments: [Segment::default(); MAX_SEGMENTS], len: 0 };
+        let mut elapsed = 0u32;
+        for i in 0..count {
+            let value = channels.get(i).copied().unwrap_or(CENTER_US).clamp(PPM_MIN_US, PPM_MAX_US) as u32;
+            let pulse = self.pulse_us.min(value);
+            timings.push(active, pulse);
+            timings.push(!active, value - pulse);
+            elapsed += value;
+        }
+        timings.push(active, self.pulse_us);
+        elapsed += self.pulse_us;
+        timings.push(!active, self.frame_us.saturating_sub(elapsed).max(self.min_sync_us));
+        timings
+    }
+
+    /// The timings for the channels of a set message, or `None` for other messages.
+    /// Frames with 18 channels are unpacked first.
+    pub fn timings_for_msg(&self, msg: &IBusMsg) -> Option<PpmTimings> {
+        match msg {
+            IBusMsg::SetMsg(data) => Some(self.timings(ChannelFrame::from_set_msg(data).channels())),
+            _ => None
+        }
+    }
+}
+
+
+const MAX_SEGMENTS: usize = 2 * (MAX_CHANNELS + 1);
+
+/// The segments of one PPM frame, starting with the pulse of the first channel.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct PpmTimings {
+    segments: [Segment; MAX_SEGMENTS],
+    len: usize,
+}
+
+impl PpmTimings {
+    fn push(&mut self, level: bool, duration_us: u32) {
+        self.segments[self.len] = Segment { level, duration_us };
+        self.len += 1;
+    }
+
+    pub fn segments(&self) -> &[Segment] { &self.segments[..self.len] }
+
+    /// The length of the whole frame, sync gap included.
+    pub fn frame_us(&self) -> u32 { self.segments().iter().map(|s| s.duration_us).sum() }
+}
+
+
+/// Converts channel values to duty values of a PWM timer running one servo per output.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct ServoTiming {
+    /// The PWM period, 20000 us (50 Hz) for most analog servos.
+    pub period_us: u32,
+    /// The duty value for an output that is active all of the period, i.e. the timer resolution.
+    pub max_duty: u32,
+    pub polarity: Polarity,
+}
+
+impl ServoTiming {
+    pub const fn new(period_us: u32, max_duty: u32) -> Self {
+        ServoTiming { period_us, max_duty, polarity: Polarity::Normal }
+    }
+
+    pub const fn with_polarity(mut self, polarity: Polarity) -> Self {
+        self.polarity = polarity;
+        self
+    }
+
+    /// The duty value for a pulse of `pulse_us`, rounded to the nearest step.
+    pub fn duty(&self, pulse_us: u16) -> u32 {
+        if self.period_us == 0 {
+            return 0;
+        }
+        let pulse = (pulse_us as u64).min(self.period_us as u64);
+        let duty = ((pulse * self.max_duty as u64 + self.period_us as u64 / 2) / self.period_us as u64) as u32;
+        match self.polarity {
+            Polarity::Normal => duty,
+            Polarity::Inverted => self.max_duty - duty,
+        }
+    }
+
+    /// The duty values for all channels, e.g. the 14 of a set message.
+    pub fn duties<const N: usize>(&self, channels: &[u16; N]) -> [u32; N] {
+        let mut duties = [0u32; N];
+        for (duty, channel) in duties.iter_mut().zip(channels.iter()) {
+            *duty = self.duty(*channel);
+        }
+        duties
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(level: bool, duration_us: u32) -> Segment { Segment { level, duration_us } }
+
+    #[test]
+    fn test_ppm() {
+        let config = PpmConfig { channels: 4, frame_us: 20_000, ..PpmConfig::default() };
+        let timings = config.timings(&[1000, 2000, 1500]);
+        assert_eq!(timings.segments(), [
+            segment(true, 300), segment(false, 700),
+            segment(true, 300), segment(false, 1700),
+            segment(true, 300), segment(false, 1200),
+            segment(true, 300), segment(false, 1200),
+            segment(true, 300), segment(false, 20_000 - 6_300),
+        ]);
+        assert_eq!(timings.frame_us(), 20_000);
+    }
+
+    #[test]
+    fn test_ppm_stretch() {
+        // Eight channels at full throw do not fit in 18 ms with a 4 ms sync gap
+        let config = PpmConfig { frame_us: 18_000, polarity: Polarity::Inverted, ..PpmConfig::default() };
+        let timings = config.timings_for_msg(&IBusMsg::SetMsg([3000; 14])).unwrap();
+        let segments = timings.segments();
+        assert_eq!(segments.len(), 18);
+        assert_eq!(segments[0], segment(false, 300));
+        assert_eq!(segments[1], segment(true, 2200));
+        assert_eq!(segments[17], segment(true, 4_000));
+        assert_eq!(timings.frame_us(), 8 * 2500 + 300 + 4_000);
+        assert_eq!(config.timings_for_msg(&IBusMsg::DiscoveryRequest(1)), None);
+    }
+
+    #[test]
+    fn test_ppm_18channels() {
+        let mut values = [1500u16; MAX_CHANNELS];
+        values[4] = 1000;
+        values[17] = 2000;
+        let msg = IBusMsg::SetMsg(crate::RustIBus::packChannels(&values));
+        let config = PpmConfig { channels: 18, frame_us: 40_000, ..PpmConfig::default() };
+        let timings = config.timings_for_msg(&msg).unwrap();
+        let segments = timings.segments();
+        // The packed word of channel 5 also carries 4 bits of channel 16
+        assert_eq!(segments[2 * 4 + 1], segment(false, 700));
+        assert_eq!(segments[2 * 17 + 1], segment(false, 1700));
+        assert_eq!(timings.frame_us(), 40_000);
+    }
+
+    #[test]
+    fn test_servo() {
+        // 50 Hz on a 16 bit timer
+        let timing = ServoTiming::new(20_000, 0xffff);
+        assert_eq!(timing.duty(1000), 3277);
+        assert_eq!(timing.duty(1500), 4915);
+        assert_eq!(timing.duty(2000), 6554);
+        assert_eq!(timing.duty(u16::MAX), 0xffff);
+
+        // 1 us per count
+        let timing = ServoTiming::new(20_000, 20_000);
+        assert_eq!(timing.duties(&[1000, 1500, 2000]), [1000, 1500, 2000]);
+        let timing = timing.with_polarity(Polarity::Inverted);
+        assert_eq!(timing.duties(&[1000, 1500, 2000]), [19_000, 18_500, 18_000]);
+    }
+}
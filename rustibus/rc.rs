@@ -0,0 +1,60 @@
+use crate::RustIBus::{unpackChannels, MAX_CHANNELS};
+
+
+/// The value a channel rests at: the center of the 1000 - 2000 us servo range.
+pub const CENTER_US: u16 = 1500;
+
+
+/// One set of channel values, whatever the protocol it came in with.
+///
+/// Values are servo pulse widths in microseconds: 1000 - 2000 over the normal stick range,
+/// 1500 in the center. Protocols with a wider range may go somewhat beyond.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct ChannelFrame {
+    channels: [u16; MAX_CHANNELS],
+    count: usize,
+    /// The receiver lost the transmitter and is sending its failsafe values.
+    pub failsafe: bool,
+    /// The receiver missed the last frame from the transmitter and repeats the one before.
+    pub frame_lost: bool,
+}
+
+impl ChannelFrame {
+    /// A frame with the values in `channels`; more than `MAX_CHANNELS` values are ignored.
+    pub fn new(channels: &[u16]) -> Self {
+        let count = channels.len().min(MAX_CHANNELS);
+        let mut values = [CENTER_US; MAX_CHANNELS];
+        values[..count].copy_from_slice(&channels[..count]);
+        ChannelFrame { channels: values, count, failsafe: false, frame_lost: false }
+    }
+
+    /// The channels of an IBus set message. Frames using the 18 channel extension are unpacked
+    /// to 18 channels; other frames, whose values never reach the top 4 bits, have 14.
+    pub fn from_set_msg(data: &[u16; 14]) -> Self {
+        let values = unpackChannels(data);
+        let count = if values[14..].iter().any(|v| *v != 0) { MAX_CHANNELS } else { 14 };
+        ChannelFrame::new(&values[..count])
+    }
+
+    pub fn channels(&self) -> &[u16] { &self.channels[..self.count] }
+
+    /// The value of channel `index`, counting from 0.
+    pub fn channel(&self, index: usize) -> Option<u16> { self.channels().get(index).copied() }
+
+    pub fn len(&self) -> usize { self.count }
+    pub fn is_empty(&self) -> bool { self.count == 0 }
+}
+
+
+/// A decoder for the byte stream of an RC receiver.
+///
+/// All protocols deliver the same `ChannelFrame`s, so application code does not need to
+/// change when it moves to a receiver with a different protocol.
+pub trait RcInput {
+    /// Handle one received byte.
+    fn feed(&mut self, byte: u8);
+
+    /// The next channel frame in the bytes fed so far, if any. Other messages the protocol
+    /// may carry are skipped.
+    fn pop_frame(&mut self) -> Option<ChannelFrame>;
+}
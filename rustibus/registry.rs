@@ -0,0 +1,183 @@
+
+use crate::RustIBus::{IBusMsg, IBusSensor, IBusSensorLength};
+use crate::rpm::RpmSensor;
+
+
+/// The highest address a sensor can use; address 0 is the receiver itself.
+pub const MAX_ADDRESS: u8 = 0x0f;
+
+
+/// Anything that can provide the current value of a sensor.
+pub trait SensorSource {
+    fn read(&mut self) -> u32;
+}
+
+impl<F: FnMut() -> u32> SensorSource for F {
+    fn read(&mut self) -> u32 { self() }
+}
+
+impl<const WINDOW: usize> SensorSource for RpmSensor<WINDOW> {
+    fn read(&mut self) -> u32 { self.value() as u32 }
+}
+
+
+/// Anything that answers the sensor requests of a receiver.
+pub trait SensorResponder {
+    /// Returns the response to `msg`, or `None` if it is not a request for one of our addresses.
+    fn respond(&mut self, msg: &IBusMsg) -> Option<IBusMsg>;
+
+    /// Called on every poll of the bus. A responder that could not answer a request right away,
+    /// e.g. because it forwarded it to another bus, returns the answer here once it has it.
+    fn poll(&mut self, _now_us: u32) -> Option<IBusMsg> { None }
+}
+
+
+/// A responder without any sensors, for devices that only need to ignore requests.
+#[derive(PartialEq, Debug, Default, Clone, Copy)]
+pub struct NoSensors;
+
+impl SensorResponder for NoSensors {
+    fn respond(&mut self, _msg: &IBusMsg) -> Option<IBusMsg> { None }
+}
+
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum RegistryError {
+    /// All slots in the registry, or all 15 sensor addresses, are in use.
+    Full,
+}
+
+
+struct Entry<'a> {
+    sensor: IBusSensor,
+    length: IBusSensorLength,
+    source: &'a mut dyn SensorSource,
+}
+
+
+/// A set of up to `N` sensors that answers discovery, type and value requests by itself.
+/// Sensors get consecutive addresses starting at 1, in the order they are registered.
+pub struct SensorRegistry<'a, const N: usize> {
+    entries: [Option<Entry<'a>>; N],
+    count: usize,
+}
+
+impl<'a, const N: usize> SensorRegistry<'a, N> {
+    pub fn new() -> Self {
+        Self { entries: core::array::from_fn(|_| None), count: 0 }
+    }
+
+    /// Register a sensor using the value length normal for its type.
+    /// Returns the address assigned to the sensor.
+    pub fn register(&mut self, sensor: IBusSensor, source: &'a mut dyn SensorSource) -> Result<u8, RegistryError> {
+        self.register_with_length(sensor, sensor.default_length(), source)
+    }
+
+    pub fn register_with_length(&mut self, sensor: IBusSensor, length: IBusSensorLength,
+                                source: &'a mut dyn SensorSource) -> Result<u8, RegistryError> {
+        if self.count >= N || self.count >= MAX_ADDRESS as usize {
+            return Err(RegistryError::Full);
+        }
+        self.entries[self.count] = Some(Entry { sensor, length, source });
+        self.count += 1;
+        Ok(self.count as u8)
+    }
+
+    pub fn len(&self) -> usize { self.count }
+    pub fn is_empty(&self) -> bool { self.count == 0 }
+
+    fn entry(&mut self, addr: u8) -> Option<&mut Entry<'a>> {
+        if addr == 0 {
+            return None;
+        }
+        self.entries.get_mut(addr as usize - 1)?.as_mut()
+    }
+
+    /// Answer a request for one of the registered sensors.
+    /// Returns `None` if the message is not a request for one of our addresses.
+    pub fn respond(&mut self, msg: &IBusMsg) -> Option<IBusMsg> {
+        match *msg {
+            IBusMsg::DiscoveryRequest(addr) =>
+                self.entry(addr).map(|_| IBusMsg::DiscoveryResponse(addr)),
+            IBusMsg::TypeRequest(addr) =>
+                self.entry(addr).map(|e| IBusMsg::TypeResponse(addr, e.sensor, e.length)),
+            IBusMsg::ValueRequest(addr) =>
+                self.entry(addr).map(|e| IBusMsg::value_response(addr, e.length, e.source.read())),
+            _ => None
+        }
+    }
+}
+
+impl<'a, const N: usize> SensorResponder for SensorRegistry<'a, N> {
+    fn respond(&mut self, msg: &IBusMsg) -> Option<IBusMsg> { SensorRegistry::respond(self, msg) }
+}
+
+impl<'a, const N: usize> Default for SensorRegistry<'a, N> {
+    fn default() -> Self { Self::new() }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Counter(u32);
+
+    impl SensorSource for Counter {
+        fn read(&mut self) -> u32 {
+            self.0 += 1;
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_addressing() {
+        let mut temp = || 400u32;
+        let mut press = Counter(100_000);
+        let mut registry = SensorRegistry::<4>::new();
+        assert_eq!(registry.register(IBusSensor::TEMP, &mut temp), Ok(1));
+        assert_eq!(registry.register(IBusSensor::PRESS, &mut press), Ok(2));
+        assert_eq!(registry.len(), 2);
+
+        assert_eq!(registry.respond(&IBusMsg::DiscoveryRequest(0)), None);
+        assert_eq!(registry.respond(&IBusMsg::DiscoveryRequest(1)), Some(IBusMsg::DiscoveryResponse(1)));
+        assert_eq!(registry.respond(&IBusMsg::DiscoveryRequest(2)), Some(IBusMsg::DiscoveryResponse(2)));
+        assert_eq!(registry.respond(&IBusMsg::DiscoveryRequest(3)), None);
+        assert_eq!(registry.respond(&IBusMsg::TypeRequest(1)),
+                   Some(IBusMsg::TypeResponse(1, IBusSensor::TEMP, IBusSensorLength::Short)));
+        assert_eq!(registry.respond(&IBusMsg::TypeRequest(2)),
+                   Some(IBusMsg::TypeResponse(2, IBusSensor::PRESS, IBusSensorLength::Long)));
+        assert_eq!(registry.respond(&IBusMsg::ValueRequest(1)), Some(IBusMsg::ValueResponseShort(1, 400)));
+        assert_eq!(registry.respond(&IBusMsg::ValueRequest(2)), Some(IBusMsg::ValueResponseLong(2, 100_001)));
+        assert_eq!(registry.respond(&IBusMsg::ValueRequest(2)), Some(IBusMsg::ValueResponseLong(2, 100_002)));
+        assert_eq!(registry.respond(&IBusMsg::SetMsg([1500; 14])), None);
+    }
+
+    #[test]
+    fn test_full() {
+        let mut sources: [Counter; 16] = core::array::from_fn(|_| Counter(0));
+        let mut registry = SensorRegistry::<16>::new();
+        let mut iter = sources.iter_mut();
+        for i in 1..=15 {
+            assert_eq!(registry.register(IBusSensor::EXTV, iter.next().unwrap()), Ok(i));
+        }
+        assert_eq!(registry.register(IBusSensor::EXTV, iter.next().unwrap()), Err(RegistryError::Full));
+        assert_eq!(registry.respond(&IBusMsg::TypeRequest(15)),
+                   Some(IBusMsg::TypeResponse(15, IBusSensor::EXTV, IBusSensorLength::Short)));
+
+        let mut a = || 1u32;
+        let mut b = || 2u32;
+        let mut small = SensorRegistry::<1>::new();
+        assert_eq!(small.register(IBusSensor::TEMP, &mut a), Ok(1));
+        assert_eq!(small.register(IBusSensor::TEMP, &mut b), Err(RegistryError::Full));
+    }
+
+    #[test]
+    fn test_rpm_source() {
+        let mut rpm = RpmSensor::<1>::new(2);
+        rpm.push_erpm(1234);
+        let mut registry = SensorRegistry::<2>::new();
+        assert_eq!(registry.register(IBusSensor::RPM, &mut rpm), Ok(1));
+        assert_eq!(registry.respond(&IBusMsg::ValueRequest(1)), Some(IBusMsg::ValueResponseShort(1, 1234)));
+    }
+}
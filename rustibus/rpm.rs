@@ -0,0 +1,157 @@
+
+use crate::RustIBus::{IBusMsg, IBusSensor, IBusSensorLength};
+
+
+/// Converts ESC telemetry (electrical RPM, electrical frequency or pulse period)
+/// into the mechanical RPM reported by an `IBusSensor::RPM` sensor.
+/// The last `WINDOW` samples are averaged; the result saturates at `u16::MAX`.
+pub struct RpmSensor<const WINDOW: usize> {
+    poles: u8,
+    gear_motor: u16,
+    gear_output: u16,
+    samples: [u32; WINDOW],
+    pos: usize,
+    count: usize,
+}
+
+impl<const WINDOW: usize> RpmSensor<WINDOW> {
+    pub const SENSOR: IBusSensor = IBusSensor::RPM;
+    pub const LENGTH: IBusSensorLength = IBusSensorLength::Short;
+
+    /// Create a sensor for a motor with `poles` magnet poles (not pole pairs), driving the
+    /// output directly.
+    pub const fn new(poles: u8) -> Self {
+        Self {
+            poles: if poles < 2 { 2 } else { poles },
+            gear_motor: 1,
+            gear_output: 1,
+            samples: [0u32; WINDOW],
+            pos: 0,
+            count: 0,
+        }
+    }
+
+    /// Set the gear ratio: the motor turns `motor` times for every `output` turns.
+    /// E.g. a 10:1 reduction is `with_gear_ratio(10, 1)`.
+    pub const fn with_gear_ratio(mut self, motor: u16, output: u16) -> Self {
+        self.gear_motor = if motor == 0 { 1 } else { motor };
+        self.gear_output = if output == 0 { 1 } else { output };
+        self
+    }
+
+    /// Add a sample given as electrical RPM, as reported by most ESC telemetry.
+    pub fn push_erpm(&mut self, erpm: u32) {
+        // Mechanical RPM = eRPM / pole pairs, then through the gearbox.
+        let rpm = (erpm as u64 * 2 * self.gear_output as u64)
+            / (self.poles as u64 * self.gear_motor as u64);
+        self.push_rpm(rpm);
+    }
+
+    /// Add a sample given as electrical frequency in Hz.
+    pub fn push_frequency(&mut self, hz: u32) {
+        self.push_erpm(hz.saturating_mul(60));
+    }
+
+    /// Add a sample given as the period of one electrical revolution, in microseconds.
+    /// For a three-phase BLDC the electrical period is six commutation periods.
+    /// A period of zero is taken to mean the motor is stopped.
+    pub fn push_period_us(&mut self, period_us: u32) {
+        if period_us == 0 {
+            self.push_rpm(0);
+            return;
+        }
+        let erpm = 60_000_000u64 / period_us as u64;
+        self.push_erpm(erpm.min(u32::MAX as u64) as u32);
+    }
+
+    fn push_rpm(&mut self, rpm: u64) {
+        if WINDOW == 0 {
+            return;
+        }
+        self.samples[self.pos] = rpm.min(u32::MAX as u64) as u32;
+        self.pos = (self.pos + 1) % WINDOW;
+        if self.count < WINDOW {
+            self.count += 1;
+        }
+    }
+
+    /// Forget all samples, e.g. when the ESC telemetry is lost.
+    pub fn reset(&mut self) {
+        self.pos = 0;
+        self.count = 0;
+    }
+
+    /// The averaged mechanical RPM, saturated to the 16 bits the sensor can report.
+    pub fn value(&self) -> u16 {
+        if self.count == 0 {
+            return 0;
+        }
+        let sum: u64 = self.samples[..self.count].iter().map(|s| *s as u64).sum();
+        (sum / self.count as u64).min(u16::MAX as u64) as u16
+    }
+
+    /// The response to a value request for this sensor at address `addr`.
+    pub fn response(&self, addr: u8) -> IBusMsg {
+        IBusMsg::ValueResponseShort(addr, self.value())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversions() {
+        // A 14 pole motor turning at 1000 RPM runs at 7000 eRPM, 116.67 Hz
+        // or 8571 us per electrical revolution.
+        let mut s = RpmSensor::<1>::new(14);
+        s.push_erpm(7000);
+        assert_eq!(s.value(), 1000);
+        s.push_frequency(117);
+        assert_eq!(s.value(), 1002);
+        s.push_period_us(8571);
+        assert_eq!(s.value(), 1000);
+        s.push_period_us(0);
+        assert_eq!(s.value(), 0);
+    }
+
+    #[test]
+    fn test_gear_ratio() {
+        let mut s = RpmSensor::<1>::new(2).with_gear_ratio(10, 1);
+        s.push_erpm(30000);
+        assert_eq!(s.value(), 3000);
+        let mut s = RpmSensor::<1>::new(2).with_gear_ratio(1, 3);
+        s.push_erpm(1000);
+        assert_eq!(s.response(0x02), IBusMsg::ValueResponseShort(0x02, 3000));
+    }
+
+    #[test]
+    fn test_saturation() {
+        let mut s = RpmSensor::<1>::new(2);
+        s.push_erpm(70000);
+        assert_eq!(s.value(), u16::MAX);
+        s.push_frequency(u32::MAX);
+        assert_eq!(s.value(), u16::MAX);
+        s.push_period_us(1);
+        assert_eq!(s.value(), u16::MAX);
+    }
+
+    #[test]
+    fn test_averaging() {
+        let mut s = RpmSensor::<4>::new(2);
+        assert_eq!(s.value(), 0);
+        s.push_erpm(1000);
+        assert_eq!(s.value(), 1000);
+        s.push_erpm(2000);
+        assert_eq!(s.value(), 1500);
+        for _ in 0..4 {
+            s.push_erpm(4000);
+        }
+        assert_eq!(s.value(), 4000);
+        s.push_erpm(0);
+        assert_eq!(s.value(), 3000);
+        s.reset();
+        assert_eq!(s.value(), 0);
+    }
+}
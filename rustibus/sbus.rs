@@ -0,0 +1,300 @@
+//! SBUS, the servo protocol of FrSky and Futaba receivers.
+//!
+//! A frame is 25 bytes at 100000 Baud, 8E2 and inverted: a 0x0f header, 16 channels of
+//! 11 bits packed least significant bit first, a flags byte and a 0x00 footer.
+
+use crate::deque::Deque;
+use crate::rc::{ChannelFrame, RcInput};
+
+
+pub const HEADER: u8 = 0x0f;
+pub const FRAME_LENGTH: usize = 25;
+pub const CHANNELS: usize = 16;
+
+/// Raw channel values at the ends and in the center of the normal stick range.
+pub const RAW_MIN: u16 = 172;
+pub const RAW_CENTER: u16 = 992;
+pub const RAW_MAX: u16 = 1811;
+
+const FLAG_CH17: u8 = 0x01;
+const FLAG_CH18: u8 = 0x02;
+const FLAG_FRAME_LOST: u8 = 0x04;
+const FLAG_FAILSAFE: u8 = 0x08;
+
+
+/// A raw channel value as a servo pulse width in microseconds: 172 - 1811 map to 988 - 2012.
+pub const fn raw_to_us(raw: u16) -> u16 {
+    ((raw as u32 * 5 + 4) / 8 + 880) as u16
+}
+
+/// The reverse of `raw_to_us`, limited to the 11 bits of a channel.
+pub fn us_to_raw(us: u16) -> u16 {
+    let raw = ((us.max(880) as u32 - 880) * 8 + 2) / 5;
+    raw.min(0x7ff) as u16
+}
+
+/// Packs 16 channels of 11 bits into 22 bytes, least significant bit first, as SBUS and CRSF do.
+pub(crate) fn pack_channels(channels: &[u16; CHANNELS]) -> [u8; 22] {
+    let mut bytes = [0u8; 22];
+    for (i, channel) in channels.iter().enumerate() {
+        for bit in 0..11 {
+            if channel & (1 << bit) != 0 {
+                let pos = i * 11 + bit;
+                bytes[pos / 8] |= 1 << (pos % 8);
+            }
+        }
+    }
+    bytes
+}
+
+pub(crate) fn unpack_channels(bytes: &[u8; 22]) -> [u16; CHANNELS] {
+    let mut channels = [0u16; CHANNELS];
+    for (i, channel) in channels.iter_mut().enumerate() {
+        for bit in 0..11 {
+            let pos = i * 11 + bit;
+            if bytes[pos / 8] & (1 << (pos % 8)) != 0 {
+                *channel |= 1 << bit;
+            }
+        }
+    }
+    channels
+}
+
+/// SBUS2 receivers use the footer to number telemetry slots: 0x04, 0x14, 0x24 and 0x34.
+fn valid_footer(byte: u8) -> bool {
+    byte == 0x00 || (byte & 0xcf) == 0x04
+}
+
+
+#[derive(PartialEq, Debug, Default, Clone, Copy)]
+pub struct SbusFrame {
+    /// Raw 11 bit values, see `raw_to_us`.
+    pub channels: [u16; CHANNELS],
+    /// The two digital channels.
+    pub ch17: bool,
+    pub ch18: bool,
+    pub frame_lost: bool,
+    pub failsafe: bool,
+}
+
+impl SbusFrame {
+    /// Decodes a complete frame, or returns `None` if the header or footer is wrong.
+    pub fn decode(bytes: &[u8; FRAME_LENGTH]) -> Option<SbusFrame> {
+        if bytes[0] != HEADER || !valid_footer(bytes[24]) {
+            return None;
+        }
+        let channels = unpack_channels(bytes[1..23].try_into().unwrap());
+        let flags = bytes[23];
+        Some(SbusFrame {
+            channels,
+            ch17: flags & FLAG_CH17 != 0,
+            ch18: flags & FLAG_CH18 != 0,
+            frame_lost: flags & FLAG_FRAME_LOST != 0,
+            failsafe: flags & FLAG_FAILSAFE != 0,
+        })
+    }
+
+    /// Encodes the frame, with a 0x00 footer. Channel values are limited to 11 bits.
+    pub fn encode(&self) -> [u8; FRAME_LENGTH] {
+        let mut bytes = [0u8; FRAME_LENGTH];
+        bytes[0] = HEADER;
+        bytes[1..23].copy_from_slice(&pack_channels(&self.channels));
+        bytes[23] = (if self.ch17 { FLAG_CH17 } else { 0 })
+            | (if self.ch18 { FLAG_CH18 } else { 0 })
+            | (if self.frame_lost { FLAG_FRAME_LOST } else { 0 })
+            | (if self.failsafe { FLAG_FAILSAFE } else { 0 });
+        bytes
+    }
+
+    /// The frame as 18 channels in microseconds; the digital channels are 1000 or 2000.
+    pub fn to_channel_frame(&self) -> ChannelFrame {
+        let mut values = [0u16; CHANNELS + 2];
+        for (value, raw) in values.iter_mut().zip(self.channels.iter()) {
+            *value = raw_to_us(*raw);
+        }
+        values[16] = if self.ch17 { 2000 } else { 1000 };
+        values[17] = if self.ch18 { 2000 } else { 1000 };
+        let mut frame = ChannelFrame::new(&values);
+        frame.failsafe = self.failsafe;
+        frame.frame_lost = self.frame_lost;
+        frame
+    }
+
+    /// A frame with the first 16 channels of `frame`; channels 17 and 18 are on above 1500 us.
+    pub fn from_channel_frame(frame: &ChannelFrame) -> SbusFrame {
+        let mut channels = [RAW_CENTER; CHANNELS];
+        for (raw, us) in channels.iter_mut().zip(frame.channels()) {
+            *raw = us_to_raw(*us);
+        }
+        SbusFrame {
+            channels,
+            ch17: frame.channel(16).is_some_and(|us| us > 1500),
+            ch18: frame.channel(17).is_some_and(|us| us > 1500),
+            frame_lost: frame.frame_lost,
+            failsafe: frame.failsafe,
+        }
+    }
+}
+
+
+#[derive(PartialEq, Debug, Default, Clone, Copy)]
+pub struct SbusStats {
+    /// Frames decoded.
+    pub frames: u32,
+    /// Of which frames the receiver flagged as lost.
+    pub frames_lost: u32,
+    /// Of which frames with failsafe values.
+    pub failsafe_frames: u32,
+    /// Bytes skipped while looking for the start of a frame.
+    pub resync_bytes: u32,
+}
+
+
+/// Decodes an SBUS byte stream, one byte at a time.
+///
+/// SBUS has no checksum, so a frame is found by its header and footer bytes alone.
+/// `SIZE` must hold at least one complete frame, so at least 26 bytes.
+pub struct SbusDecoder<const SIZE: usize = 64> {
+    buffer: Deque<SIZE>,
+    stats: SbusStats,
+}
+
+impl<const SIZE: usize> SbusDecoder<SIZE> {
+    pub const fn new() -> Self {
+        // The buffer holds SIZE - 1 bytes
+        const { assert!(SIZE > FRAME_LENGTH, "SIZE must hold a complete frame") };
+        SbusDecoder { buffer: Deque::new(), stats: SbusStats {
+            frames: 0, frames_lost: 0, failsafe_frames: 0, resync_bytes: 0 } }
+    }
+
+    pub fn feed(&mut self, byte: u8) {
+        self.buffer.push(byte);
+    }
+
+    /// The next frame in the bytes fed so far, if any.
+    pub fn pop(&mut self) -> Option<SbusFrame> {
+        while !Deque::is_empty(&self.buffer) {
+            if self.buffer[0] != HEADER {
+                self.skip();
+                continue;
+            }
+            if self.buffer.len() < FRAME_LENGTH {
+                return None;
+            }
+            let mut bytes = [0u8; FRAME_LENGTH];
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                *byte = self.buffer[i];
+            }
+            match SbusFrame::decode(&bytes) {
+                Some(frame) => {
+                    for _ in 0..FRAME_LENGTH {
+                        self.buffer.pop();
+                    }
+                    self.stats.frames = self.stats.frames.wrapping_add(1);
+                    if frame.frame_lost {
+                        self.stats.frames_lost = self.stats.frames_lost.wrapping_add(1);
+                    }
+                    if frame.failsafe {
+                        self.stats.failsafe_frames = self.stats.failsafe_frames.wrapping_add(1);
+                    }
+                    return Some(frame);
+                }
+                // A 0x0f within a frame, try the next one
+                None => self.skip()
+            }
+        }
+        None
+    }
+
+    fn skip(&mut self) {
+        self.buffer.pop();
+        self.stats.resync_bytes = self.stats.resync_bytes.wrapping_add(1);
+    }
+
+    pub fn stats(&self) -> SbusStats { self.stats }
+
+    pub fn reset_stats(&mut self) { self.stats = SbusStats::default(); }
+}
+
+impl<const SIZE: usize> Default for SbusDecoder<SIZE> {
+    fn default() -> Self { Self::new() }
+}
+
+impl<const SIZE: usize> RcInput for SbusDecoder<SIZE> {
+    fn feed(&mut self, byte: u8) { SbusDecoder::feed(self, byte) }
+
+    fn pop_frame(&mut self) -> Option<ChannelFrame> {
+        self.pop().map(|frame| frame.to_channel_frame())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversion() {
+        assert_eq!(raw_to_us(RAW_MIN), 988);
+        assert_eq!(raw_to_us(RAW_CENTER), 1500);
+        assert_eq!(raw_to_us(RAW_MAX), 2012);
+        for us in 988..=2012 {
+            assert_eq!(raw_to_us(us_to_raw(us)), us);
+        }
+        assert_eq!(us_to_raw(0), 0);
+        assert_eq!(us_to_raw(u16::MAX), 0x7ff);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut frame = SbusFrame { ch18: true, failsafe: true, ..SbusFrame::default() };
+        for (i, channel) in frame.channels.iter_mut().enumerate() {
+            *channel = (i as u16 * 131) & 0x7ff;
+        }
+        frame.channels[5] = 0x7ff;
+        let bytes = frame.encode();
+        assert_eq!(bytes[0], HEADER);
+        assert_eq!(bytes[23], FLAG_CH18 | FLAG_FAILSAFE);
+        assert_eq!(SbusFrame::decode(&bytes), Some(frame));
+
+        // All bits of the first channel in the first byte, then the low 3 bits of the next one
+        let bytes = SbusFrame { channels: [0x7ff, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], ..SbusFrame::default() }.encode();
+        assert_eq!(bytes[1..4], [0xff, 0x07, 0x00]);
+    }
+
+    #[test]
+    fn test_decoder() {
+        let mut decoder = SbusDecoder::<64>::new();
+        let first = SbusFrame { channels: [RAW_MIN; CHANNELS], ..SbusFrame::default() };
+        let second = SbusFrame { channels: [RAW_MAX; CHANNELS], frame_lost: true, ..SbusFrame::default() };
+        let mut sbus2 = second.encode();
+        sbus2[24] = 0x14;
+        let mut frames = alloc::vec::Vec::new();
+        // A partial frame first, as when starting halfway
+        for &b in first.encode()[10..].iter().chain(first.encode().iter()).chain(sbus2.iter()) {
+            decoder.feed(b);
+            frames.extend(core::iter::from_fn(|| decoder.pop()));
+        }
+        assert_eq!(frames, [first, second]);
+        let stats = decoder.stats();
+        assert_eq!(stats.frames, 2);
+        assert_eq!(stats.frames_lost, 1);
+        assert_eq!(stats.resync_bytes, 15);
+    }
+
+    #[test]
+    fn test_rc_input() {
+        let mut decoder = SbusDecoder::<64>::new();
+        let frame = SbusFrame { channels: [RAW_CENTER; CHANNELS], ch17: true, ..SbusFrame::default() };
+        for b in frame.encode() {
+            RcInput::feed(&mut decoder, b);
+        }
+        let channels = decoder.pop_frame().unwrap();
+        assert_eq!(channels.len(), 18);
+        assert_eq!(channels.channel(0), Some(1500));
+        assert_eq!(channels.channel(16), Some(2000));
+        assert_eq!(channels.channel(17), Some(1000));
+        assert!(!channels.failsafe);
+        assert_eq!(SbusFrame::from_channel_frame(&channels), frame);
+    }
+}
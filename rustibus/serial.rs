@@ -0,0 +1,27 @@
+
+/// Non-blocking reading from a serial port, one byte at a time.
+pub trait SerialRead {
+    /// Returns the next received byte, if there is one.
+    fn read(&mut self) -> Option<u8>;
+}
+
+/// Non-blocking writing to a serial port, one byte at a time.
+pub trait SerialWrite {
+    /// Queue a byte for transmission. Returns false if the port can not accept it right now.
+    fn write(&mut self, byte: u8) -> bool;
+
+    /// True when all queued bytes have actually left the port.
+    fn is_tx_complete(&mut self) -> bool { true }
+}
+
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Direction {
+    Receive,
+    Transmit,
+}
+
+/// A serial port on a single wire, that must be switched between receiving and transmitting.
+pub trait HalfDuplex: SerialRead + SerialWrite {
+    fn set_direction(&mut self, direction: Direction);
+}
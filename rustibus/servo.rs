@@ -0,0 +1,332 @@
+//! Drives servos and ESCs from received channels, one `embedded_hal` PWM pin per output.
+//!
+//! Each output has its own end points, trim, slew rate and failsafe; `pulse::ServoTiming`
+//! turns the pulse widths into duty values.
+
+use embedded_hal::PwmPin;
+
+use crate::RustIBus::{IBusMsg, MAX_CHANNELS};
+use crate::pulse::ServoTiming;
+use crate::rc::{ChannelFrame, CENTER_US};
+
+
+/// What an output does while the link is lost.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Failsafe {
+    /// Keep the last position.
+    Hold,
+    /// Move to this pulse width, in microseconds.
+    Position(u16),
+    /// Stop sending pulses, which most ESCs take as stop.
+    Off,
+}
+
+
+/// How a channel value becomes the pulse of one output.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct OutputConfig {
+    /// The channel driving this output, counting from 0.
+    pub channel: usize,
+    /// The end points the pulse is limited to, in microseconds.
+    pub min_us: u16,
+    pub max_us: u16,
+    /// Mirror the channel around the center.
+    pub reverse: bool,
+    /// Added to the channel value, after reversing.
+    pub subtrim_us: i16,
+    /// The most the pulse may change per second, in microseconds; 0 for no limit.
+    pub slew_us_per_s: u32,
+    pub failsafe: Failsafe,
+}
+
+impl OutputConfig {
+    /// Output `channel` over the 1000 - 2000 us range, without limits, moving to the center
+    /// when the link is lost.
+    pub const fn new(channel: usize) -> Self {
+        OutputConfig {
+            channel,
+            min_us: 1000,
+            max_us: 2000,
+            reverse: false,
+            subtrim_us: 0,
+            slew_us_per_s: 0,
+            failsafe: Failsafe::Position(CENTER_US),
+        }
+    }
+
+    /// The pulse width for a channel value, before slew rate limiting.
+    pub fn pulse_us(&self, value: u16) -> u16 {
+        let value = if self.reverse { 2 * CENTER_US as i32 - value as i32 } else { value as i32 };
+        (value + self.subtrim_us as i32).clamp(self.min_us as i32, self.max_us as i32) as u16
+    }
+}
+
+
+/// A `PwmPin` behind a reference, so outputs on pins of different types can be driven together.
+pub struct DynPin<'a, D>(pub &'a mut dyn PwmPin<Duty = D>);
+
+impl<D> PwmPin for DynPin<'_, D> {
+    type Duty = D;
+    fn disable(&mut self) { self.0.disable() }
+    fn enable(&mut self) { self.0.enable() }
+    fn get_duty(&self) -> D { self.0.get_duty() }
+    fn get_max_duty(&self) -> D { self.0.get_max_duty() }
+    fn set_duty(&mut self, duty: D) { self.0.set_duty(duty) }
+}
+
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+struct State {
+    /// The position in thousandths of a microsecond, so slow slew rates still move on fast polls.
+    position: Option<i32>,
+    enabled: bool,
+}
+
+
+/// Drives servos and ESCs from received channel frames, one PWM pin per output.
+///
+/// Hand every frame to `set_channels` (or a message to `handle`) and call `poll` regularly
+/// with a microsecond timestamp, so slew rate limited outputs keep moving and a lost link is
+/// noticed. Until the first frame arrives, and after `timeout_us` without one, the outputs
+/// go to their failsafe.
+pub struct ServoOutputs<P: PwmPin, const N: usize> {
+    pins: [P; N],
+    configs: [OutputConfig; N],
+    states: [State; N],
+    timing: ServoTiming,
+    channels: [u16; MAX_CHANNELS],
+    timeout_us: u32,
+    received_us: Option<u32>,
+    polled_us: Option<u32>,
+}
+
+impl<P: PwmPin, const N: usize> ServoOutputs<P, N>
+    where P::Duty: Into<u32> + TryFrom<u32>
+{
+    /// Frames stop being fresh after this time without input, in microseconds.
+    pub const DEFAULT_TIMEOUT_US: u32 = 100_000;
+
+    /// Outputs on `pins`, configured by `configs`, with the PWM timer running at `period_us`.
+    pub fn new(mut pins: [P; N], configs: [OutputConfig; N], period_us: u32) -> Self {
+        let max_duty = pins.first().map_or(0, |pin| pin.get_max_duty().into());
+        for pin in pins.iter_mut() {
+            pin.disable();
+        }
+        ServoOutputs {
+            pins,
+            configs,
+            states: [State { position: None, enabled: false }; N],
+            timing: ServoTiming::new(period_us, max_duty),
+            channels: [CENTER_US; MAX_CHANNELS],
+            timeout_us: Self::DEFAULT_TIMEOUT_US,
+            received_us: None,
+            polled_us: None,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout_us: u32) -> Self {
+        self.timeout_us = timeout_us;
+        self
+    }
+
+    /// Take a new frame of channel values and update the outputs.
+    pub fn set_channels(&mut self, channels: &[u16], now_us: u32) {
+        for (value, channel) in self.channels.iter_mut().zip(channels) {
+            *value = *channel;
+        }
+        self.received_us = Some(now_us);
+        self.poll(now_us);
+    }
+
+    /// Take the channels of a set message, unpacking 18 channel frames; other messages are ignored.
+    pub fn handle(&mut self, msg: &IBusMsg, now_us: u32) {
+        if let IBusMsg::SetMsg(data) = msg {
+            self.set_channels(ChannelFrame::from_set_msg(data).channels(), now_us);
+        }
+    }
+
+    /// True while frames arrive within the timeout. Once `poll` has seen the timeout pass,
+    /// this stays false until the next frame.
+    pub fn is_linked(&self, now_us: u32) -> bool {
+        self.received_us.is_some_and(|received| now_us.wrapping_sub(received) <= self.timeout_us)
+    }
+
+    /// Move the outputs towards their targets and write the duties.
+    pub fn poll(&mut self, now_us: u32) {
+        let elapsed_us = self.polled_us.map_or(0, |polled| now_us.wrapping_sub(polled));
+        self.polled_us = Some(now_us);
+        let linked = self.is_linked(now_us);
+        if !linked {
+            // Latch the loss, or the old time would look recent again once the timer wraps
+            self.received_us = None;
+        }
+        for i in 0..N {
+            let config = &self.configs[i];
+            let target = match (linked, config.failsafe) {
+                (true, _) => Some(config.pulse_us(self.channels.get(config.channel).copied().unwrap_or(CENTER_US))),
+                (false, Failsafe::Position(pulse_us)) => Some(pulse_us),
+                (false, Failsafe::Hold) => self.states[i].position.map(|p| ((p + 500) / 1000) as u16),
+                (false, Failsafe::Off) => None,
+            };
+            let Some(target) = target else {
+                if self.states[i].enabled {
+                    self.pins[i].disable();
+                    self.states[i].enabled = false;
+                }
+                continue;
+            };
+
+            let target = target as i32 * 1000;
+            let position = match self.states[i].position {
+                Some(position) if config.slew_us_per_s > 0 => {
+                    let step = (config.slew_us_per_s as u64 * elapsed_us as u64 / 1000).min(i32::MAX as u64) as i32;
+                    target.clamp(position.saturating_sub(step), position.saturating_add(step))
+                }
+                _ => target
+            };
+            self.states[i].position = Some(position);
+
+            let duty = self.timing.duty(((position + 500) / 1000) as u16);
+            if let Ok(duty) = P::Duty::try_from(duty) {
+                self.pins[i].set_duty(duty);
+            }
+            if !self.states[i].enabled {
+                self.pins[i].enable();
+                self.states[i].enabled = true;
+            }
+        }
+    }
+
+    /// The current pulse width of each output, or `None` for outputs that are off.
+    pub fn pulses_us(&self) -> [Option<u16>; N] {
+        let mut pulses = [None; N];
+        for (pulse, state) in pulses.iter_mut().zip(self.states.iter()) {
+            if state.enabled {
+                *pulse = state.position.map(|p| ((p + 500) / 1000) as u16);
+            }
+        }
+        pulses
+    }
+
+    pub fn config(&mut self, output: usize) -> Option<&mut OutputConfig> { self.configs.get_mut(output) }
+
+    pub fn release(self) -> [P; N] { self.pins }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 16 bit timer channel at 1 us per count.
+    #[derive(Default)]
+    struct Pin {
+        duty: u16,
+        enabled: bool,
+    }
+
+    impl PwmPin for Pin {
+        type Duty = u16;
+        fn disable(&mut self) { self.enabled = false; }
+        fn enable(&mut self) { self.enabled = true; }
+        fn get_duty(&self) -> u16 { self.duty }
+        fn get_max_duty(&self) -> u16 { 20_000 }
+        fn set_duty(&mut self, duty: u16) { self.duty = duty; }
+    }
+
+    fn duties<const N: usize>(outputs: ServoOutputs<Pin, N>) -> [Option<u16>; N] {
+        outputs.release().map(|pin| if pin.enabled { Some(pin.duty) } else { None })
+    }
+
+    #[test]
+    fn test_mapping() {
+        let reversed = OutputConfig { reverse: true, subtrim_us: 20, ..OutputConfig::new(0) };
+        let limited = OutputConfig { min_us: 1100, max_us: 1800, ..OutputConfig::new(2) };
+        let mut outputs = ServoOutputs::new([Pin::default(), Pin::default(), Pin::default()],
+                                            [OutputConfig::new(2), reversed, limited], 20_000);
+        outputs.poll(0);
+        assert_eq!(outputs.pulses_us(), [Some(1500); 3]);
+
+        let mut data = [1500u16; 14];
+        data[0] = 1200;
+        data[2] = 1950;
+        outputs.handle(&IBusMsg::SetMsg(data), 1_000);
+        assert!(outputs.is_linked(1_000));
+        assert_eq!(outputs.pulses_us(), [Some(1950), Some(1820), Some(1800)]);
+        assert_eq!(duties(outputs), [Some(1950), Some(1820), Some(1800)]);
+    }
+
+    #[test]
+    fn test_18channels() {
+        let mut outputs = ServoOutputs::new([Pin::default(), Pin::default()],
+                                            [OutputConfig::new(4), OutputConfig::new(17)], 20_000);
+        let mut values = [1500u16; MAX_CHANNELS];
+        values[4] = 1000;
+        values[17] = 1900;
+        outputs.handle(&IBusMsg::SetMsg(crate::RustIBus::packChannels(&values)), 0);
+        assert_eq!(outputs.pulses_us(), [Some(1000), Some(1900)]);
+    }
+
+    #[test]
+    fn test_slew() {
+        let config = OutputConfig { slew_us_per_s: 1000, ..OutputConfig::new(0) };
+        let mut outputs = ServoOutputs::new([Pin::default()], [config], 20_000);
+        outputs.set_channels(&[1000], 0);
+        assert_eq!(outputs.pulses_us(), [Some(1000)]);
+        outputs.set_channels(&[2000], 100_000);
+        assert_eq!(outputs.pulses_us(), [Some(1100)]);
+        // Many small steps add up
+        for t in 0..1000 {
+            outputs.poll(100_000 + t * 100);
+        }
+        assert_eq!(outputs.pulses_us(), [Some(1200)]);
+    }
+
+    #[test]
+    fn test_failsafe() {
+        let hold = OutputConfig { failsafe: Failsafe::Hold, ..OutputConfig::new(0) };
+        let off = OutputConfig { failsafe: Failsafe::Off, ..OutputConfig::new(1) };
+        let position = OutputConfig { failsafe: Failsafe::Position(1100), ..OutputConfig::new(2) };
+        let mut outputs = ServoOutputs::new([Pin::default(), Pin::default(), Pin::default()],
+                                            [hold, off, position], 20_000).with_timeout(50_000);
+        // No link yet
+        outputs.poll(0);
+        assert_eq!(outputs.pulses_us(), [None, None, Some(1100)]);
+
+        outputs.set_channels(&[1300, 1400, 1600], 10_000);
+        assert_eq!(outputs.pulses_us(), [Some(1300), Some(1400), Some(1600)]);
+        outputs.poll(60_000);
+        assert_eq!(outputs.pulses_us(), [Some(1300), Some(1400), Some(1600)]);
+        outputs.poll(60_001);
+        assert!(!outputs.is_linked(60_001));
+        assert_eq!(outputs.pulses_us(), [Some(1300), None, Some(1100)]);
+        assert_eq!(duties(outputs), [Some(1300), None, Some(1100)]);
+    }
+
+    #[test]
+    fn test_failsafe_wrap() {
+        let mut outputs = ServoOutputs::new([Pin::default()], [OutputConfig::new(0)], 20_000);
+        outputs.set_channels(&[1800], 0);
+        // No frames for longer than it takes the timer to wrap around
+        for s in 1..=4_294u64 {
+            outputs.poll((s * 1_000_000) as u32);
+        }
+        outputs.poll(10_000);
+        assert!(!outputs.is_linked(10_000));
+        assert_eq!(outputs.pulses_us(), [Some(1500)]);
+        outputs.set_channels(&[1800], 20_000);
+        assert_eq!(outputs.pulses_us(), [Some(1800)]);
+    }
+
+    #[test]
+    fn test_dyn_pins() {
+        let mut first = Pin::default();
+        let mut second = Pin::default();
+        let pins = [DynPin(&mut first as &mut dyn PwmPin<Duty = u16>), DynPin(&mut second)];
+        {
+            let mut outputs = ServoOutputs::new(pins, [OutputConfig::new(1), OutputConfig::new(0)], 20_000);
+            outputs.set_channels(&[1000, 2000], 0);
+        }
+        assert_eq!((first.duty, second.duty), (2000, 1000));
+    }
+}
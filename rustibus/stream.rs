@@ -0,0 +1,199 @@
+
+use crate::RustIBus::{IBusMsg, popIBusMsg, popIBusResponse, unpackChannels, MAX_LENGTH, SET, DISCOVER, TYPE, VALUE};
+use crate::deque::Deque;
+use crate::rc::{ChannelFrame, RcInput};
+use crate::transmitter::ChannelCount;
+
+
+#[derive(PartialEq, Debug, Default, Clone, Copy)]
+pub struct LinkStats {
+    /// All bytes fed to the decoder.
+    pub bytes: u32,
+    /// Messages decoded, of any kind.
+    pub messages: u32,
+    /// Of which servo (set) messages.
+    pub set_msgs: u32,
+    /// Bytes skipped while looking for the start of a message.
+    pub resync_bytes: u32,
+    /// Complete messages with a plausible length and command whose checksum did not match.
+    /// The first byte of such a message is also counted in `resync_bytes`.
+    pub checksum_errors: u32,
+}
+
+
+/// Decodes a byte stream, one byte at a time, while keeping statistics on the link quality.
+///
+/// Feed the received bytes with `feed` and take the decoded messages with `pop`.
+pub struct StreamDecoder<const SIZE: usize = 64> {
+    buffer: Deque<SIZE>,
+    responses: bool,
+    channels: ChannelCount,
+    stats: LinkStats,
+}
+
+impl<const SIZE: usize> StreamDecoder<SIZE> {
+    /// A decoder for what a receiver sends: servo messages and sensor requests.
+    pub const fn new() -> Self {
+        StreamDecoder { buffer: Deque::new(), responses: false, channels: ChannelCount::Fourteen, stats: LinkStats {
+            bytes: 0, messages: 0, set_msgs: 0, resync_bytes: 0, checksum_errors: 0 } }
+    }
+
+    /// A decoder for what sensors send: sensor responses.
+    pub const fn for_responses() -> Self {
+        let mut decoder = Self::new();
+        decoder.responses = true;
+        decoder
+    }
+
+    /// Take the servo messages as frames of `count` channels when used as an `RcInput`.
+    pub const fn with_channels(mut self, count: ChannelCount) -> Self {
+        self.channels = count;
+        self
+    }
+
+    pub fn feed(&mut self, byte: u8) {
+        self.stats.bytes = self.stats.bytes.wrapping_add(1);
+        self.buffer.push(byte);
+    }
+
+    /// The next message in the bytes fed so far, if any.
+    pub fn pop(&mut self) -> Option<IBusMsg> {
+        while !Deque::is_empty(&self.buffer) {
+            let (msg, consumed) = if self.responses { popIBusResponse(&self.buffer) }
+                                  else { popIBusMsg(&self.buffer) };
+            if consumed == 0 {
+                return None;
+            }
+            if consumed == 1 && msg.is_none() {
+                if self.bad_checksum() {
+                    self.stats.checksum_errors = self.stats.checksum_errors.wrapping_add(1);
+                }
+                self.stats.resync_bytes = self.stats.resync_bytes.wrapping_add(1);
+            }
+            for _ in 0..consumed {
+                self.buffer.pop();
+            }
+            if let Some(msg) = msg {
+                self.stats.messages = self.stats.messages.wrapping_add(1);
+                if let IBusMsg::SetMsg(_) = msg {
+                    self.stats.set_msgs = self.stats.set_msgs.wrapping_add(1);
+                }
+                return Some(msg);
+            }
+        }
+        None
+    }
+
+    /// Tells whether a rejected buffer starts with a complete message that only fails the CRC.
+    fn bad_checksum(&self) -> bool {
+        let length = self.buffer[0];
+        if !(4..=MAX_LENGTH).contains(&length) || self.buffer.len() < length as usize {
+            return false;
+        }
+        let cmnd = self.buffer[1] & 0xf0;
+        match cmnd {
+            SET => length == MAX_LENGTH,
+            DISCOVER => length == 4,
+            TYPE | VALUE if self.responses => length == 6 || (cmnd == VALUE && length == 8),
+            TYPE | VALUE => length == 4,
+            _ => false
+        }
+    }
+
+    pub fn stats(&self) -> LinkStats { self.stats }
+
+    pub fn reset_stats(&mut self) { self.stats = LinkStats::default(); }
+}
+
+impl<const SIZE: usize> Default for StreamDecoder<SIZE> {
+    fn default() -> Self { Self::new() }
+}
+
+impl<const SIZE: usize> RcInput for StreamDecoder<SIZE> {
+    fn feed(&mut self, byte: u8) { StreamDecoder::feed(self, byte) }
+
+    fn pop_frame(&mut self) -> Option<ChannelFrame> {
+        while let Some(msg) = self.pop() {
+            if let IBusMsg::SetMsg(data) = msg {
+                return Some(match self.channels {
+                    ChannelCount::Fourteen => ChannelFrame::new(&data),
+                    ChannelCount::Eighteen => ChannelFrame::new(&unpackChannels(&data)),
+                });
+            }
+        }
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::encode;
+
+    #[test]
+    fn test_stats() {
+        let mut decoder = StreamDecoder::<64>::new();
+        let mut msgs = alloc::vec::Vec::new();
+        let mut bad = encode(&IBusMsg::SetMsg([1500; 14]));
+        bad[5] ^= 0x01;
+        let bytes: alloc::vec::Vec<u8> = [0x55u8, 0x01].iter().copied()
+            .chain(encode(&IBusMsg::SetMsg([1000; 14])))
+            .chain(bad)
+            .chain(encode(&IBusMsg::DiscoveryRequest(3)))
+            .collect();
+        for b in bytes {
+            decoder.feed(b);
+            while let Some(msg) = decoder.pop() {
+                msgs.push(msg);
+            }
+        }
+        assert_eq!(msgs, [IBusMsg::SetMsg([1000; 14]), IBusMsg::DiscoveryRequest(3)]);
+        let stats = decoder.stats();
+        assert_eq!(stats.bytes, 2 + 32 + 32 + 4);
+        assert_eq!(stats.messages, 2);
+        assert_eq!(stats.set_msgs, 1);
+        assert_eq!(stats.resync_bytes, 2 + 32);
+        assert_eq!(stats.checksum_errors, 1);
+        decoder.reset_stats();
+        assert_eq!(decoder.stats(), LinkStats::default());
+    }
+
+    #[test]
+    fn test_responses() {
+        let mut decoder = StreamDecoder::<64>::for_responses();
+        for b in encode(&IBusMsg::ValueResponseLong(2, 100_000)) {
+            decoder.feed(b);
+        }
+        assert_eq!(decoder.pop(), Some(IBusMsg::ValueResponseLong(2, 100_000)));
+        assert_eq!(decoder.pop(), None);
+    }
+
+    #[test]
+    fn test_rc_input() {
+        let mut data = [1500u16; 14];
+        data[0] = 1000;
+        let bytes: alloc::vec::Vec<u8> = encode(&IBusMsg::DiscoveryRequest(1)).into_iter()
+            .chain(encode(&IBusMsg::SetMsg(data)))
+            .collect();
+
+        let mut decoder = StreamDecoder::<64>::new();
+        for &b in &bytes {
+            RcInput::feed(&mut decoder, b);
+        }
+        let frame = decoder.pop_frame().unwrap();
+        assert_eq!(frame.len(), 14);
+        assert_eq!(frame.channel(0), Some(1000));
+        assert_eq!(decoder.pop_frame(), None);
+        assert_eq!(decoder.stats().messages, 2);
+
+        // The high nibbles of 1500 (0x5dc) are zero, so channels 15 - 18 read as 0
+        let mut decoder = StreamDecoder::<64>::new().with_channels(ChannelCount::Eighteen);
+        for &b in &bytes {
+            RcInput::feed(&mut decoder, b);
+        }
+        let frame = decoder.pop_frame().unwrap();
+        assert_eq!(frame.len(), 18);
+        assert_eq!(frame.channels()[13..], [1500, 0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,225 @@
+//! Turns the channels of switches and buttons into positions and events.
+
+use crate::RustIBus::IBusMsg;
+use crate::rc::ChannelFrame;
+
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum SwitchKind {
+    TwoPosition,
+    ThreePosition,
+    /// A momentary button, pressed above the threshold.
+    Button,
+}
+
+impl SwitchKind {
+    pub const fn positions(&self) -> u8 {
+        match self {
+            SwitchKind::ThreePosition => 3,
+            _ => 2
+        }
+    }
+}
+
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct SwitchConfig {
+    /// The channel carrying the switch, counting from 0.
+    pub channel: usize,
+    pub kind: SwitchKind,
+    /// The values between positions 0 and 1, and between 1 and 2, in microseconds.
+    /// Only the first is used for two position switches and buttons.
+    pub thresholds: [u16; 2],
+    /// How far a value must pass a threshold to change position, in microseconds.
+    pub hysteresis_us: u16,
+    /// The number of frames in a row a new position must be seen before it is taken.
+    pub debounce_frames: u8,
+}
+
+impl SwitchConfig {
+    pub const fn new(channel: usize, kind: SwitchKind) -> Self {
+        let thresholds = match kind {
+            SwitchKind::ThreePosition => [1250, 1750],
+            _ => [1500, 1500]
+        };
+        SwitchConfig { channel, kind, thresholds, hysteresis_us: 50, debounce_frames: 2 }
+    }
+
+    pub const fn two_position(channel: usize) -> Self { Self::new(channel, SwitchKind::TwoPosition) }
+    pub const fn three_position(channel: usize) -> Self { Self::new(channel, SwitchKind::ThreePosition) }
+    pub const fn button(channel: usize) -> Self { Self::new(channel, SwitchKind::Button) }
+
+    /// The position for `value_us`, for a switch that is in position `from`.
+    pub fn position(&self, from: u8, value_us: u16) -> u8 {
+        let mut position = from.min(self.kind.positions() - 1);
+        while position + 1 < self.kind.positions()
+            && value_us > self.thresholds[position as usize].saturating_add(self.hysteresis_us) {
+            position += 1;
+        }
+        while position > 0 && value_us < self.thresholds[position as usize - 1].saturating_sub(self.hysteresis_us) {
+            position -= 1;
+        }
+        position
+    }
+}
+
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum SwitchEvent {
+    /// Button `switch` was pressed.
+    Pressed { switch: usize },
+    Released { switch: usize },
+    /// Switch `switch` moved from one position to another.
+    PositionChanged { switch: usize, from: u8, to: u8 },
+}
+
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+struct State {
+    position: Option<u8>,
+    candidate: u8,
+    frames: u8,
+}
+
+
+/// Tracks `N` switches and buttons over the received frames.
+///
+/// The first frame sets the positions without any events, so a switch that is already on at
+/// start-up does not look as if it was just switched on.
+pub struct SwitchDecoder<const N: usize> {
+    configs: [SwitchConfig; N],
+    states: [State; N],
+}
+
+impl<const N: usize> SwitchDecoder<N> {
+    pub const fn new(configs: [SwitchConfig; N]) -> Self {
+        SwitchDecoder { configs, states: [State { position: None, candidate: 0, frames: 0 }; N] }
+    }
+
+    /// Handle a frame of channel values. Returns the event of each switch, if any.
+    pub fn update(&mut self, channels: &[u16]) -> [Option<SwitchEvent>; N] {
+        let mut events = [None; N];
+        for (switch, (config, state)) in self.configs.iter().zip(self.states.iter_mut()).enumerate() {
+            let Some(&value) = channels.get(config.channel) else { continue };
+            let Some(from) = state.position else {
+                state.position = Some(config.position(0, value));
+                continue;
+            };
+            let to = config.position(from, value);
+            if to == from {
+                state.frames = 0;
+                continue;
+            }
+            if to == state.candidate && state.frames > 0 {
+                state.frames = state.frames.saturating_add(1);
+            } else {
+                state.candidate = to;
+                state.frames = 1;
+            }
+            if state.frames < config.debounce_frames {
+                continue;
+            }
+            state.position = Some(to);
+            state.frames = 0;
+            events[switch] = Some(match config.kind {
+                SwitchKind::Button if to == 1 => SwitchEvent::Pressed { switch },
+                SwitchKind::Button => SwitchEvent::Released { switch },
+                _ => SwitchEvent::PositionChanged { switch, from, to },
+            });
+        }
+        events
+    }
+
+    /// Handle the channels of a set message, unpacking 18 channel frames; other messages give no events.
+    pub fn update_msg(&mut self, msg: &IBusMsg) -> [Option<SwitchEvent>; N] {
+        match msg {
+            IBusMsg::SetMsg(data) => self.update(ChannelFrame::from_set_msg(data).channels()),
+            _ => [None; N]
+        }
+    }
+
+    /// The position of switch `switch`, once a frame has been seen.
+    pub fn position(&self, switch: usize) -> Option<u8> {
+        self.states.get(switch).and_then(|state| state.position)
+    }
+
+    pub fn is_pressed(&self, switch: usize) -> bool { self.position(switch) == Some(1) }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn frame(ch5: u16, ch6: u16) -> IBusMsg {
+        let mut data = [1500u16; 14];
+        data[4] = ch5;
+        data[5] = ch6;
+        IBusMsg::SetMsg(data)
+    }
+
+    #[test]
+    fn test_position() {
+        let config = SwitchConfig::three_position(0);
+        assert_eq!(config.position(0, 1000), 0);
+        assert_eq!(config.position(0, 1500), 1);
+        assert_eq!(config.position(0, 2000), 2);
+        // Hysteresis around the thresholds
+        assert_eq!(config.position(0, 1290), 0);
+        assert_eq!(config.position(1, 1210), 1);
+        assert_eq!(config.position(1, 1199), 0);
+        assert_eq!(config.position(2, 1710), 2);
+        assert_eq!(config.position(2, 1000), 0);
+    }
+
+    #[test]
+    fn test_events() {
+        let mut decoder = SwitchDecoder::new([SwitchConfig::three_position(4), SwitchConfig::button(5)]);
+        assert_eq!(decoder.position(0), None);
+        assert_eq!(decoder.update_msg(&frame(2000, 1000)), [None, None]);
+        assert_eq!(decoder.position(0), Some(2));
+        assert!(!decoder.is_pressed(1));
+
+        let mut events = Vec::new();
+        // A glitch of a single frame is ignored
+        for (ch5, ch6) in [(1500, 1000), (2000, 2000), (2000, 1000), (1500, 2000), (1500, 2000), (1500, 1000), (1000, 1000), (1000, 1000)] {
+            events.extend(decoder.update_msg(&frame(ch5, ch6)).into_iter().flatten());
+        }
+        assert_eq!(events, [
+            SwitchEvent::PositionChanged { switch: 0, from: 2, to: 1 },
+            SwitchEvent::Pressed { switch: 1 },
+            SwitchEvent::Released { switch: 1 },
+            SwitchEvent::PositionChanged { switch: 0, from: 1, to: 0 },
+        ]);
+        assert_eq!(decoder.update_msg(&IBusMsg::DiscoveryRequest(1)), [None, None]);
+    }
+
+    #[test]
+    fn test_debounce() {
+        let config = SwitchConfig { debounce_frames: 3, ..SwitchConfig::two_position(0) };
+        let mut decoder = SwitchDecoder::new([config]);
+        decoder.update(&[1000]);
+        assert_eq!(decoder.update(&[2000]), [None]);
+        assert_eq!(decoder.update(&[2000]), [None]);
+        // Interrupted, so counting starts again
+        assert_eq!(decoder.update(&[1000]), [None]);
+        assert_eq!(decoder.update(&[2000]), [None]);
+        assert_eq!(decoder.update(&[2000]), [None]);
+        assert_eq!(decoder.update(&[2000]), [Some(SwitchEvent::PositionChanged { switch: 0, from: 0, to: 1 })]);
+        // A missing channel leaves the switch alone
+        assert_eq!(decoder.update(&[]), [None]);
+        assert_eq!(decoder.position(0), Some(1));
+    }
+
+    #[test]
+    fn test_18channels() {
+        let mut decoder = SwitchDecoder::new([SwitchConfig::three_position(4), SwitchConfig::button(17)]);
+        let mut values = [2000u16; crate::RustIBus::MAX_CHANNELS];
+        values[4] = 1000;
+        // Channel 5 carries bits of channel 16 in its packed word
+        decoder.update_msg(&IBusMsg::SetMsg(crate::RustIBus::packChannels(&values)));
+        assert_eq!(decoder.position(0), Some(0));
+        assert!(decoder.is_pressed(1));
+    }
+}
@@ -0,0 +1,143 @@
+
+use crate::RustIBus::{IBusMsg, IBusSensor, IBusSensorLength};
+use crate::registry::SensorResponder;
+
+#[cfg(feature = "derive")]
+pub use rustibus_derive::IBusTelemetry;
+
+
+/// Conversion of a value into the units an IBus sensor reports.
+///
+/// Floating point values are taken to be in physical units and are scaled:
+/// volts to 0.01 V, degrees Celsius to 0.1 °C with a 40 °C offset, pascal and RPM as is.
+/// Integer values are taken to be in IBus units already and are passed unchanged;
+/// as no sensor reports negative values, negative integers are sent as 0.
+pub trait SensorValue {
+    fn to_ibus(&self, sensor: IBusSensor) -> u32;
+}
+
+impl SensorValue for f32 {
+    fn to_ibus(&self, sensor: IBusSensor) -> u32 {
+        let scaled = match sensor {
+            IBusSensor::INTV | IBusSensor::EXTV => *self * 100.0,
+            IBusSensor::TEMP => (*self + 40.0) * 10.0,
+            _ => *self
+        };
+        // Round to nearest; the cast saturates negative values to 0.
+        (scaled + 0.5) as u32
+    }
+}
+
+macro_rules! raw_sensor_value {
+    ($($t:ty),*) => {
+        $(impl SensorValue for $t {
+            fn to_ibus(&self, _sensor: IBusSensor) -> u32 { *self as u32 }
+        })*
+    };
+}
+
+raw_sensor_value!(u8, u16, u32);
+
+macro_rules! signed_sensor_value {
+    ($($t:ty),*) => {
+        $(impl SensorValue for $t {
+            fn to_ibus(&self, _sensor: IBusSensor) -> u32 { (*self).max(0) as u32 }
+        })*
+    };
+}
+
+signed_sensor_value!(i16, i32);
+
+
+/// A struct whose fields are exposed as IBus sensors, at addresses 1 and up.
+/// Normally implemented with `#[derive(IBusTelemetry)]` (feature `derive`):
+///
+/// ```ignore
+/// #[derive(IBusTelemetry)]
+/// struct Telemetry {
+///     #[ibus(sensor = "TEMP")]
+///     temperature: f32,
+///     #[ibus(sensor = "EXTV")]
+///     battery: f32,
+/// }
+/// ```
+pub trait IBusTelemetry {
+    /// Type and value length of each sensor, in address order.
+    const SENSORS: &'static [(IBusSensor, IBusSensorLength)];
+
+    /// The current value of sensor `index` (address - 1), in IBus units.
+    fn sensor_value(&self, index: usize) -> u32;
+
+    /// Answer a request for one of the sensors.
+    /// Returns `None` if the message is not a request for one of our addresses.
+    fn respond(&self, msg: &IBusMsg) -> Option<IBusMsg> {
+        let addr = match *msg {
+            IBusMsg::DiscoveryRequest(addr) | IBusMsg::TypeRequest(addr) | IBusMsg::ValueRequest(addr) => addr,
+            _ => return None
+        };
+        let index = (addr as usize).checked_sub(1)?;
+        let (sensor, length) = *Self::SENSORS.get(index)?;
+        match *msg {
+            IBusMsg::DiscoveryRequest(_) => Some(IBusMsg::DiscoveryResponse(addr)),
+            IBusMsg::TypeRequest(_) => Some(IBusMsg::TypeResponse(addr, sensor, length)),
+            _ => Some(IBusMsg::value_response(addr, length, self.sensor_value(index)))
+        }
+    }
+}
+
+impl<T: IBusTelemetry> SensorResponder for T {
+    fn respond(&mut self, msg: &IBusMsg) -> Option<IBusMsg> { IBusTelemetry::respond(self, msg) }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Manual {
+        temperature: f32,
+        pressure: u32,
+    }
+
+    impl IBusTelemetry for Manual {
+        const SENSORS: &'static [(IBusSensor, IBusSensorLength)] = &[
+            (IBusSensor::TEMP, IBusSensorLength::Short),
+            (IBusSensor::PRESS, IBusSensorLength::Long),
+        ];
+
+        fn sensor_value(&self, index: usize) -> u32 {
+            match index {
+                0 => self.temperature.to_ibus(IBusSensor::TEMP),
+                1 => self.pressure.to_ibus(IBusSensor::PRESS),
+                _ => 0
+            }
+        }
+    }
+
+    #[test]
+    fn test_units() {
+        assert_eq!(12.34f32.to_ibus(IBusSensor::EXTV), 1234);
+        assert_eq!(3.3f32.to_ibus(IBusSensor::INTV), 330);
+        assert_eq!(25.0f32.to_ibus(IBusSensor::TEMP), 650);
+        assert_eq!((-40.0f32).to_ibus(IBusSensor::TEMP), 0);
+        assert_eq!((-50.0f32).to_ibus(IBusSensor::TEMP), 0);
+        assert_eq!(101325.0f32.to_ibus(IBusSensor::PRESS), 101325);
+        assert_eq!(650u16.to_ibus(IBusSensor::TEMP), 650);
+        assert_eq!(650i16.to_ibus(IBusSensor::TEMP), 650);
+        assert_eq!((-1i16).to_ibus(IBusSensor::TEMP), 0);
+        assert_eq!(i32::MIN.to_ibus(IBusSensor::RPM), 0);
+    }
+
+    #[test]
+    fn test_respond() {
+        let t = Manual { temperature: 21.5, pressure: 100_000 };
+        assert_eq!(t.respond(&IBusMsg::DiscoveryRequest(0)), None);
+        assert_eq!(t.respond(&IBusMsg::DiscoveryRequest(2)), Some(IBusMsg::DiscoveryResponse(2)));
+        assert_eq!(t.respond(&IBusMsg::DiscoveryRequest(3)), None);
+        assert_eq!(t.respond(&IBusMsg::TypeRequest(2)),
+                   Some(IBusMsg::TypeResponse(2, IBusSensor::PRESS, IBusSensorLength::Long)));
+        assert_eq!(t.respond(&IBusMsg::ValueRequest(1)), Some(IBusMsg::ValueResponseShort(1, 615)));
+        assert_eq!(t.respond(&IBusMsg::ValueRequest(2)), Some(IBusMsg::ValueResponseLong(2, 100_000)));
+        assert_eq!(t.respond(&IBusMsg::SetMsg([1500; 14])), None);
+    }
+}
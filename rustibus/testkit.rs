@@ -0,0 +1,407 @@
+//! Test support: in-memory serial ports and a simulated receiver, for testing
+//! drivers and sensor firmware on the host.
+
+use alloc::collections::VecDeque;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::ops::Index;
+
+use crate::RustIBus::{IBusMsg, IBusSensor, IBusSensorLength, MAX_LENGTH, popIBusMsg, popIBusResponse, pushIBusMsg};
+use crate::serial::{Direction, HalfDuplex, SerialRead, SerialWrite};
+
+
+/// A fault injected into the bytes read from a port. The position counts the bytes read
+/// from the moment the fault is added, so 0 is the next byte.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Fault {
+    /// The byte is lost.
+    Drop(usize),
+    /// The byte is read with the bits in the mask inverted.
+    Flip(usize, u8),
+    /// An extra byte is read before it.
+    Insert(usize, u8),
+}
+
+impl Fault {
+    fn position(&self) -> usize {
+        match *self {
+            Fault::Drop(n) | Fault::Flip(n, _) | Fault::Insert(n, _) => n
+        }
+    }
+}
+
+
+/// Applies faults to a stream of bytes.
+#[derive(Default)]
+struct Faults {
+    pending: Vec<(usize, Fault)>,
+    position: usize,
+}
+
+impl Faults {
+    fn add(&mut self, fault: Fault) {
+        self.pending.push((self.position + fault.position(), fault));
+    }
+
+    fn read(&mut self, rx: &mut VecDeque<u8>) -> Option<u8> {
+        loop {
+            // Faults apply to bytes, so they wait until their byte has arrived
+            let byte = rx.pop_front()?;
+            let index = self.pending.iter().position(|(at, _)| *at == self.position);
+            let fault = index.map(|i| self.pending.remove(i).1);
+            if let Some(Fault::Insert(_, extra)) = fault {
+                rx.push_front(byte);
+                return Some(extra);
+            }
+            self.position += 1;
+            match fault {
+                Some(Fault::Drop(_)) => (),
+                Some(Fault::Flip(_, mask)) => return Some(byte ^ mask),
+                _ => return Some(byte)
+            }
+        }
+    }
+}
+
+
+/// A slice that can be handed to the decoder.
+struct Window<'a>(&'a [u8]);
+
+impl Index<usize> for Window<'_> {
+    type Output = u8;
+    fn index(&self, i: usize) -> &u8 { &self.0[i] }
+}
+
+impl Iterator for Window<'_> {
+    type Item = u8;
+    fn next(&mut self) -> Option<u8> {
+        let (first, rest) = self.0.split_first()?;
+        self.0 = rest;
+        Some(*first)
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) { (self.0.len(), Some(self.0.len())) }
+}
+
+impl ExactSizeIterator for Window<'_> {}
+
+
+fn decode(bytes: &[u8], responses: bool) -> Vec<IBusMsg> {
+    let mut msgs = Vec::new();
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        // No more than a receiver would buffer
+        let window = Window(&rest[..rest.len().min(MAX_LENGTH as usize)]);
+        let (msg, step) = if responses { popIBusResponse(&window) } else { popIBusMsg(&window) };
+        if step == 0 {
+            break;
+        }
+        msgs.extend(msg);
+        rest = &rest[step as usize..];
+    }
+    msgs
+}
+
+/// All messages sent by a receiver in `bytes`: servo messages and sensor requests.
+pub fn decode_msgs(bytes: &[u8]) -> Vec<IBusMsg> { decode(bytes, false) }
+
+/// All messages sent by sensors in `bytes`: sensor responses.
+pub fn decode_responses(bytes: &[u8]) -> Vec<IBusMsg> { decode(bytes, true) }
+
+/// The encoding of `msg` on the wire.
+pub fn encode(msg: &IBusMsg) -> Vec<u8> {
+    let mut buffer = [0u8; 32];
+    let n = pushIBusMsg(msg, &mut buffer) as usize;
+    buffer[..n].to_vec()
+}
+
+
+/// A serial port with scripted input that keeps everything written to it.
+pub struct MockSerial {
+    rx: VecDeque<u8>,
+    tx: Vec<u8>,
+    faults: Faults,
+    direction: Direction,
+    echo: bool,
+    strict: bool,
+}
+
+impl MockSerial {
+    pub fn new() -> Self {
+        MockSerial {
+            rx: VecDeque::new(),
+            tx: Vec::new(),
+            faults: Faults::default(),
+            direction: Direction::Receive,
+            echo: false,
+            strict: false,
+        }
+    }
+
+    /// Read back everything written, as on a single wire.
+    pub fn with_echo(mut self) -> Self {
+        self.echo = true;
+        self
+    }
+
+    /// Panic on writes while the port is set to receive.
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    pub fn inject(&mut self, bytes: &[u8]) { self.rx.extend(bytes); }
+    pub fn inject_msg(&mut self, msg: &IBusMsg) { self.inject(&encode(msg)); }
+    pub fn fault(&mut self, fault: Fault) { self.faults.add(fault); }
+
+    /// The number of bytes waiting to be read.
+    pub fn pending(&self) -> usize { self.rx.len() }
+    pub fn direction(&self) -> Direction { self.direction }
+    pub fn transmitted(&self) -> &[u8] { &self.tx }
+    pub fn take_transmitted(&mut self) -> Vec<u8> { core::mem::take(&mut self.tx) }
+
+    /// Decode and clear the transmitted bytes as sensor responses.
+    pub fn take_responses(&mut self) -> Vec<IBusMsg> { decode_responses(&self.take_transmitted()) }
+}
+
+impl Default for MockSerial {
+    fn default() -> Self { Self::new() }
+}
+
+impl SerialRead for MockSerial {
+    fn read(&mut self) -> Option<u8> { self.faults.read(&mut self.rx) }
+}
+
+impl SerialWrite for MockSerial {
+    fn write(&mut self, byte: u8) -> bool {
+        assert!(!self.strict || self.direction == Direction::Transmit, "Writing to a port that is receiving");
+        self.tx.push(byte);
+        if self.echo {
+            self.rx.push_back(byte);
+        }
+        true
+    }
+}
+
+impl HalfDuplex for MockSerial {
+    fn set_direction(&mut self, direction: Direction) { self.direction = direction; }
+}
+
+
+struct WireState {
+    rx: [VecDeque<u8>; 2],
+    traffic: Vec<u8>,
+}
+
+/// One end of a single wire shared by two ports, made with `wire()`.
+/// Each end reads everything written to the wire, including its own bytes.
+pub struct WireEnd {
+    state: Rc<RefCell<WireState>>,
+    side: usize,
+    faults: Faults,
+    direction: Direction,
+}
+
+/// A single wire with two ends, e.g. for a receiver and a sensor.
+pub fn wire() -> (WireEnd, WireEnd) {
+    let state = Rc::new(RefCell::new(WireState { rx: [VecDeque::new(), VecDeque::new()], traffic: Vec::new() }));
+    let end = |side| WireEnd { state: state.clone(), side, faults: Faults::default(), direction: Direction::Receive };
+    (end(0), end(1))
+}
+
+impl WireEnd {
+    /// Inject a fault in what this end reads.
+    pub fn fault(&mut self, fault: Fault) { self.faults.add(fault); }
+    pub fn direction(&self) -> Direction { self.direction }
+    /// Everything written to the wire, by both ends.
+    pub fn traffic(&self) -> Vec<u8> { self.state.borrow().traffic.clone() }
+}
+
+impl SerialRead for WireEnd {
+    fn read(&mut self) -> Option<u8> {
+        let mut state = self.state.borrow_mut();
+        self.faults.read(&mut state.rx[self.side])
+    }
+}
+
+impl SerialWrite for WireEnd {
+    fn write(&mut self, byte: u8) -> bool {
+        let mut state = self.state.borrow_mut();
+        state.rx[0].push_back(byte);
+        state.rx[1].push_back(byte);
+        state.traffic.push(byte);
+        true
+    }
+}
+
+impl HalfDuplex for WireEnd {
+    fn set_direction(&mut self, direction: Direction) { self.direction = direction; }
+}
+
+
+/// Time to send one byte at 115200 Baud, in microseconds.
+pub const BYTE_TIME_US: u32 = 87;
+
+
+/// The receiver side of a sensor port: sends requests and collects the responses.
+/// The sensor node is run by calling a closure with the current time in microseconds.
+pub struct SimReceiver {
+    end: WireEnd,
+    now_us: u32,
+    timeout_us: u32,
+}
+
+impl SimReceiver {
+    pub fn new(end: WireEnd) -> Self {
+        SimReceiver { end, now_us: 0, timeout_us: 2000 }
+    }
+
+    /// How long to wait for a response.
+    pub fn with_timeout(mut self, timeout_us: u32) -> Self {
+        self.timeout_us = timeout_us;
+        self
+    }
+
+    pub fn now_us(&self) -> u32 { self.now_us }
+    pub fn end(&mut self) -> &mut WireEnd { &mut self.end }
+
+    /// Run the node for a while without sending anything.
+    pub fn advance<F: FnMut(u32)>(&mut self, duration_us: u32, mut node: F) {
+        let end = self.now_us.wrapping_add(duration_us);
+        while (end.wrapping_sub(self.now_us) as i32) > 0 {
+            node(self.now_us);
+            self.now_us = self.now_us.wrapping_add(BYTE_TIME_US);
+        }
+    }
+
+    /// Send a request and return the first response to arrive within the timeout.
+    pub fn request<F: FnMut(u32)>(&mut self, msg: &IBusMsg, mut node: F) -> Option<IBusMsg> {
+        while self.end.read().is_some() {}
+        let bytes = encode(msg);
+        for b in &bytes {
+            self.end.write(*b);
+        }
+        // Our own request comes back on the wire; skip it.
+        for _ in 0..bytes.len() {
+            self.end.read();
+        }
+        self.now_us = self.now_us.wrapping_add(BYTE_TIME_US * bytes.len() as u32);
+
+        let mut received = Vec::new();
+        let deadline = self.now_us.wrapping_add(self.timeout_us);
+        while (deadline.wrapping_sub(self.now_us) as i32) >= 0 {
+            node(self.now_us);
+            self.now_us = self.now_us.wrapping_add(BYTE_TIME_US);
+            while let Some(b) = self.end.read() {
+                received.push(b);
+            }
+            if let Some(response) = decode_responses(&received).into_iter().next() {
+                return Some(response);
+            }
+        }
+        None
+    }
+
+    /// Discover the sensors of the node, as a receiver does after power up: discovery and type
+    /// requests from address 1, until an address does not answer.
+    pub fn discover<F: FnMut(u32)>(&mut self, mut node: F) -> Vec<(u8, IBusSensor, IBusSensorLength)> {
+        let mut sensors = Vec::new();
+        for addr in 1..=crate::registry::MAX_ADDRESS {
+            if self.request(&IBusMsg::DiscoveryRequest(addr), &mut node) != Some(IBusMsg::DiscoveryResponse(addr)) {
+                break;
+            }
+            match self.request(&IBusMsg::TypeRequest(addr), &mut node) {
+                Some(IBusMsg::TypeResponse(a, sensor, length)) if a == addr => sensors.push((addr, sensor, length)),
+                _ => break
+            }
+        }
+        sensors
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::half_duplex::{SensorBus, Timing};
+    use crate::registry::SensorRegistry;
+
+    #[test]
+    fn test_faults() {
+        let mut serial = MockSerial::new();
+        serial.inject(&[1, 2, 3, 4, 5]);
+        serial.fault(Fault::Drop(1));
+        serial.fault(Fault::Flip(3, 0x80));
+        serial.fault(Fault::Insert(4, 0x55));
+        assert_eq!(serial.read(), Some(1));
+        let mut read = Vec::new();
+        while let Some(b) = serial.read() {
+            read.push(b);
+        }
+        assert_eq!(read, [3, 0x84, 0x55, 5]);
+
+        // Positions count from when the fault is added
+        serial.inject(&[6, 7]);
+        serial.fault(Fault::Drop(0));
+        assert_eq!(serial.read(), Some(7));
+
+        // Reading while nothing has arrived keeps the faults for the bytes to come
+        serial.fault(Fault::Drop(0));
+        serial.fault(Fault::Flip(1, 0x0f));
+        serial.fault(Fault::Insert(2, 0x55));
+        assert_eq!(serial.read(), None);
+        serial.inject(&[8, 9, 10, 11]);
+        assert_eq!(serial.read(), Some(9 ^ 0x0f));
+        assert_eq!(serial.read(), Some(0x55));
+        assert_eq!(serial.read(), Some(10));
+        assert_eq!(serial.read(), Some(11));
+    }
+
+    #[test]
+    fn test_capture() {
+        let mut serial = MockSerial::new().with_echo().strict();
+        serial.set_direction(Direction::Transmit);
+        for b in encode(&IBusMsg::DiscoveryResponse(3)) {
+            serial.write(b);
+        }
+        assert_eq!(serial.pending(), 4);
+        assert_eq!(serial.take_responses(), [IBusMsg::DiscoveryResponse(3)]);
+        assert!(serial.transmitted().is_empty());
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut bytes = encode(&IBusMsg::SetMsg([1500; 14]));
+        bytes.insert(0, 0x33);
+        bytes.extend(encode(&IBusMsg::ValueRequest(2)));
+        bytes.extend(&[0x04, 0x81]);
+        assert_eq!(decode_msgs(&bytes), [IBusMsg::SetMsg([1500; 14]), IBusMsg::ValueRequest(2)]);
+    }
+
+    #[test]
+    fn test_receiver_and_node() {
+        let mut temp = || 650u32;
+        let mut press = || 100_000u32;
+        let mut registry = SensorRegistry::<2>::new();
+        registry.register(IBusSensor::TEMP, &mut temp).unwrap();
+        registry.register(IBusSensor::PRESS, &mut press).unwrap();
+
+        let (receiver_end, node_end) = wire();
+        let mut node: SensorBus<_, _> = SensorBus::new(node_end, registry, Timing::default());
+        let mut receiver = SimReceiver::new(receiver_end);
+
+        assert_eq!(receiver.discover(|now| node.poll(now)), [
+            (1, IBusSensor::TEMP, IBusSensorLength::Short),
+            (2, IBusSensor::PRESS, IBusSensorLength::Long),
+        ]);
+        assert_eq!(receiver.request(&IBusMsg::ValueRequest(2), |now| node.poll(now)),
+                   Some(IBusMsg::ValueResponseLong(2, 100_000)));
+
+        // A corrupted request is not answered
+        receiver.advance(1000, |now| node.poll(now));
+        node.port().fault(Fault::Flip(2, 0x01));
+        assert_eq!(receiver.request(&IBusMsg::ValueRequest(1), |now| node.poll(now)), None);
+        assert_eq!(receiver.request(&IBusMsg::ValueRequest(1), |now| node.poll(now)),
+                   Some(IBusMsg::ValueResponseShort(1, 650)));
+    }
+}
@@ -0,0 +1,234 @@
+
+use crate::RustIBus::{IBusMsg, popIBusMsg, pushIBusMsg, packChannels, MAX_CHANNELS, MAX_LENGTH};
+use crate::deque::Deque;
+use crate::half_duplex::EchoFilter;
+use crate::registry::{NoSensors, SensorResponder};
+
+
+/// The value all channels start at: the center of the 1000 - 2000 servo range.
+pub const CENTER: u16 = 1500;
+
+/// The longest response `receive` can produce for a single byte.
+pub const MAX_RESPONSE_LENGTH: usize = MAX_LENGTH as usize;
+
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ChannelCount {
+    /// The classic frame, with 14 channels of 16 bits.
+    Fourteen = 14,
+    /// 18 channels of 12 bits, the extra four packed as in `RustIBus::packChannels`.
+    Eighteen = 18,
+}
+
+
+/// Emulates a receiver: produces the servo stream from a set of channel values.
+///
+/// Call `tick` at the frame rate (every 7 ms for a FlySky receiver) and send the bytes it
+/// writes. Bytes coming back over the same interface are handed to `receive`; when a
+/// responder is set, its answers to telemetry requests are written out right away, and their
+/// echo is dropped when it is read back.
+pub struct IBusTransmitter<R: SensorResponder = NoSensors, const SIZE: usize = 64> {
+    count: ChannelCount,
+    values: [u16; MAX_CHANNELS],
+    responder: R,
+    rx: Deque<SIZE>,
+    echo: EchoFilter,
+    frames: u32,
+}
+
+impl IBusTransmitter<NoSensors> {
+    pub fn new(count: ChannelCount) -> Self {
+        Self::with_responder(count, NoSensors)
+    }
+}
+
+impl<R: SensorResponder, const SIZE: usize> IBusTransmitter<R, SIZE> {
+    pub fn with_responder(count: ChannelCount, responder: R) -> Self {
+        Self { count, values: [CENTER; MAX_CHANNELS], responder, rx: Deque::new(), echo: EchoFilter::new(), frames: 0 }
+    }
+
+    pub fn channel_count(&self) -> ChannelCount { self.count }
+
+    /// The current channel values, as many as the channel count.
+    pub fn channels(&self) -> &[u16] { &self.values[..self.count as usize] }
+
+    /// Set a single channel. Returns false if the index is out of range.
+    /// With 18 channels, values are limited to 12 bits.
+    pub fn set_channel(&mut self, index: usize, value: u16) -> bool {
+        if index >= self.count as usize {
+            return false;
+        }
+        self.values[index] = match self.count {
+            ChannelCount::Fourteen => value,
+            ChannelCount::Eighteen => value.min(0x0fff),
+        };
+        true
+    }
+
+    /// Set the first channels from a slice; values beyond the channel count are ignored.
+    pub fn set_channels(&mut self, values: &[u16]) {
+        for (i, v) in values.iter().enumerate() {
+            self.set_channel(i, *v);
+        }
+    }
+
+    /// The set message for the current channel values.
+    pub fn frame(&self) -> IBusMsg {
+        match self.count {
+            ChannelCount::Fourteen => {
+                let mut data = [0u16; 14];
+                data.copy_from_slice(&self.values[..14]);
+                IBusMsg::SetMsg(data)
+            },
+            ChannelCount::Eighteen => IBusMsg::SetMsg(packChannels(&self.values)),
+        }
+    }
+
+    /// Write the next frame into `out`, returning its length (32 bytes, or 0 if `out` is too short).
+    pub fn tick(&mut self, out: &mut [u8]) -> usize {
+        let length = pushIBusMsg(&self.frame(), out) as usize;
+        if length > 0 {
+            self.frames = self.frames.wrapping_add(1);
+        }
+        length
+    }
+
+    /// The number of frames written so far.
+    pub fn frames(&self) -> u32 { self.frames }
+
+    /// Handle a byte received on the interface. If it completes a telemetry request that the
+    /// responder answers, the response is written into `out` and its length returned.
+    pub fn receive(&mut self, byte: u8, out: &mut [u8]) -> usize {
+        let (Some(byte), _) = self.echo.filter(byte) else { return 0 };
+        self.rx.push(byte);
+        let mut written = 0;
+        loop {
+            let (msg, consumed) = popIBusMsg(&self.rx);
+            if consumed == 0 {
+                break;
+            }
+            for _ in 0..consumed {
+                self.rx.pop();
+            }
+            // Our own frames are not requests
+            if let Some(msg @ (IBusMsg::DiscoveryRequest(_) | IBusMsg::TypeRequest(_) | IBusMsg::ValueRequest(_))) = msg {
+                if let Some(response) = self.responder.respond(&msg) {
+                    written += pushIBusMsg(&response, &mut out[written..]) as usize;
+                }
+            }
+            if Deque::is_empty(&self.rx) {
+                break;
+            }
+        }
+        if self.rx.is_full() {
+            // Nothing sensible has come in for a while; don't let it block the buffer.
+            self.rx.clear();
+        }
+        if written > 0 {
+            self.echo.expect(&out[..written]);
+        }
+        written
+    }
+
+    pub fn responder(&mut self) -> &mut R { &mut self.responder }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RustIBus::{IBusSensor, IBusSensorLength, unpackChannels};
+    use crate::registry::SensorRegistry;
+    use crate::testkit::{decode_msgs, decode_responses, encode};
+
+    #[test]
+    fn test_frames() {
+        let mut tx = IBusTransmitter::new(ChannelCount::Fourteen);
+        let mut out = [0u8; 32];
+        assert_eq!(tx.tick(&mut out), 32);
+        assert_eq!(decode_msgs(&out), [IBusMsg::SetMsg([CENTER; 14])]);
+
+        tx.set_channels(&[1000, 2000, 1234]);
+        assert!(tx.set_channel(13, 1999));
+        assert!(!tx.set_channel(14, 1999));
+        assert_eq!(tx.tick(&mut out), 32);
+        let mut expected = [CENTER; 14];
+        expected[..3].copy_from_slice(&[1000, 2000, 1234]);
+        expected[13] = 1999;
+        assert_eq!(decode_msgs(&out), [IBusMsg::SetMsg(expected)]);
+        assert_eq!(tx.frames(), 2);
+        assert_eq!(tx.tick(&mut out[..31]), 0);
+        assert_eq!(tx.frames(), 2);
+    }
+
+    #[test]
+    fn test_18channels() {
+        let mut tx = IBusTransmitter::new(ChannelCount::Eighteen);
+        assert_eq!(tx.channels().len(), 18);
+        assert!(tx.set_channel(17, 1800));
+        assert!(tx.set_channel(14, 0xffff));
+        let mut out = [0u8; 32];
+        tx.tick(&mut out);
+        match decode_msgs(&out)[..] {
+            [IBusMsg::SetMsg(data)] => {
+                let values = unpackChannels(&data);
+                assert_eq!(values[..14], [CENTER; 14]);
+                assert_eq!(values[14..], [0x0fff, CENTER, CENTER, 1800]);
+            },
+            ref other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_telemetry() {
+        let mut temp = || 400u32;
+        let mut registry = SensorRegistry::<2>::new();
+        registry.register(IBusSensor::TEMP, &mut temp).unwrap();
+        let mut tx: IBusTransmitter<_> = IBusTransmitter::with_responder(ChannelCount::Fourteen, registry);
+
+        let mut out = [0u8; MAX_RESPONSE_LENGTH];
+        let mut responses = alloc::vec::Vec::new();
+        // Noise and our own frame echoed back are ignored
+        let mut frame = [0u8; 32];
+        tx.tick(&mut frame);
+        for byte in [0x55u8, 0x00].iter().chain(frame.iter()) {
+            assert_eq!(tx.receive(*byte, &mut out), 0);
+        }
+        for request in [IBusMsg::DiscoveryRequest(1), IBusMsg::DiscoveryRequest(2),
+                        IBusMsg::TypeRequest(1), IBusMsg::ValueRequest(1)] {
+            for byte in encode(&request) {
+                let n = tx.receive(byte, &mut out);
+                responses.extend_from_slice(&out[..n]);
+            }
+        }
+        assert_eq!(decode_responses(&responses), [
+            IBusMsg::DiscoveryResponse(1),
+            IBusMsg::TypeResponse(1, IBusSensor::TEMP, IBusSensorLength::Short),
+            IBusMsg::ValueResponseShort(1, 400)]);
+    }
+
+    #[test]
+    fn test_echo() {
+        let mut temp = || 400u32;
+        let mut registry = SensorRegistry::<1>::new();
+        registry.register(IBusSensor::TEMP, &mut temp).unwrap();
+        let mut tx: IBusTransmitter<_> = IBusTransmitter::with_responder(ChannelCount::Fourteen, registry);
+
+        // The echo of a discovery response reads as a discovery request
+        let mut out = [0u8; MAX_RESPONSE_LENGTH];
+        let mut n = 0;
+        for byte in encode(&IBusMsg::DiscoveryRequest(1)) {
+            n = tx.receive(byte, &mut out);
+        }
+        assert_eq!(n, 4);
+        let echo = out;
+        for byte in &echo[..n] {
+            assert_eq!(tx.receive(*byte, &mut out), 0);
+        }
+        // The next request is answered again
+        for byte in encode(&IBusMsg::DiscoveryRequest(1)) {
+            n = tx.receive(byte, &mut out);
+        }
+        assert_eq!(decode_responses(&out[..n]), [IBusMsg::DiscoveryResponse(1)]);
+    }
+}
@@ -0,0 +1,162 @@
+//! Runs the `rustibus` binary on capture files, stdin and a pseudo-terminal.
+
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use rustibus::RustIBus::{IBusMsg, pushIBusMsg};
+
+
+const BIN: &str = env!("CARGO_BIN_EXE_rustibus");
+
+
+fn encode(msgs: &[IBusMsg]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for msg in msgs {
+        let mut buffer = [0u8; 32];
+        let n = pushIBusMsg(msg, &mut buffer) as usize;
+        bytes.extend_from_slice(&buffer[..n]);
+    }
+    bytes
+}
+
+fn capture(name: &str, bytes: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("rustibus-{}-{}.bin", name, std::process::id()));
+    std::fs::write(&path, bytes).unwrap();
+    path
+}
+
+fn channels(first: u16) -> IBusMsg {
+    let mut data = [1500u16; 14];
+    data[0] = first;
+    IBusMsg::SetMsg(data)
+}
+
+
+#[test]
+fn test_capture_file() {
+    let mut bytes = vec![0x55, 0xaa];
+    bytes.extend(encode(&[channels(1000), IBusMsg::DiscoveryRequest(1), channels(2000)]));
+    let path = capture("file", &bytes);
+
+    let output = Command::new(BIN).args(["--format", "json"]).arg(&path).output().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("{\"type\":\"set\",\"channels\":[1000,1500,"));
+    assert_eq!(lines[1], "{\"type\":\"discover_request\",\"addr\":1}");
+    assert!(lines[2].starts_with("{\"type\":\"set\",\"channels\":[2000,1500,"));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(stderr.trim(), "bytes=70 messages=3 frames=2 resync_bytes=2 checksum_errors=0");
+}
+
+#[test]
+fn test_stdin_csv() {
+    let mut child = Command::new(BIN).args(["--format", "csv", "-"])
+        .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped())
+        .spawn().unwrap();
+    let mut bad = encode(&[channels(1200)]);
+    bad[4] ^= 0x10;
+    let mut bytes = bad;
+    bytes.extend(encode(&[channels(1100)]));
+    child.stdin.take().unwrap().write_all(&bytes).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("type,addr,value,ch1,ch2,"));
+    assert_eq!(lines[1], "set,,,1100,1500,1500,1500,1500,1500,1500,1500,1500,1500,1500,1500,1500,1500");
+    assert!(String::from_utf8(output.stderr).unwrap().contains("checksum_errors=1"));
+}
+
+#[test]
+fn test_capture_replay() {
+    use rustibus::capture::CaptureWriter;
+    use rustibus::serial::Direction;
+
+    let mut buffer = [0u8; 256];
+    let mut writer = CaptureWriter::new(&mut buffer, true).unwrap();
+    writer.record(0, Direction::Receive, &encode(&[channels(1000)])).unwrap();
+    writer.record(400_000, Direction::Receive, &encode(&[channels(1100)])).unwrap();
+    let path = capture("replay", writer.as_bytes());
+
+    // At twice the recorded pace, the second frame comes 200 ms after the first
+    let start = Instant::now();
+    let output = Command::new(BIN).args(["--format", "csv", "--speed", "2"]).arg(&path).output().unwrap();
+    let elapsed = start.elapsed();
+    std::fs::remove_file(&path).unwrap();
+    assert!(output.status.success());
+    assert!(elapsed >= Duration::from_millis(200), "took {:?}", elapsed);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[1].starts_with("set,,,1000,"));
+    assert!(lines[2].starts_with("set,,,1100,"));
+}
+
+#[test]
+fn test_bad_arguments() {
+    let output = Command::new(BIN).arg("--format").arg("xml").arg("-").output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let output = Command::new(BIN).args(["monitor", "--responses", "-"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let output = Command::new(BIN).arg("/nonexistent/capture.bin").output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+}
+
+
+/// A serial device as seen by the binary: the slave side of a pseudo-terminal.
+#[cfg(unix)]
+#[test]
+fn test_pty() {
+    use std::os::fd::FromRawFd;
+
+    let (mut master, slave_path) = unsafe {
+        let mut master = 0;
+        let mut slave = 0;
+        let mut name = [0 as libc::c_char; 128];
+        assert_eq!(libc::openpty(&mut master, &mut slave, name.as_mut_ptr(),
+                                 std::ptr::null(), std::ptr::null()), 0);
+        let mut termios = std::mem::zeroed();
+        libc::tcgetattr(slave, &mut termios);
+        libc::cfmakeraw(&mut termios);
+        libc::tcsetattr(slave, libc::TCSANOW, &termios);
+        let path = std::ffi::CStr::from_ptr(name.as_ptr()).to_str().unwrap().to_string();
+        libc::close(slave);
+        (std::fs::File::from_raw_fd(master), path)
+    };
+
+    let mut child = Command::new(BIN).args(["--format", "table", &slave_path])
+        .stdout(Stdio::piped()).stderr(Stdio::null())
+        .spawn().unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let stdout = child.stdout.take().unwrap();
+    std::thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            if tx.send(line.unwrap()).is_err() {
+                break;
+            }
+        }
+    });
+
+    // The port is flushed when it is opened, so keep sending until frames come out.
+    let frame = encode(&[channels(1234)]);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut lines = Vec::new();
+    while lines.len() < 3 && Instant::now() < deadline {
+        master.write_all(&frame).unwrap();
+        while let Ok(line) = rx.recv_timeout(Duration::from_millis(20)) {
+            lines.push(line);
+        }
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    assert!(lines.len() >= 3, "got {:?}", lines);
+    assert!(lines[0].starts_with("type"));
+    assert!(lines[1..].iter().all(|l| l.starts_with("set") && l.contains(" 1234  1500")), "got {:?}", lines);
+}
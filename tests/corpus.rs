@@ -0,0 +1,100 @@
+//! Decodes every capture in `tests/corpus` and compares the result with the `.expected` file
+//! next to it.
+//!
+//! The expected files are checked by hand against what the transmitter sent, not just taken
+//! from the decoder; see `tests/corpus/README.md`. After an intended change in the decoder
+//! output, regenerate them with `RUSTIBUS_BLESS=1 cargo test --test corpus` and review the
+//! differences.
+
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rustibus::capture::CaptureReader;
+use rustibus::serial::Direction;
+use rustibus::stream::{LinkStats, StreamDecoder};
+
+
+fn corpus_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("corpus")
+}
+
+fn captures(dir: &Path) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            found.extend(captures(&path));
+        } else if path.extension().is_some_and(|e| e == "ibcp") {
+            found.push(path);
+        }
+    }
+    found.sort();
+    found
+}
+
+
+fn stats_line(name: &str, stats: &LinkStats) -> String {
+    format!("{} stats: bytes={} messages={} frames={} resync_bytes={} checksum_errors={}\n", name,
+            stats.bytes, stats.messages, stats.set_msgs, stats.resync_bytes, stats.checksum_errors)
+}
+
+/// The decoded contents of a capture: one line per message, with the time of the record that
+/// completed it, followed by the link statistics. In captures with directions, received bytes
+/// are decoded as receiver messages and transmitted bytes as sensor responses.
+fn decode(capture: &[u8]) -> String {
+    let reader = CaptureReader::new(capture).unwrap();
+    let directions = reader.has_directions();
+    let mut requests = StreamDecoder::<64>::new();
+    let mut responses = StreamDecoder::<64>::for_responses();
+    let mut out = String::new();
+    for record in reader {
+        let record = record.unwrap();
+        let decoder = match record.direction {
+            Some(Direction::Transmit) => &mut responses,
+            _ => &mut requests,
+        };
+        for &byte in record.bytes {
+            decoder.feed(byte);
+            while let Some(msg) = decoder.pop() {
+                writeln!(out, "{} {:?}", record.time_us, msg).unwrap();
+            }
+        }
+    }
+    out.push_str(&stats_line("requests", &requests.stats()));
+    if directions {
+        out.push_str(&stats_line("responses", &responses.stats()));
+    }
+    out
+}
+
+
+#[test]
+fn test_corpus() {
+    let bless = std::env::var_os("RUSTIBUS_BLESS").is_some();
+    let captures = captures(&corpus_dir());
+    if captures.is_empty() {
+        eprintln!("no captures in {:?} yet", corpus_dir());
+        return;
+    }
+    let mut failed = Vec::new();
+    for path in captures {
+        let decoded = decode(&fs::read(&path).unwrap());
+        let expected_path = path.with_extension("expected");
+        if bless {
+            fs::write(&expected_path, &decoded).unwrap();
+            continue;
+        }
+        let expected = fs::read_to_string(&expected_path)
+            .unwrap_or_else(|_| panic!("{:?} is missing, run with RUSTIBUS_BLESS=1 to create it", expected_path));
+        if decoded != expected {
+            let line = decoded.lines().zip(expected.lines()).position(|(a, b)| a != b)
+                .unwrap_or(decoded.lines().count().min(expected.lines().count()));
+            failed.push(format!("{}: differs from line {}:\n  got:      {}\n  expected: {}",
+                                path.display(), line + 1,
+                                decoded.lines().nth(line).unwrap_or("<end>"),
+                                expected.lines().nth(line).unwrap_or("<end>")));
+        }
+    }
+    assert!(failed.is_empty(), "\n{}", failed.join("\n"));
+}